@@ -0,0 +1,130 @@
+use crate::editor::{Mode, Note, Style};
+use crate::ensemble::{Routing, MAX_PARTS};
+use crate::groove::GrooveTemplate;
+use crate::notation::NotationFormat;
+use crate::preview::{Generation, MusicEvent, Processing};
+use crate::transform::Transform;
+use std::path::PathBuf;
+
+// Messages exchanged between the editor, the audio thread and the worker. Each direction has
+// its own queue with a single producer and a single consumer:
+//
+//   editor --Command--> audio thread --Job--> worker --Event--> editor
+//                            ^                  |
+//                            +------Reply-------+
+//
+// The audio thread only exchanges `Copy` values or values moved in and out of the queues, so
+// it never allocates nor frees memory while handling them. What it replaces is sent back to
+// the worker to be freed there.
+
+/// Commands queued by the editor between two blocks
+pub const COMMAND_CAPACITY: usize = 256;
+/// Jobs queued by the audio thread before the worker takes them
+pub const JOB_CAPACITY: usize = 64;
+/// Generations waiting for the next block
+pub const REPLY_CAPACITY: usize = 8;
+/// Events kept while the editor is closed, the newer ones are dropped
+pub const EVENT_CAPACITY: usize = 256;
+
+/// Sent by the editor to the audio thread
+#[derive(Debug, Clone, Copy)]
+pub enum Command {
+    Generate,
+    SelectStyle(Style),
+    /// Styles of the parts generated together, the selected style is used when there is none
+    SetEnsemble([Option<Style>; MAX_PARTS]),
+    SelectNote(Note),
+    SelectMode(Mode),
+    Download,
+    RenderAudio,
+    CopyNotation(NotationFormat),
+    PlayPreview,
+    StopPreview,
+    SetGrooveTemplate(Option<GrooveTemplate>),
+}
+
+/// Settings of a generation, captured by the audio thread when it is requested
+#[derive(Debug, Clone, Copy)]
+pub struct GenerationRequest {
+    pub parts: [Option<Style>; MAX_PARTS],
+    pub root: Note,
+    pub mode: Mode,
+    pub bpm: f64,
+    pub time_sig_numerator: i32,
+    pub time_sig_denominator: i32,
+}
+
+impl GenerationRequest {
+    pub fn parts(&self) -> Vec<Style> {
+        self.parts.iter().flatten().copied().collect()
+    }
+}
+
+/// Work sent by the audio thread to the worker, along with the state of the plugin it needs
+#[derive(Debug)]
+pub enum Job {
+    /// With `at_bar`, the preview already playing is replaced on the next bar at the earliest
+    Generate {
+        request: GenerationRequest,
+        processing: Processing,
+        at_bar: bool,
+    },
+    /// Processes the last generation again after a change of the parameters
+    Process(Processing),
+    Download {
+        processing: Processing,
+        transform: Transform,
+        routing: Routing,
+    },
+    RenderAudio {
+        processing: Processing,
+        transform: Transform,
+        sample_rate: f32,
+    },
+    CopyNotation {
+        format: NotationFormat,
+        processing: Processing,
+        transform: Transform,
+    },
+    /// Generation replaced on the audio thread, freed by the worker
+    Release(Generation),
+    /// Events replaced in the player, freed by the worker
+    ReleaseEvents(Vec<MusicEvent>),
+}
+
+/// Sent by the worker to the audio thread
+#[derive(Debug)]
+pub enum Reply {
+    /// The generation and its events processed with `processing`
+    Generated {
+        generation: Generation,
+        events: Vec<MusicEvent>,
+        processing: Processing,
+        at_bar: bool,
+    },
+    Processed {
+        events: Vec<MusicEvent>,
+        processing: Processing,
+    },
+}
+
+/// Sent by the worker to the editor
+#[derive(Debug, Clone)]
+pub enum Event {
+    DownloadAvailable,
+    /// Progress of the download, from 0 to 100
+    DownloadProgress(u8),
+    DownloadComplete(PathBuf),
+    DownloadError(String),
+    /// `None` when there is nothing to copy
+    NotationCopied(Option<String>),
+}
+
+/// Styles of an ensemble in the fixed size array of the commands, the extra parts are dropped
+pub fn ensemble(parts: &[Style]) -> [Option<Style>; MAX_PARTS] {
+    let mut ensemble = [None; MAX_PARTS];
+    for (slot, style) in ensemble.iter_mut().zip(parts) {
+        *slot = Some(*style);
+    }
+    ensemble
+}
@@ -0,0 +1,657 @@
+use crate::bus::GenerationRequest;
+use crate::editor::{Mode, Note, Style};
+use crate::ensemble;
+use crate::file_naming::{self, NameFields};
+use crate::logging::log_info;
+use crate::midi_file::MidiFile;
+use crate::musicxml::ScoreInfo;
+use crate::player;
+use crate::preview::{self, Generation, MusicEvent};
+use crate::requester::Requester;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::mpsc;
+
+// Command line tool generating MIDI files without the plugin, for scripts building sample
+// packs. It sends the same requests as the plugin and writes the same MIDI files, then prints
+// the results as JSON on the standard output.
+
+const USAGE: &str = "\
+Generates MIDI files with Harmonia, without a DAW.
+
+Usage: harmonia-cli [OPTIONS]
+       harmonia-cli --batch <FILE> [OPTIONS]
+
+Options:
+  --style <STYLES>     Styles of the parts, joined with '+' (default: Acoustic Grand)
+  --key <NOTE>         Root of the key, such as C, F# or Bb (default: C)
+  --mode <MODE>        major or minor (default: major)
+  --bpm <BPM>          Tempo (default: 120)
+  --bars <BARS>        Keeps only the first bars of the generation
+  --time-sig <SIG>     Time signature, such as 4/4 (default: 4/4)
+  --seed <SEED>        Seed sent with the request and written in the file (default: random)
+  --out <PATH>         Folder of the files, or path of a .mid file (default: .)
+  --batch <FILE>       Generates every job of a JSON or CSV file, see below
+  --api-url <URL>      Server the generations are requested from
+  -h, --help           Prints this help
+
+The jobs of a batch file have the names of the options as their fields, without the dashes,
+the options given on the command line being used for the missing ones. A JSON file holds an
+array of objects, a CSV file has the names of its columns on its first line:
+
+  style,key,mode,bpm,bars,out
+  Acoustic Bass,E,minor,90,4,bass
+";
+
+/// Fields of the jobs, the names of the options with underscores
+const JOB_FIELDS: [&str; 8] = [
+    "style", "key", "mode", "bpm", "bars", "time_sig", "seed", "out",
+];
+
+/// Settings of a generation, read from the options or from a job of a batch file
+#[derive(Debug, Clone)]
+struct Job {
+    parts: Vec<Style>,
+    root: Note,
+    mode: Mode,
+    bpm: f64,
+    bars: Option<u32>,
+    time_sig_numerator: i32,
+    time_sig_denominator: i32,
+    seed: u64,
+    out: PathBuf,
+}
+
+/// Result of a job, printed as JSON
+#[derive(Debug, Serialize)]
+struct JobResult {
+    style: String,
+    key: String,
+    mode: String,
+    bpm: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bars: Option<u32>,
+    time_sig: String,
+    seed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    notes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Runs the tool with its arguments, without the name of the program
+pub fn run(args: Vec<String>) -> ExitCode {
+    let mut fields = HashMap::new();
+    let mut batch = None;
+    let mut api_url = String::from(crate::API_URL);
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            print!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        let Some(name) = arg.strip_prefix("--") else {
+            return usage_error(&format!("Unexpected argument: {}", arg));
+        };
+        let Some(value) = args.next() else {
+            return usage_error(&format!("Missing value for --{}", name));
+        };
+        let field = name.replace('-', "_");
+        match name {
+            "batch" => batch = Some(PathBuf::from(value)),
+            "api-url" => api_url = value,
+            _ if JOB_FIELDS.contains(&field.as_str()) => {
+                fields.insert(field, value);
+            }
+            _ => return usage_error(&format!("Unknown option: {}", arg)),
+        }
+    }
+
+    let jobs = match &batch {
+        Some(path) => match read_batch(path) {
+            Ok(jobs) => jobs
+                .into_iter()
+                .map(|job| {
+                    // The empty fields of a job keep the value of the option
+                    let mut job_fields = fields.clone();
+                    job_fields.extend(
+                        job.into_iter()
+                            .filter(|(_, value)| !value.trim().is_empty()),
+                    );
+                    job_fields
+                })
+                .collect(),
+            Err(err) => return usage_error(&err),
+        },
+        None => vec![fields],
+    };
+
+    let (events, _) = mpsc::channel();
+    let requester = Requester::new(api_url, events);
+    let results: Vec<JobResult> = jobs
+        .iter()
+        .map(|fields| match Job::from_fields(fields) {
+            Ok(job) => generate(&requester, &job),
+            Err(err) => JobResult::failed(fields, err),
+        })
+        .collect();
+    let failed = results.iter().any(|result| result.error.is_some());
+
+    let json = if batch.is_some() {
+        serde_json::to_string_pretty(&results)
+    } else {
+        serde_json::to_string_pretty(&results[0])
+    };
+    match json {
+        Ok(json) => println!("{}", json),
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn usage_error(message: &str) -> ExitCode {
+    eprintln!("{}\n\n{}", message, USAGE);
+    ExitCode::from(2)
+}
+
+impl Job {
+    fn from_fields(fields: &HashMap<String, String>) -> Result<Self, String> {
+        let field = |name: &str| {
+            fields
+                .get(name)
+                .map(|value| value.trim())
+                .filter(|value| !value.is_empty())
+        };
+
+        let parts = match field("style") {
+            Some(styles) => styles
+                .split('+')
+                .map(parse_style)
+                .collect::<Result<Vec<_>, _>>()?,
+            None => vec![Style::AcousticGrand],
+        };
+        if parts.len() > ensemble::MAX_PARTS {
+            return Err(format!(
+                "At most {} styles can be generated together",
+                ensemble::MAX_PARTS
+            ));
+        }
+        let (time_sig_numerator, time_sig_denominator) = match field("time_sig") {
+            Some(time_sig) => parse_time_signature(time_sig)?,
+            None => (4, 4),
+        };
+        let bpm: f64 = field("bpm")
+            .map(|bpm| parse_number(bpm, "bpm"))
+            .transpose()?
+            .unwrap_or(120.0);
+        if bpm.is_nan() || bpm <= 0.0 {
+            return Err(format!("Invalid bpm: {}", bpm));
+        }
+        let bars: Option<u32> = field("bars")
+            .map(|bars| parse_number(bars, "bars"))
+            .transpose()?;
+        if bars == Some(0) {
+            return Err(String::from("Invalid bars: 0"));
+        }
+
+        Ok(Job {
+            parts,
+            root: field("key").map(parse_note).transpose()?.unwrap_or(Note::C),
+            mode: field("mode")
+                .map(parse_mode)
+                .transpose()?
+                .unwrap_or(Mode::Major),
+            bpm,
+            bars,
+            time_sig_numerator,
+            time_sig_denominator,
+            seed: field("seed")
+                .map(|seed| parse_number(seed, "seed"))
+                .transpose()?
+                .unwrap_or_else(rand::random),
+            out: PathBuf::from(field("out").unwrap_or(".")),
+        })
+    }
+}
+
+/// Requests a generation and writes it in a MIDI file
+fn generate(requester: &Requester, job: &Job) -> JobResult {
+    let mut result = JobResult::from_job(job);
+    let generation = match request(requester, job) {
+        Ok(generation) => generation,
+        Err(err) => {
+            result.error = Some(err);
+            return result;
+        }
+    };
+
+    result.notes = generation.events.len();
+    match write_midi(&generation, &job.out) {
+        Ok(path) => {
+            log_info!("Generation written to {}", path.display());
+            result.path = Some(path);
+        }
+        Err(err) => result.error = Some(err),
+    }
+    result
+}
+
+fn request(requester: &Requester, job: &Job) -> Result<Generation, String> {
+    let mut parts = [None; ensemble::MAX_PARTS];
+    for (part, style) in parts.iter_mut().zip(&job.parts) {
+        *part = Some(*style);
+    }
+    let request = GenerationRequest {
+        parts,
+        root: job.root,
+        mode: job.mode,
+        bpm: job.bpm,
+        time_sig_numerator: job.time_sig_numerator,
+        time_sig_denominator: job.time_sig_denominator,
+    };
+    let response = requester.generate(&request, Some(job.seed))?;
+
+    let mut events = preview::flatten_groups(response.preview);
+    ensemble::assign_channels(&mut events, &job.parts);
+    if let Some(bars) = job.bars {
+        let end =
+            bars as f64 * player::bar_length(job.time_sig_numerator, job.time_sig_denominator);
+        events = truncate(&events, end);
+    }
+    if events.is_empty() {
+        return Err(String::from("The generation has no notes"));
+    }
+
+    Ok(Generation {
+        download_link: response.download_link,
+        parts: job.parts.clone(),
+        root: job.root,
+        mode: job.mode,
+        bpm: job.bpm,
+        time_sig_numerator: job.time_sig_numerator,
+        time_sig_denominator: job.time_sig_denominator,
+        seed: job.seed,
+        // The seed also makes the humanization reproducible
+        humanize_seed: job.seed,
+        events,
+    })
+}
+
+/// Notes starting before `end`, in beats, shortened so they end before it
+fn truncate(events: &[MusicEvent], end: f64) -> Vec<MusicEvent> {
+    events
+        .iter()
+        .filter(|event| event.time < end)
+        .map(|event| MusicEvent {
+            duration: event.duration.min(end - event.time),
+            ..*event
+        })
+        .collect()
+}
+
+/// Writes the MIDI file of a generation, named like the exports of the plugin when `out` is a
+/// folder
+fn write_midi(generation: &Generation, out: &Path) -> Result<PathBuf, String> {
+    let path = if out.extension().is_some_and(|extension| extension == "mid") {
+        if let Some(folder) = out.parent().filter(|folder| !folder.as_os_str().is_empty()) {
+            file_naming::check_folder(folder)?;
+        }
+        out.to_path_buf()
+    } else {
+        file_naming::check_folder(out)?;
+        let fields = NameFields {
+            style: generation
+                .parts
+                .iter()
+                .map(|style| style.to_string())
+                .collect::<Vec<_>>()
+                .join("+"),
+            key: generation.root.to_string(),
+            mode: generation.mode.to_string(),
+            bpm: generation.bpm,
+            date: file_naming::today(),
+            seed: generation.seed,
+        };
+        file_naming::unique_path(out, file_naming::DEFAULT_TEMPLATE, &fields, "mid", &[])?
+    };
+
+    let programs: Vec<Option<u8>> = generation
+        .parts
+        .iter()
+        .map(|style| Some(style.program()))
+        .collect();
+    let info = ScoreInfo {
+        parts: &generation.parts,
+        root: generation.root,
+        mode: generation.mode,
+        bpm: generation.bpm,
+        time_sig_numerator: generation.time_sig_numerator,
+        time_sig_denominator: generation.time_sig_denominator,
+    };
+    let midi = MidiFile::from_events(&generation.events, &programs, &info, &generation.describe());
+    fs::write(&path, midi.encode())
+        .map_err(|err| format!("Cannot write {}: {}", path.display(), err))?;
+    Ok(path)
+}
+
+impl JobResult {
+    fn from_job(job: &Job) -> Self {
+        JobResult {
+            style: job
+                .parts
+                .iter()
+                .map(|style| style.to_string())
+                .collect::<Vec<_>>()
+                .join("+"),
+            key: job.root.to_string(),
+            mode: job.mode.to_string(),
+            bpm: job.bpm,
+            bars: job.bars,
+            time_sig: format!("{}/{}", job.time_sig_numerator, job.time_sig_denominator),
+            seed: job.seed,
+            path: None,
+            notes: 0,
+            error: None,
+        }
+    }
+
+    /// Result of a job whose fields are invalid, with the fields as they were given
+    fn failed(fields: &HashMap<String, String>, error: String) -> Self {
+        let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+        JobResult {
+            style: field("style"),
+            key: field("key"),
+            mode: field("mode"),
+            bpm: field("bpm").parse().unwrap_or(0.0),
+            bars: field("bars").parse().ok(),
+            time_sig: field("time_sig"),
+            seed: field("seed").parse().unwrap_or(0),
+            path: None,
+            notes: 0,
+            error: Some(error),
+        }
+    }
+}
+
+/// Jobs of a batch file, as the values of their fields. The file is read as CSV unless its
+/// extension is `json`. The fields which are not options are rejected, so a misspelled field is
+/// not silently replaced by its default value.
+fn read_batch(path: &Path) -> Result<Vec<HashMap<String, String>>, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|err| format!("Cannot read {}: {}", path.display(), err))?;
+    let jobs = if path
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    {
+        read_json_jobs(&text)?
+    } else {
+        read_csv_jobs(&text)?
+    };
+
+    for job in &jobs {
+        if let Some(name) = job.keys().find(|name| !JOB_FIELDS.contains(&name.as_str())) {
+            return Err(format!("Unknown field in the batch file: {}", name));
+        }
+    }
+    Ok(jobs)
+}
+
+fn read_json_jobs(text: &str) -> Result<Vec<HashMap<String, String>>, String> {
+    let jobs: Vec<serde_json::Map<String, serde_json::Value>> =
+        serde_json::from_str(text).map_err(|err| format!("Invalid batch file: {}", err))?;
+    Ok(jobs
+        .into_iter()
+        .map(|job| {
+            job.into_iter()
+                .filter_map(|(name, value)| {
+                    // A null field is missing, like an empty one
+                    let value = match value {
+                        serde_json::Value::Null => return None,
+                        serde_json::Value::String(value) => value,
+                        value => value.to_string(),
+                    };
+                    Some((name.replace('-', "_"), value))
+                })
+                .collect()
+        })
+        .collect())
+}
+
+/// Reads a CSV file without quoted fields, the commas only separating the columns
+fn read_csv_jobs(text: &str) -> Result<Vec<HashMap<String, String>>, String> {
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let header: Vec<String> = lines
+        .next()
+        .ok_or("The batch file is empty")?
+        .split(',')
+        .map(|name| name.trim().replace('-', "_"))
+        .collect();
+
+    lines
+        .enumerate()
+        .map(|(index, line)| {
+            let values: Vec<&str> = line.split(',').collect();
+            if values.len() != header.len() {
+                return Err(format!(
+                    "Line {} of the batch file has {} columns instead of {}",
+                    index + 2,
+                    values.len(),
+                    header.len()
+                ));
+            }
+            Ok(header
+                .iter()
+                .cloned()
+                .zip(values.iter().map(|value| value.trim().to_string()))
+                .collect())
+        })
+        .collect()
+}
+
+/// Style from its name or its number, the case, spaces and dashes being ignored
+fn parse_style(name: &str) -> Result<Style, String> {
+    let simplify = |name: &str| {
+        name.chars()
+            .filter(|c| c.is_alphanumeric())
+            .collect::<String>()
+            .to_lowercase()
+    };
+    let wanted = simplify(name);
+    if let Ok(id) = wanted.parse::<u8>() {
+        return Style::from_id(id).ok_or_else(|| format!("Unknown style: {}", name));
+    }
+    (0..=u8::MAX)
+        .filter_map(Style::from_id)
+        .find(|style| simplify(&style.to_string()) == wanted)
+        .ok_or_else(|| format!("Unknown style: {}", name))
+}
+
+/// Note from its name, with `#` or `b` for the sharps and flats
+fn parse_note(name: &str) -> Result<Note, String> {
+    let mut chars = name.chars();
+    let pitch_class: i32 = match chars.next().map(|c| c.to_ascii_uppercase()) {
+        Some('C') => 0,
+        Some('D') => 2,
+        Some('E') => 4,
+        Some('F') => 5,
+        Some('G') => 7,
+        Some('A') => 9,
+        Some('B') => 11,
+        _ => return Err(format!("Unknown key: {}", name)),
+    };
+    let alteration = match chars.as_str() {
+        "" => 0,
+        "#" => 1,
+        "b" => -1,
+        _ => return Err(format!("Unknown key: {}", name)),
+    };
+    Ok(Note::from_pitch_class(
+        (pitch_class + alteration).rem_euclid(12) as u8,
+    ))
+}
+
+fn parse_mode(name: &str) -> Result<Mode, String> {
+    match name.to_lowercase().as_str() {
+        "major" | "maj" => Ok(Mode::Major),
+        "minor" | "min" => Ok(Mode::Minor),
+        _ => Err(format!("Unknown mode: {}", name)),
+    }
+}
+
+fn parse_time_signature(time_sig: &str) -> Result<(i32, i32), String> {
+    let invalid = || format!("Invalid time signature: {}", time_sig);
+    let (numerator, denominator) = time_sig.split_once('/').ok_or_else(invalid)?;
+    let numerator: i32 = numerator.trim().parse().map_err(|_| invalid())?;
+    let denominator: i32 = denominator.trim().parse().map_err(|_| invalid())?;
+    if numerator < 1 || !matches!(denominator, 1 | 2 | 4 | 8 | 16 | 32) {
+        return Err(invalid());
+    }
+    Ok((numerator, denominator))
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, name: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid {}: {}", name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a batch file in the temporary folder, removed with the returned guard
+    struct BatchFile(PathBuf);
+
+    impl BatchFile {
+        fn new(name: &str, text: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("harmonia-{}-{}", std::process::id(), name));
+            fs::write(&path, text).unwrap();
+            BatchFile(path)
+        }
+    }
+
+    impl Drop for BatchFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn time_signatures_are_parsed() {
+        assert_eq!(parse_time_signature("4/4"), Ok((4, 4)));
+        assert_eq!(parse_time_signature(" 7 / 8 "), Ok((7, 8)));
+        for invalid in ["4", "0/4", "3/5", "a/4", "4/", "-3/4"] {
+            assert_eq!(
+                parse_time_signature(invalid),
+                Err(format!("Invalid time signature: {}", invalid))
+            );
+        }
+    }
+
+    #[test]
+    fn keys_and_styles_are_parsed() {
+        assert_eq!(parse_note("C"), Ok(Note::C));
+        assert_eq!(parse_note("f#"), Ok(Note::FSharp));
+        assert_eq!(parse_note("Bb"), Ok(Note::ASharp));
+        assert_eq!(parse_note("Cb"), Ok(Note::B));
+        assert!(parse_note("H").is_err());
+        assert_eq!(parse_mode("MIN"), Ok(Mode::Minor));
+        assert_eq!(parse_style("acoustic-grand"), Ok(Style::AcousticGrand));
+        assert!(parse_style("kazoo").is_err());
+    }
+
+    #[test]
+    fn jobs_are_read_from_the_fields() {
+        let job = Job::from_fields(&fields(&[
+            ("style", "Acoustic Bass"),
+            ("key", "E"),
+            ("mode", "minor"),
+            ("bpm", "90"),
+            ("bars", "4"),
+            ("time_sig", "3/4"),
+            ("seed", "42"),
+        ]))
+        .unwrap();
+        assert_eq!(job.parts, vec![Style::AcousticBass]);
+        assert_eq!((job.root, job.mode), (Note::E, Mode::Minor));
+        assert_eq!((job.bpm, job.bars, job.seed), (90.0, Some(4), 42));
+        assert_eq!((job.time_sig_numerator, job.time_sig_denominator), (3, 4));
+        assert_eq!(job.out, PathBuf::from("."));
+
+        for (name, value) in [("bpm", "0"), ("bpm", "fast"), ("bars", "0"), ("seed", "-1")] {
+            assert!(Job::from_fields(&fields(&[(name, value)])).is_err());
+        }
+    }
+
+    #[test]
+    fn csv_batches_are_read() {
+        let jobs = read_csv_jobs("style, key ,time-sig\nAcoustic Bass,E,3/4\n\n,C,\n").unwrap();
+        assert_eq!(
+            jobs,
+            vec![
+                fields(&[
+                    ("style", "Acoustic Bass"),
+                    ("key", "E"),
+                    ("time_sig", "3/4")
+                ]),
+                fields(&[("style", ""), ("key", "C"), ("time_sig", "")]),
+            ]
+        );
+        assert_eq!(
+            read_csv_jobs("style,key\nCelesta\n"),
+            Err(String::from(
+                "Line 2 of the batch file has 1 columns instead of 2"
+            ))
+        );
+    }
+
+    #[test]
+    fn null_json_fields_are_missing() {
+        let jobs = read_json_jobs(r#"[{"bpm": 100, "key": null, "time-sig": "6/8"}]"#).unwrap();
+        assert_eq!(jobs, vec![fields(&[("bpm", "100"), ("time_sig", "6/8")])]);
+    }
+
+    #[test]
+    fn unknown_batch_fields_are_rejected() {
+        let csv = BatchFile::new("jobs.csv", "style,tempo\nCelesta,90\n");
+        assert_eq!(
+            read_batch(&csv.0),
+            Err(String::from("Unknown field in the batch file: tempo"))
+        );
+
+        let json = BatchFile::new("jobs.json", r#"[{"style": "Celesta", "scale": "D"}]"#);
+        assert_eq!(
+            read_batch(&json.0),
+            Err(String::from("Unknown field in the batch file: scale"))
+        );
+    }
+
+    #[test]
+    fn usage_errors_exit_with_2() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect();
+        assert_eq!(run(args(&["--tempo", "90"])), ExitCode::from(2));
+        assert_eq!(run(args(&["--bpm"])), ExitCode::from(2));
+        assert_eq!(run(args(&["jobs.csv"])), ExitCode::from(2));
+    }
+}
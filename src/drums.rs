@@ -0,0 +1,208 @@
+use crate::preview::MusicEvent;
+
+/// Channel of the drums in General MIDI, channel 10 once displayed
+pub const DRUM_CHANNEL: u8 = 9;
+
+/// Range of the General MIDI percussion map
+pub const PERCUSSION_RANGE: (u8, u8) = (35, 81);
+
+/// Steps of the drum grid per beat, sixteenth notes
+pub const GRID_STEPS_PER_BEAT: usize = 4;
+/// Largest number of steps shown by the drum grid, two bars of 4/4
+pub const MAX_GRID_STEPS: usize = 32;
+
+/// Drum kits of General MIDI 2, selected with a Program Change on the drum channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrumKit {
+    Standard,
+    Room,
+    Power,
+    Electronic,
+    Brush,
+}
+
+impl DrumKit {
+    /// Program number of the kit on the drum channel
+    pub fn program(self) -> u8 {
+        match self {
+            DrumKit::Standard => 0,
+            DrumKit::Room => 8,
+            DrumKit::Power => 16,
+            DrumKit::Electronic => 24,
+            DrumKit::Brush => 40,
+        }
+    }
+}
+
+impl std::fmt::Display for DrumKit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DrumKit::Standard => "Standard Kit",
+            DrumKit::Room => "Room Kit",
+            DrumKit::Power => "Power Kit",
+            DrumKit::Electronic => "Electronic Kit",
+            DrumKit::Brush => "Brush Kit",
+        })
+    }
+}
+
+/// Name of a note of the General MIDI percussion map
+pub fn percussion_name(note: u8) -> Option<&'static str> {
+    Some(match note {
+        35 => "Acoustic Bass Drum",
+        36 => "Bass Drum 1",
+        37 => "Side Stick",
+        38 => "Acoustic Snare",
+        39 => "Hand Clap",
+        40 => "Electric Snare",
+        41 => "Low Floor Tom",
+        42 => "Closed Hi-Hat",
+        43 => "High Floor Tom",
+        44 => "Pedal Hi-Hat",
+        45 => "Low Tom",
+        46 => "Open Hi-Hat",
+        47 => "Low-Mid Tom",
+        48 => "Hi-Mid Tom",
+        49 => "Crash Cymbal 1",
+        50 => "High Tom",
+        51 => "Ride Cymbal 1",
+        52 => "Chinese Cymbal",
+        53 => "Ride Bell",
+        54 => "Tambourine",
+        55 => "Splash Cymbal",
+        56 => "Cowbell",
+        57 => "Crash Cymbal 2",
+        58 => "Vibraslap",
+        59 => "Ride Cymbal 2",
+        60 => "Hi Bongo",
+        61 => "Low Bongo",
+        62 => "Mute Hi Conga",
+        63 => "Open Hi Conga",
+        64 => "Low Conga",
+        65 => "High Timbale",
+        66 => "Low Timbale",
+        67 => "High Agogo",
+        68 => "Low Agogo",
+        69 => "Cabasa",
+        70 => "Maracas",
+        71 => "Short Whistle",
+        72 => "Long Whistle",
+        73 => "Short Guiro",
+        74 => "Long Guiro",
+        75 => "Claves",
+        76 => "Hi Wood Block",
+        77 => "Low Wood Block",
+        78 => "Mute Cuica",
+        79 => "Open Cuica",
+        80 => "Mute Triangle",
+        81 => "Open Triangle",
+        _ => return None,
+    })
+}
+
+/// A row of the drum grid, the velocity of the hits on each step and 0 for the silent steps
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrumRow {
+    pub note: u8,
+    pub steps: Vec<u8>,
+}
+
+impl DrumRow {
+    pub fn name(&self) -> String {
+        percussion_name(self.note)
+            .map(String::from)
+            .unwrap_or_else(|| format!("Note {}", self.note))
+    }
+}
+
+/// Lays out the hits on the drum channel on a sixteenth note grid, one row per percussion
+/// sorted by note
+pub fn drum_grid(events: &[MusicEvent]) -> Vec<DrumRow> {
+    let drums = events.iter().filter(|event| event.channel == DRUM_CHANNEL);
+    let clip_end = drums.clone().map(|event| event.time).fold(0.0, f64::max);
+    let step_count =
+        ((clip_end * GRID_STEPS_PER_BEAT as f64).floor() as usize + 1).min(MAX_GRID_STEPS);
+
+    let mut rows: Vec<DrumRow> = Vec::new();
+    for event in drums {
+        let step = (event.time * GRID_STEPS_PER_BEAT as f64).round() as usize;
+        if step >= step_count {
+            continue;
+        }
+
+        let index = match rows.iter().position(|row| row.note == event.note) {
+            Some(index) => index,
+            None => {
+                rows.push(DrumRow {
+                    note: event.note,
+                    steps: vec![0; step_count],
+                });
+                rows.len() - 1
+            }
+        };
+        let velocity = &mut rows[index].steps[step];
+        *velocity = (*velocity).max(event.velocity.max(1));
+    }
+
+    rows.sort_by_key(|row| row.note);
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hit(note: u8, time: f64, velocity: u8) -> MusicEvent {
+        MusicEvent {
+            channel: DRUM_CHANNEL,
+            duration: 0.1,
+            note,
+            time,
+            track: 0,
+            velocity,
+        }
+    }
+
+    #[test]
+    fn the_percussion_map_is_named() {
+        let (low, high) = PERCUSSION_RANGE;
+        assert!((low..=high).all(|note| percussion_name(note).is_some()));
+        assert_eq!(percussion_name(low - 1), None);
+        assert_eq!(percussion_name(high + 1), None);
+        assert_eq!(percussion_name(42), Some("Closed Hi-Hat"));
+    }
+
+    #[test]
+    fn hits_are_laid_out_on_sixteenths() {
+        let rows = drum_grid(&[
+            hit(42, 0.0, 80),
+            hit(36, 0.0, 100),
+            hit(42, 0.5, 0),
+            hit(38, 1.0, 90),
+            // Played late, but on the same step
+            hit(42, 0.52, 70),
+            MusicEvent {
+                channel: 0,
+                ..hit(60, 2.0, 100)
+            },
+        ]);
+
+        let notes: Vec<_> = rows.iter().map(|row| row.note).collect();
+        assert_eq!(notes, [36, 38, 42]);
+        assert_eq!(rows[0].name(), "Bass Drum 1");
+        assert_eq!(rows[0].steps, [100, 0, 0, 0, 0]);
+        assert_eq!(rows[1].steps, [0, 0, 0, 0, 90]);
+        assert_eq!(rows[2].steps, [80, 0, 70, 0, 0]);
+    }
+
+    #[test]
+    fn the_grid_is_limited_to_two_bars() {
+        let rows = drum_grid(&[hit(36, 0.0, 100), hit(100, 7.75, 100), hit(38, 8.0, 100)]);
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1].name(), "Note 100");
+        assert_eq!(rows[1].steps.len(), MAX_GRID_STEPS);
+        assert_eq!(rows[1].steps[MAX_GRID_STEPS - 1], 100);
+
+        assert!(drum_grid(&[]).is_empty());
+    }
+}
@@ -1,9 +1,27 @@
-use crate::mpsc;
-use crate::{thread_safe_map::ThreadSafeMap, Harmonia, HarmoniaParams, Message as MainMessage};
+use crate::bus::{self, Event};
+use crate::drums::{self, DrumKit, DrumRow};
+use crate::ensemble::{self, MAX_PARTS};
+use crate::file_naming::{self, SubFolder};
+use crate::groove::{GrooveTemplate, SwingGrid};
+use crate::key_detection::{self, NoteCapture};
+use crate::logging::{self, log_debug, log_warn, Level};
+use crate::midi_file;
+use crate::notation::NotationFormat;
+use crate::quantize::{GridDivision, GridFeel};
+use crate::render::{self, AudioFormat};
+use crate::player::LaunchQuantize;
+use crate::scale_quantizer::{self, QuantizeMode};
+use crate::spsc;
+use crate::telemetry::{Queue, Telemetry};
+use crate::transform::TransposeMode;
+use crate::thread_safe_map::{ThreadSafeMap, Watcher};
+use crate::{Harmonia, HarmoniaParams};
 use nih_plug::prelude::*;
 use nih_plug_iced::pick_list::State as PickListState;
 use nih_plug_iced::*;
-use std::sync::{mpsc::Sender, Arc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
 
 // crate pour l'ui
 // le button génération
@@ -24,23 +42,71 @@ use nih_plug_iced::widgets::ParamMessage;
 
 // Makes sense to also define this here, makes it a bit easier to keep track of
 pub(crate) fn default_state() -> Arc<IcedState> {
-    IcedState::from_size(600, 400)
+    IcedState::from_size(600, 630)
 }
 
 pub fn create(
     params: Arc<HarmoniaParams>,
     editor_state: Arc<IcedState>,
     debug_info: ThreadSafeMap<String, String>,
-    _message_sender: mpsc::Sender<crate::Message>,
-    main_thread_sender: Sender<MainMessage>,
+    telemetry: Arc<Telemetry>,
+    commands: Arc<Mutex<spsc::Producer<bus::Command>>>,
     download_available: Arc<std::sync::atomic::AtomicBool>,
+    note_capture: Arc<NoteCapture>,
+    drum_grid: Arc<RwLock<Vec<DrumRow>>>,
+    render_progress: Arc<std::sync::atomic::AtomicU8>,
+    events: Arc<Mutex<spsc::Consumer<Event>>>,
 ) -> Option<Box<dyn Editor>> {
-    create_iced_editor::<HarmoniaEditor>(editor_state, (params, debug_info, main_thread_sender, download_available))
+    create_iced_editor::<HarmoniaEditor>(
+        editor_state,
+        (
+            params,
+            debug_info,
+            telemetry,
+            commands,
+            download_available,
+            note_capture,
+            drum_grid,
+            render_progress,
+            events,
+        ),
+    )
+}
+
+/// Number of key detection candidates shown in the editor
+const KEY_CANDIDATES: usize = 3;
+/// Number of log records shown in the debug panel
+const LOG_LINES: usize = 12;
+
+/// State of the last download or render, shown above the download button
+#[derive(Debug, Clone, PartialEq)]
+enum DownloadStatus {
+    Hidden,
+    /// Progress from 0 to 100
+    InProgress(u8),
+    Saved(PathBuf),
+    Failed(String),
+}
+
+/// Pages of the editor, selected from the buttons under the title
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Page {
+    Generate,
+    Parts,
+    Edit,
+    Export,
 }
 
 struct HarmoniaEditor {
     params: Arc<HarmoniaParams>,
     context: Arc<dyn GuiContext>,
+
+    page: Page,
+    generate_page_state: button::State,
+    parts_page_state: button::State,
+    edit_page_state: button::State,
+    export_page_state: button::State,
+
     // state pour le button de géneration pour le call modèle
     button_state: button::State,
     // state pour le button de download
@@ -49,6 +115,15 @@ struct HarmoniaEditor {
     download_link_available: bool,
 
     debug_info: ThreadSafeMap<String, String>,
+    /// Rebuilds `debug_text` when the worker changes the debug info
+    debug_watcher: Watcher<String, String>,
+    debug_text: String,
+    /// State of the audio thread, read without waiting for it
+    telemetry: Arc<Telemetry>,
+    /// Least important records shown in the debug panel
+    log_level: Level,
+    log_level_state: PickListState<Level>,
+    open_log_folder_state: button::State,
 
     // info du button potentiometre
     knob_drag_state: bool,
@@ -60,11 +135,107 @@ struct HarmoniaEditor {
     style_state: PickListState<Style>,
     selected_style: Option<Style>,
 
+    // section pour la génération de plusieurs instruments ensemble
+    ensemble: Vec<Style>,
+    part_style_state: PickListState<Style>,
+    selected_part_style: Option<Style>,
+    add_part_state: button::State,
+    remove_part_states: [button::State; MAX_PARTS],
+    part_channel_knob_drag_states: [bool; MAX_PARTS],
+    part_channel_knob_last_ys: [f32; MAX_PARTS],
+    /// Drums of the last generation, written by the plugin
+    drum_grid: Arc<RwLock<Vec<DrumRow>>>,
+
     note_state: PickListState<Note>,
     selected_note: Option<Note>,
     mode_state: PickListState<Mode>,
     selected_mode: Option<Mode>,
 
+    // section pour la détection de la tonalité
+    note_capture: Arc<NoteCapture>,
+
+    // section pour le rendu audio de la génération
+    /// Progress of the render, written by the plugin
+    render_progress: Arc<std::sync::atomic::AtomicU8>,
+    render_format_state: PickListState<AudioFormat>,
+    render_tail_knob_drag_state: bool,
+    render_tail_knob_last_y: f32,
+    render_state: button::State,
+
+    // section pour l'export de la génération
+    /// Set while the plugin writes the notation to copy, the notations received otherwise are
+    /// ignored
+    copy_pending: bool,
+    copy_abc_state: button::State,
+    copy_note_list_state: button::State,
+    notation_status: String,
+
+    // section pour le dossier et le nommage des fichiers exportés
+    downloads_folder_state: text_input::State,
+    downloads_folder: String,
+    /// Shown as the placeholder of the folder, used when no folder is chosen
+    default_downloads_folder: String,
+    apply_folder_state: button::State,
+    default_folder_state: button::State,
+    folder_status: String,
+    file_name_template_state: text_input::State,
+    file_name_template: String,
+    reset_template_state: button::State,
+    sub_folder_state: PickListState<SubFolder>,
+    project_folder_state: text_input::State,
+    project_folder: String,
+
+    key_candidate_states: [button::State; KEY_CANDIDATES],
+    clear_capture_state: button::State,
+
+    // section pour le mode effet MIDI (quantification sur la gamme)
+    quantize_mode_state: PickListState<QuantizeMode>,
+
+    // section pour la transposition de la génération
+    transpose_mode_state: PickListState<TransposeMode>,
+    octave_knob_drag_state: bool,
+    octave_knob_last_y: f32,
+
+    // section pour le groove et l'humanisation
+    swing_knob_drag_state: bool,
+    swing_knob_last_y: f32,
+    humanize_knob_drag_state: bool,
+    humanize_knob_last_y: f32,
+    velocity_random_knob_drag_state: bool,
+    velocity_random_knob_last_y: f32,
+    groove_amount_knob_drag_state: bool,
+    groove_amount_knob_last_y: f32,
+    swing_grid_state: PickListState<SwingGrid>,
+    groove_path_state: text_input::State,
+    groove_path: String,
+    load_groove_state: button::State,
+    clear_groove_state: button::State,
+    /// Name of the loaded groove template, or the error encountered while loading it
+    groove_status: String,
+
+    // section pour la quantification de la génération
+    quantize_grid_state: PickListState<GridDivision>,
+    quantize_feel_state: PickListState<GridFeel>,
+    quantize_strength_knob_drag_state: bool,
+    quantize_strength_knob_last_y: f32,
+    quantize_range_knob_drag_state: bool,
+    quantize_range_knob_last_y: f32,
+    note_length_knob_drag_state: bool,
+    note_length_knob_last_y: f32,
+
+    // section pour la lecture de la génération
+    launch_quantize_state: PickListState<LaunchQuantize>,
+    launch_bars_knob_drag_state: bool,
+    launch_bars_knob_last_y: f32,
+    play_preview_state: button::State,
+    stop_preview_state: button::State,
+
+    // section pour la génération automatique
+    generate_every_knob_drag_state: bool,
+    generate_every_knob_last_y: f32,
+    trigger_key_knob_drag_state: bool,
+    trigger_key_knob_last_y: f32,
+
     // États pour le potentiomètre BPM
     bpm_knob_drag_state: bool,
     bpm_knob_last_y: f32,
@@ -73,11 +244,20 @@ struct HarmoniaEditor {
     time_sig_knob_drag_state: bool,
     time_sig_knob_last_y: f32,
 
-    main_thread_sender: Sender<MainMessage>,
+    /// Commands sent to the audio thread, see `send`
+    commands: Arc<Mutex<spsc::Producer<bus::Command>>>,
 
     // section pour state du download
-    show_popup: bool,
-    download_file_path: Option<String>
+    /// Events sent by the worker of the plugin, received on every frame
+    events: Arc<Mutex<spsc::Consumer<Event>>>,
+    download_status: DownloadStatus,
+    /// Message sent again to retry the last download or render
+    last_export: Option<Message>,
+    popup_status: String,
+    reveal_state: button::State,
+    copy_path_state: button::State,
+    retry_state: button::State,
+    dismiss_state: button::State,
 }
 
 impl IcedEditor for HarmoniaEditor {
@@ -86,19 +266,57 @@ impl IcedEditor for HarmoniaEditor {
     type InitializationFlags = (
         Arc<HarmoniaParams>,
         ThreadSafeMap<String, String>,
-        Sender<MainMessage>,
+        Arc<Telemetry>,
+        Arc<Mutex<spsc::Producer<bus::Command>>>,
         Arc<std::sync::atomic::AtomicBool>,
+        Arc<NoteCapture>,
+        Arc<RwLock<Vec<DrumRow>>>,
+        Arc<std::sync::atomic::AtomicU8>,
+        Arc<Mutex<spsc::Consumer<Event>>>,
     );
 
     fn new(
-        (params, debug_info, main_thread_sender, download_available): Self::InitializationFlags,
+        (params, debug_info, telemetry, commands, download_available, note_capture, drum_grid, render_progress, events): Self::InitializationFlags,
         context: Arc<dyn GuiContext>,
     ) -> (Self, Command<Self::Message>) {
+        let file_name_template = params
+            .file_name_template
+            .read()
+            .map(|template| template.clone())
+            .unwrap_or_default();
+        let project_folder = params
+            .project_folder
+            .read()
+            .map(|project| project.clone())
+            .unwrap_or_default();
+        let downloads_folder = params
+            .downloads_folder
+            .read()
+            .map(|folder| folder.clone())
+            .unwrap_or_default();
+        let folder_status = folder_status(&file_naming::downloads_folder(&downloads_folder));
+        let ensemble = params
+            .ensemble
+            .read()
+            .map(|parts| parts.clone())
+            .unwrap_or_default();
+
         let editor = HarmoniaEditor {
-            main_thread_sender,
+            commands,
             params,
             context,
+            page: Page::Generate,
+            generate_page_state: button::State::new(),
+            parts_page_state: button::State::new(),
+            edit_page_state: button::State::new(),
+            export_page_state: button::State::new(),
+            debug_watcher: debug_info.watch(),
+            debug_text: String::new(),
             debug_info,
+            telemetry,
+            log_level: Level::Info,
+            log_level_state: PickListState::default(),
+            open_log_folder_state: button::State::new(),
             button_state: button::State::new(),
             download_state: button::State::new(),
             knob_drag_state: false,
@@ -111,6 +329,14 @@ impl IcedEditor for HarmoniaEditor {
             style_state: PickListState::default(),
             selected_style: None,
 
+            ensemble,
+            part_style_state: PickListState::default(),
+            selected_part_style: None,
+            add_part_state: button::State::new(),
+            remove_part_states: Default::default(),
+            part_channel_knob_drag_states: [false; MAX_PARTS],
+            part_channel_knob_last_ys: [0.0; MAX_PARTS],
+
             download_available,
 
             note_state: PickListState::default(),
@@ -118,9 +344,84 @@ impl IcedEditor for HarmoniaEditor {
             mode_state: PickListState::default(),
             selected_mode: None,
 
+            note_capture,
+            drum_grid,
+            render_progress,
+            render_format_state: PickListState::default(),
+            render_tail_knob_drag_state: false,
+            render_tail_knob_last_y: 0.0,
+            render_state: button::State::new(),
+            copy_pending: false,
+            copy_abc_state: button::State::new(),
+            copy_note_list_state: button::State::new(),
+            notation_status: String::new(),
+            downloads_folder_state: text_input::State::new(),
+            downloads_folder,
+            default_downloads_folder: file_naming::default_downloads_folder()
+                .to_string_lossy()
+                .to_string(),
+            apply_folder_state: button::State::new(),
+            default_folder_state: button::State::new(),
+            folder_status,
+            file_name_template_state: text_input::State::new(),
+            file_name_template,
+            reset_template_state: button::State::new(),
+            sub_folder_state: PickListState::default(),
+            project_folder_state: text_input::State::new(),
+            project_folder,
+            key_candidate_states: Default::default(),
+            clear_capture_state: button::State::new(),
+
+            quantize_mode_state: PickListState::default(),
+
+            transpose_mode_state: PickListState::default(),
+            octave_knob_drag_state: false,
+            octave_knob_last_y: 0.0,
+
+            swing_knob_drag_state: false,
+            swing_knob_last_y: 0.0,
+            humanize_knob_drag_state: false,
+            humanize_knob_last_y: 0.0,
+            velocity_random_knob_drag_state: false,
+            velocity_random_knob_last_y: 0.0,
+            groove_amount_knob_drag_state: false,
+            groove_amount_knob_last_y: 0.0,
+            swing_grid_state: PickListState::default(),
+            groove_path_state: text_input::State::new(),
+            groove_path: String::new(),
+            load_groove_state: button::State::new(),
+            clear_groove_state: button::State::new(),
+            groove_status: String::from("No groove template"),
+
+            quantize_grid_state: PickListState::default(),
+            quantize_feel_state: PickListState::default(),
+            quantize_strength_knob_drag_state: false,
+            quantize_strength_knob_last_y: 0.0,
+            quantize_range_knob_drag_state: false,
+            quantize_range_knob_last_y: 0.0,
+            note_length_knob_drag_state: false,
+            note_length_knob_last_y: 0.0,
+
+            launch_quantize_state: PickListState::default(),
+            launch_bars_knob_drag_state: false,
+            launch_bars_knob_last_y: 0.0,
+            play_preview_state: button::State::new(),
+            stop_preview_state: button::State::new(),
+
+            generate_every_knob_drag_state: false,
+            generate_every_knob_last_y: 0.0,
+            trigger_key_knob_drag_state: false,
+            trigger_key_knob_last_y: 0.0,
+
             // initialisation du state de download
-            show_popup: false,
-            download_file_path: None,
+            events,
+            download_status: DownloadStatus::Hidden,
+            last_export: None,
+            popup_status: String::new(),
+            reveal_state: button::State::new(),
+            copy_path_state: button::State::new(),
+            retry_state: button::State::new(),
+            dismiss_state: button::State::new(),
         };
         (editor, Command::none())
     }
@@ -129,34 +430,44 @@ impl IcedEditor for HarmoniaEditor {
         self.context.as_ref()
     }
 
+    fn subscription(
+        &self,
+        window_subs: &mut WindowSubs<Self::Message>,
+    ) -> Subscription<Self::Message> {
+        // The files saved by the plugin are looked for on every frame
+        window_subs.on_frame = Some(Message::Tick);
+        Subscription::none()
+    }
+
     fn update(
         &mut self,
-        _window: &mut WindowQueue,
+        window: &mut WindowQueue,
         message: Self::Message,
     ) -> Command<Self::Message> {
         match message {
 
             Message::Generate => {
-                println!("Generate button pressed !");
-                let result = self.main_thread_sender.send(MainMessage::Generate);
-                println!("Message envoyé avec succès: {}", result.is_ok());
+                if !self.send(bus::Command::Generate) {
+                    log_warn!("The generation could not be sent to the plugin");
+                }
             }
 
             // message pour le style de musique
             Message::SelectStyle(style) => {
-                println!("Selected {:?}", style);
-                let result = self.main_thread_sender.send(MainMessage::SelectStyle(style));
+                log_debug!("Selected {}", style);
+                self.send(bus::Command::SelectStyle(style));
                 self.selected_style = Some(style);
             }
 
             Message::Download => {
-                println!("EDITOR: Bouton Download cliqué!");
-                let result = self.main_thread_sender.send(MainMessage::Download);
-                println!("EDITOR: Résultat de l'envoi du message Download: {}", result.is_ok());
+                if !self.send(bus::Command::Download) {
+                    log_warn!("The download could not be sent to the plugin");
+                }
+                self.last_export = Some(Message::Download);
+                self.download_status = DownloadStatus::InProgress(0);
             }
 
             Message::DownloadLinkAvailableEditor(isAvailable) => {
-                println!("EDITOR: Received DownloadLinkAvailable({})", isAvailable);
                 self.download_link_available = isAvailable;
                 return Command::perform(async {}, |_| Message::RefreshUI);
             }
@@ -165,14 +476,287 @@ impl IcedEditor for HarmoniaEditor {
 
             }
 
+            // The events of the plugin are handled like the messages of the editor
+            Message::Tick => {
+                let events: Vec<Message> = match self.events.lock() {
+                    Ok(mut events) => events.drain().map(Message::from).collect(),
+                    Err(_) => Vec::new(),
+                };
+                if self.debug_watcher.changed() {
+                    self.debug_text = debug_text(&self.debug_info);
+                }
+                let mut commands = Vec::with_capacity(events.len());
+                for event in events {
+                    commands.push(self.update(window, event));
+                }
+                return Command::batch(commands);
+            }
+
+            Message::DownloadProgress(progress) => {
+                self.download_status = DownloadStatus::InProgress(progress.min(100));
+            }
+
+            Message::DownloadComplete(path) => {
+                self.popup_status = String::new();
+                self.download_status = DownloadStatus::Saved(path);
+            }
+
+            Message::DownloadError(error) => {
+                self.download_status = DownloadStatus::Failed(error);
+            }
+
+            Message::RetryDownload => {
+                if let Some(message) = self.last_export.clone() {
+                    return self.update(window, message);
+                }
+            }
+
+            // The folder is opened from the editor, the plugin never waits for the file manager
+            Message::RevealSavedFile => {
+                if let DownloadStatus::Saved(path) = &self.download_status {
+                    if let Some(Err(err)) = path.parent().map(open::that) {
+                        self.popup_status = format!("Cannot open the folder: {}", err);
+                    }
+                }
+            }
+
+            Message::SelectLogLevel(level) => {
+                self.log_level = level;
+            }
+
+            Message::OpenLogFolder => {
+                if let Some(Err(err)) = logging::log_folder().map(open::that) {
+                    log_warn!("Cannot open the log folder: {}", err);
+                }
+            }
+
+            Message::CopySavedPath => {
+                if let DownloadStatus::Saved(path) = &self.download_status {
+                    let path = path.to_string_lossy().to_string();
+                    self.popup_status = String::from("Path copied");
+                    return clipboard::write(path);
+                }
+            }
+
+            Message::DismissPopup => {
+                self.download_status = DownloadStatus::Hidden;
+            }
+
             Message::SelectNote(note) => {
-                println!("Selected note {:?}", note);
+                log_debug!("Selected the note {}", note);
                 self.selected_note = Some(note);
+                self.send(bus::Command::SelectNote(note));
             }
 
             Message::SelectMode(mode) => {
-                println!("Selected mode {:?}", mode);
+                log_debug!("Selected the mode {}", mode);
                 self.selected_mode = Some(mode);
+                self.send(bus::Command::SelectMode(mode));
+            }
+
+            Message::ApplyKey(note, mode) => {
+                self.selected_note = Some(note);
+                self.selected_mode = Some(mode);
+                self.send(bus::Command::SelectNote(note));
+                self.send(bus::Command::SelectMode(mode));
+            }
+
+            Message::ClearKeyCapture => {
+                self.note_capture.clear();
+            }
+
+            Message::SelectQuantizeMode(mode) => {
+                let setter = ParamSetter::new(self.context.as_ref());
+                setter.begin_set_parameter(&self.params.scale_quantize);
+                setter.set_parameter(&self.params.scale_quantize, mode);
+                setter.end_set_parameter(&self.params.scale_quantize);
+            }
+
+            Message::SelectTransposeMode(mode) => {
+                let setter = ParamSetter::new(self.context.as_ref());
+                setter.begin_set_parameter(&self.params.transpose_mode);
+                setter.set_parameter(&self.params.transpose_mode, mode);
+                setter.end_set_parameter(&self.params.transpose_mode);
+            }
+
+            Message::SelectPage(page) => {
+                self.page = page;
+            }
+
+            Message::SelectPartStyle(style) => {
+                self.selected_part_style = Some(style);
+            }
+
+            Message::AddPart => {
+                if let Some(style) = self.selected_part_style {
+                    if self.ensemble.len() < MAX_PARTS {
+                        self.ensemble.push(style);
+                        self.apply_ensemble();
+                    }
+                }
+            }
+
+            Message::RemovePart(index) => {
+                if index < self.ensemble.len() {
+                    self.shift_part_params(index, self.ensemble.len());
+                    self.ensemble.remove(index);
+                    self.apply_ensemble();
+                }
+            }
+
+            Message::DownloadsFolderChanged(folder) => {
+                self.downloads_folder = folder;
+            }
+
+            // The folder is only saved once it can be written in
+            Message::ApplyDownloadsFolder => {
+                let folder = file_naming::downloads_folder(&self.downloads_folder);
+                self.folder_status = folder_status(&folder);
+                if file_naming::check_folder(&folder).is_ok() {
+                    if let Ok(mut persisted) = self.params.downloads_folder.write() {
+                        *persisted = self.downloads_folder.trim().to_string();
+                    }
+                }
+            }
+
+            Message::ResetDownloadsFolder => {
+                self.downloads_folder = String::new();
+                if let Ok(mut persisted) = self.params.downloads_folder.write() {
+                    *persisted = String::new();
+                }
+                self.folder_status = folder_status(&file_naming::default_downloads_folder());
+            }
+
+            Message::FileNameTemplateChanged(template) => {
+                if let Ok(mut persisted) = self.params.file_name_template.write() {
+                    *persisted = template.clone();
+                }
+                self.file_name_template = template;
+            }
+
+            Message::ResetFileNameTemplate => {
+                let template = String::from(file_naming::DEFAULT_TEMPLATE);
+                if let Ok(mut persisted) = self.params.file_name_template.write() {
+                    *persisted = template.clone();
+                }
+                self.file_name_template = template;
+            }
+
+            Message::SelectSubFolder(sub_folder) => {
+                let setter = ParamSetter::new(self.context.as_ref());
+                setter.begin_set_parameter(&self.params.sub_folder);
+                setter.set_parameter(&self.params.sub_folder, sub_folder);
+                setter.end_set_parameter(&self.params.sub_folder);
+            }
+
+            Message::ProjectFolderChanged(project) => {
+                if let Ok(mut persisted) = self.params.project_folder.write() {
+                    *persisted = project.clone();
+                }
+                self.project_folder = project;
+            }
+
+            Message::SelectRenderFormat(format) => {
+                let setter = ParamSetter::new(self.context.as_ref());
+                setter.begin_set_parameter(&self.params.render_format);
+                setter.set_parameter(&self.params.render_format, format);
+                setter.end_set_parameter(&self.params.render_format);
+            }
+
+            Message::RenderAudio => {
+                self.send(bus::Command::RenderAudio);
+                // The progress of the render is shown next to its button
+                self.last_export = Some(Message::RenderAudio);
+                self.download_status = DownloadStatus::Hidden;
+            }
+
+            Message::SelectLaunchQuantize(launch) => {
+                let setter = ParamSetter::new(self.context.as_ref());
+                setter.begin_set_parameter(&self.params.launch_quantize);
+                setter.set_parameter(&self.params.launch_quantize, launch);
+                setter.end_set_parameter(&self.params.launch_quantize);
+            }
+
+            Message::PlayPreview => {
+                self.send(bus::Command::PlayPreview);
+            }
+
+            Message::StopPreview => {
+                self.send(bus::Command::StopPreview);
+            }
+
+            Message::SelectQuantizeGrid(division) => {
+                let setter = ParamSetter::new(self.context.as_ref());
+                setter.begin_set_parameter(&self.params.quantize_grid);
+                setter.set_parameter(&self.params.quantize_grid, division);
+                setter.end_set_parameter(&self.params.quantize_grid);
+            }
+
+            Message::SelectQuantizeFeel(feel) => {
+                let setter = ParamSetter::new(self.context.as_ref());
+                setter.begin_set_parameter(&self.params.quantize_feel);
+                setter.set_parameter(&self.params.quantize_feel, feel);
+                setter.end_set_parameter(&self.params.quantize_feel);
+            }
+
+            Message::SetBoolParam(param_ptr, value) => unsafe {
+                self.context.raw_begin_set_parameter(param_ptr);
+                self.context
+                    .raw_set_parameter_normalized(param_ptr, if value { 1.0 } else { 0.0 });
+                self.context.raw_end_set_parameter(param_ptr);
+            },
+
+            Message::SelectSwingGrid(grid) => {
+                let setter = ParamSetter::new(self.context.as_ref());
+                setter.begin_set_parameter(&self.params.swing_grid);
+                setter.set_parameter(&self.params.swing_grid, grid);
+                setter.end_set_parameter(&self.params.swing_grid);
+            }
+
+            Message::GroovePathChanged(path) => {
+                self.groove_path = path;
+            }
+
+            Message::LoadGroove => {
+                let path = std::path::PathBuf::from(self.groove_path.trim());
+                let template = std::fs::read(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|bytes| midi_file::read_midi(&bytes))
+                    .and_then(|midi| {
+                        GrooveTemplate::from_events(
+                            &midi.notes,
+                            midi.time_sig_numerator,
+                            midi.time_sig_denominator,
+                        )
+                        .ok_or_else(|| String::from("The file does not contain any note"))
+                    });
+
+                match template {
+                    Ok(template) => {
+                        let name = path
+                            .file_name()
+                            .map(|name| name.to_string_lossy().to_string())
+                            .unwrap_or_default();
+                        self.groove_status = format!("Groove: {}", name);
+                        self.send(bus::Command::SetGrooveTemplate(Some(template)));
+                    }
+                    Err(error) => {
+                        self.groove_status = format!("Could not load the groove: {}", error);
+                    }
+                }
+            }
+
+            Message::ClearGroove => {
+                self.groove_status = String::from("No groove template");
+                self.send(bus::Command::SetGrooveTemplate(None));
+            }
+
+            Message::ToggleScaleDegree(degree, enabled) => {
+                let param = &self.params.scale_degrees[degree].enabled;
+                let setter = ParamSetter::new(self.context.as_ref());
+                setter.begin_set_parameter(param);
+                setter.set_parameter(param, enabled);
+                setter.end_set_parameter(param);
             }
 
 
@@ -189,9 +773,25 @@ impl IcedEditor for HarmoniaEditor {
                         self.context.raw_end_set_parameter(param_ptr);
                     },
                 }
-                println!("Param updated: {:?}", msg);
             }
-            Message::DownloadProgress(_) | Message::DownloadError(_) => todo!(),
+            Message::CopyNotation(format) => {
+                self.send(bus::Command::CopyNotation(format));
+                self.notation_status = format!("Copying {}...", format);
+                self.copy_pending = true;
+            }
+
+            Message::NotationCopied(_) if !self.copy_pending => {}
+
+            Message::NotationCopied(Some(text)) => {
+                self.copy_pending = false;
+                self.notation_status = String::from("Copied to the clipboard");
+                return clipboard::write(text);
+            }
+
+            Message::NotationCopied(None) => {
+                self.copy_pending = false;
+                self.notation_status = String::from("Nothing to copy");
+            }
         }
         Command::none()
     }
@@ -201,10 +801,28 @@ impl IcedEditor for HarmoniaEditor {
         let gain_param_ptr = self.params.gain.as_ptr();
         let bpm_param_ptr = self.params.bpm.as_ptr();
         let time_sig_param_ptr = self.params.time_signature.as_ptr();
+        let octave_param_ptr = self.params.transpose_octaves.as_ptr();
+        let swing_param_ptr = self.params.swing.as_ptr();
+        let humanize_param_ptr = self.params.humanize_timing.as_ptr();
+        let velocity_random_param_ptr = self.params.velocity_random.as_ptr();
+        let groove_amount_param_ptr = self.params.groove_amount.as_ptr();
+        let quantize_strength_param_ptr = self.params.quantize_strength.as_ptr();
+        let quantize_range_param_ptr = self.params.quantize_range.as_ptr();
+        let note_length_param_ptr = self.params.note_length.as_ptr();
+        let render_tail_param_ptr = self.params.render_tail.as_ptr();
+        let render_normalize_param_ptr = self.params.render_normalize.as_ptr();
+        let export_musicxml_param_ptr = self.params.export_musicxml.as_ptr();
+        let export_abc_param_ptr = self.params.export_abc.as_ptr();
+        let export_note_list_param_ptr = self.params.export_note_list.as_ptr();
+        let launch_bars_param_ptr = self.params.launch_bars.as_ptr();
+        let loop_param_ptr = self.params.loop_playback.as_ptr();
+        let program_change_param_ptr = self.params.program_change.as_ptr();
+        let generate_every_param_ptr = self.params.generate_every.as_ptr();
+        let trigger_key_param_ptr = self.params.trigger_key.as_ptr();
 
         // definition du vecteur de style
         let mut styles = vec![];
-        for instrument_id in 0..128 {
+        for instrument_id in 0..=u8::MAX {
             if let Some(instrument_style) = Style::from_id(instrument_id) {
                 styles.push(instrument_style);
             }
@@ -214,10 +832,59 @@ impl IcedEditor for HarmoniaEditor {
         //pour les infos de debug
         //
 
-        let visual_debug_info = if self.params.debug.value() {
-            Text::new(self.debug_info.to_string())
+        let visual_debug_info: Element<'_, Message> = if self.params.debug.value() {
+            let mut log = Column::new().spacing(2);
+            for record in logging::recent(self.log_level, LOG_LINES) {
+                let color = match record.level {
+                    Level::Error => Color::from_rgb(0.9, 0.3, 0.3),
+                    Level::Warn => Color::from_rgb(0.9, 0.7, 0.3),
+                    Level::Info => Color::WHITE,
+                    Level::Debug => Color::from_rgb(0.6, 0.6, 0.6),
+                };
+                log = log.push(
+                    Text::new(format!(
+                        "{} {:5} {}",
+                        record.time_of_day(),
+                        record.level,
+                        record.message
+                    ))
+                    .size(12)
+                    .color(color),
+                );
+            }
+
+            let mut open_log_folder = Button::new(
+                &mut self.open_log_folder_state,
+                Text::new("Open Log Folder").size(13),
+            );
+            if logging::log_folder().is_some() {
+                open_log_folder = open_log_folder.on_press(Message::OpenLogFolder);
+            }
+
+            Column::new()
+                .spacing(5)
+                .push(telemetry_view(&self.telemetry))
+                .push(Text::new(self.debug_text.clone()).size(13))
+                .push(
+                    Row::new()
+                        .align_items(Alignment::Center)
+                        .spacing(10)
+                        .push(Text::new("Log").size(14))
+                        .push(
+                            custom_pick_list(
+                                &mut self.log_level_state,
+                                &Level::ALL,
+                                Some(self.log_level),
+                                |selected| selected,
+                            )
+                            .map(Message::SelectLogLevel),
+                        )
+                        .push(open_log_folder),
+                )
+                .push(log)
+                .into()
         } else {
-            Text::new("")
+            Text::new("").into()
         };
 
         //
@@ -268,7 +935,727 @@ impl IcedEditor for HarmoniaEditor {
             |selected| selected,
         )
         .map(|selected| Message::SelectMode(selected));
-        
+
+        //
+        //pour les parties générées ensemble
+        //
+
+        let part_style_pick_list = custom_pick_list(
+            &mut self.part_style_state,
+            &styles_owned,
+            self.selected_part_style,
+            |selected| selected,
+        )
+        .map(Message::SelectPartStyle);
+
+        let mut add_part_button =
+            Button::new(&mut self.add_part_state, Text::new("Add part").size(14))
+                .style(GenerateButton);
+        if self.ensemble.len() < MAX_PARTS {
+            add_part_button = add_part_button.on_press(Message::AddPart);
+        }
+
+        let add_part_row = Row::new()
+            .align_items(Alignment::Center)
+            .spacing(10)
+            .push(Container::new(part_style_pick_list).width(Length::Units(250)))
+            .push(add_part_button)
+            .push(Space::with_width(Length::Fill))
+            .push(
+                Checkbox::new(
+                    self.params.program_change.value(),
+                    "Program Change",
+                    move |value| Message::SetBoolParam(program_change_param_ptr, value),
+                )
+                .size(14)
+                .text_size(14)
+                .spacing(4),
+            );
+
+        let mut parts_column = Column::new().spacing(8);
+        if self.ensemble.is_empty() {
+            parts_column = parts_column.push(
+                Text::new("No part, the instrument of the Generate page is generated alone")
+                    .size(14),
+            );
+        }
+        for (index, ((style, remove_state), (knob_drag_state, knob_last_y))) in self
+            .ensemble
+            .iter()
+            .zip(self.remove_part_states.iter_mut())
+            .zip(
+                self.part_channel_knob_drag_states
+                    .iter_mut()
+                    .zip(self.part_channel_knob_last_ys.iter_mut()),
+            )
+            .enumerate()
+        {
+            let part = &self.params.parts[index];
+            let mute_param_ptr = part.mute.as_ptr();
+            let solo_param_ptr = part.solo.as_ptr();
+            let channel = match part.channel.value() {
+                0 => ensemble::style_channel(index, *style),
+                channel => (channel - 1) as u8,
+            };
+            parts_column = parts_column.push(
+                Row::new()
+                    .align_items(Alignment::Center)
+                    .spacing(15)
+                    .push(
+                        Text::new(format!("{}. {}", index + 1, style))
+                            .size(14)
+                            .width(Length::Fill),
+                    )
+                    .push(Text::new(format!("Channel {}", channel + 1)).size(14))
+                    .push(Element::<'_, Message>::from(
+                        ParamKnob::new(part.channel.as_ptr(), knob_drag_state, knob_last_y)
+                            .size(30)
+                            .map(Message::ParamUpdate),
+                    ))
+                    .push(
+                        Checkbox::new(part.mute.value(), "M", move |value| {
+                            Message::SetBoolParam(mute_param_ptr, value)
+                        })
+                        .size(14)
+                        .text_size(14)
+                        .spacing(4),
+                    )
+                    .push(
+                        Checkbox::new(part.solo.value(), "S", move |value| {
+                            Message::SetBoolParam(solo_param_ptr, value)
+                        })
+                        .size(14)
+                        .text_size(14)
+                        .spacing(4),
+                    )
+                    .push(
+                        Button::new(remove_state, Text::new("Remove").size(14))
+                            .style(WatingButton)
+                            .on_press(Message::RemovePart(index)),
+                    ),
+            );
+        }
+
+        // grille des percussions de la dernière génération
+        let mut drum_grid_column = Column::new().spacing(2);
+        let drum_rows = self
+            .drum_grid
+            .read()
+            .map(|rows| rows.clone())
+            .unwrap_or_default();
+        if drum_rows.is_empty() {
+            drum_grid_column =
+                drum_grid_column.push(Text::new("No drums in the last generation").size(14));
+        }
+        for row in &drum_rows {
+            let steps: String = row
+                .steps
+                .iter()
+                .enumerate()
+                .map(|(step, velocity)| {
+                    let hit = match velocity {
+                        0 => '·',
+                        1..=63 => 'o',
+                        _ => '●',
+                    };
+                    // Separates the beats
+                    if step > 0 && step % drums::GRID_STEPS_PER_BEAT == 0 {
+                        format!(" {}", hit)
+                    } else {
+                        hit.to_string()
+                    }
+                })
+                .collect();
+            drum_grid_column = drum_grid_column.push(
+                Row::new()
+                    .push(Text::new(row.name()).size(12).width(Length::Units(130)))
+                    .push(Text::new(steps).size(12)),
+            );
+        }
+
+        //
+        //pour la détection de la tonalité
+        //
+
+        let key_candidates = key_detection::detect_key(&self.note_capture.histogram());
+
+        let mut key_row = Row::new().align_items(Alignment::Center).spacing(10).push(
+            Text::new("Detected key: ")
+                .font(assets::NOTO_SANS_BOLD)
+                .horizontal_alignment(alignment::Horizontal::Center),
+        );
+        if key_candidates.is_empty() {
+            key_row = key_row.push(Text::new("play some notes...").size(14));
+        }
+        for (candidate, state) in key_candidates
+            .iter()
+            .zip(self.key_candidate_states.iter_mut())
+        {
+            key_row = key_row.push(
+                Button::new(state, Text::new(candidate.to_string()).size(14))
+                    .style(GenerateButton)
+                    .on_press(Message::ApplyKey(candidate.root, candidate.mode)),
+            );
+        }
+        let key_row = key_row.push(Space::with_width(Length::Fill)).push(
+            Button::new(&mut self.clear_capture_state, Text::new("Clear").size(14))
+                .style(WatingButton)
+                .on_press(Message::ClearKeyCapture),
+        );
+
+        //
+        //pour la quantification des notes entrantes sur la gamme
+        //
+
+        let quantize_pick_list = custom_pick_list(
+            &mut self.quantize_mode_state,
+            &QuantizeMode::ALL,
+            Some(self.params.scale_quantize.value()),
+            |selected| selected,
+        )
+        .map(Message::SelectQuantizeMode);
+
+        let root = self.selected_note.unwrap_or(Note::CSharp);
+        let mode = self.selected_mode.unwrap_or(Mode::Major);
+        let enabled_degrees = scale_quantizer::enabled_degrees(&self.params.scale_degrees);
+        let mut degrees_row = Row::new().align_items(Alignment::Center).spacing(8);
+        for (degree, interval) in mode.intervals().iter().enumerate() {
+            let note = Note::from_pitch_class(root.pitch_class() + interval);
+            degrees_row = degrees_row.push(
+                Checkbox::new(enabled_degrees[degree], note.to_string(), move |enabled| {
+                    Message::ToggleScaleDegree(degree, enabled)
+                })
+                .size(14)
+                .text_size(14)
+                .spacing(4),
+            );
+        }
+
+        let quantize_row = Row::new()
+            .align_items(Alignment::Center)
+            .push(
+                Text::new("Scale quantize: ")
+                    .font(assets::NOTO_SANS_BOLD)
+                    .horizontal_alignment(alignment::Horizontal::Center),
+            )
+            .push(Space::with_width(Length::Units(10)))
+            .push(Container::new(quantize_pick_list).width(Length::Units(110)))
+            .push(Space::with_width(Length::Units(20)))
+            .push(degrees_row);
+
+        //
+        //pour la transposition de la génération dans la tonalité sélectionnée
+        //
+
+        let transpose_pick_list = custom_pick_list(
+            &mut self.transpose_mode_state,
+            &TransposeMode::ALL,
+            Some(self.params.transpose_mode.value()),
+            |selected| selected,
+        )
+        .map(Message::SelectTransposeMode);
+
+        //
+        //pour la lecture de la génération, calée sur les mesures de l'hôte
+        //
+
+        let launch_pick_list = custom_pick_list(
+            &mut self.launch_quantize_state,
+            &LaunchQuantize::ALL,
+            Some(self.params.launch_quantize.value()),
+            |selected| selected,
+        )
+        .map(Message::SelectLaunchQuantize);
+
+        let playback_row = Row::new()
+            .align_items(Alignment::Center)
+            .spacing(15)
+            .push(
+                Text::new("Preview: ")
+                    .font(assets::NOTO_SANS_BOLD)
+                    .horizontal_alignment(alignment::Horizontal::Center),
+            )
+            .push(
+                Button::new(&mut self.play_preview_state, Text::new("Play").size(14))
+                    .style(GenerateButton)
+                    .on_press(Message::PlayPreview),
+            )
+            .push(
+                Button::new(&mut self.stop_preview_state, Text::new("Stop").size(14))
+                    .style(WatingButton)
+                    .on_press(Message::StopPreview),
+            )
+            .push(Container::new(launch_pick_list).width(Length::Units(120)))
+            .push(Element::<'_, Message>::from(
+                ParamKnob::new(
+                    launch_bars_param_ptr,
+                    &mut self.launch_bars_knob_drag_state,
+                    &mut self.launch_bars_knob_last_y,
+                )
+                .size(40)
+                .label("Bars")
+                .map(Message::ParamUpdate),
+            ))
+            .push(
+                Checkbox::new(self.params.loop_playback.value(), "Loop", move |value| {
+                    Message::SetBoolParam(loop_param_ptr, value)
+                })
+                .size(14)
+                .text_size(14)
+                .spacing(4),
+            );
+
+        //
+        //pour la génération automatique pendant la lecture
+        //
+
+        let auto_generate_row = Row::new()
+            .align_items(Alignment::Center)
+            .spacing(15)
+            .push(
+                Text::new("Auto generate: ")
+                    .font(assets::NOTO_SANS_BOLD)
+                    .horizontal_alignment(alignment::Horizontal::Center),
+            )
+            .push(Element::<'_, Message>::from(
+                ParamKnob::new(
+                    generate_every_param_ptr,
+                    &mut self.generate_every_knob_drag_state,
+                    &mut self.generate_every_knob_last_y,
+                )
+                .size(40)
+                .label("Every")
+                .map(Message::ParamUpdate),
+            ))
+            .push(Element::<'_, Message>::from(
+                ParamKnob::new(
+                    trigger_key_param_ptr,
+                    &mut self.trigger_key_knob_drag_state,
+                    &mut self.trigger_key_knob_last_y,
+                )
+                .size(40)
+                .label("Trigger key")
+                .map(Message::ParamUpdate),
+            ))
+            .push(Space::with_width(Length::Fill));
+
+        //
+        //pour le groove et l'humanisation
+        //
+
+        let swing_grid_pick_list = custom_pick_list(
+            &mut self.swing_grid_state,
+            &SwingGrid::ALL,
+            Some(self.params.swing_grid.value()),
+            |selected| selected,
+        )
+        .map(Message::SelectSwingGrid);
+
+        let groove_row = Row::new()
+            .align_items(Alignment::Center)
+            .spacing(15)
+            .push(Element::<'_, Message>::from(
+                ParamKnob::new(
+                    swing_param_ptr,
+                    &mut self.swing_knob_drag_state,
+                    &mut self.swing_knob_last_y,
+                )
+                .size(40)
+                .label("Swing")
+                .map(Message::ParamUpdate),
+            ))
+            .push(Container::new(swing_grid_pick_list).width(Length::Units(70)))
+            .push(Element::<'_, Message>::from(
+                ParamKnob::new(
+                    humanize_param_ptr,
+                    &mut self.humanize_knob_drag_state,
+                    &mut self.humanize_knob_last_y,
+                )
+                .size(40)
+                .label("Humanize")
+                .map(Message::ParamUpdate),
+            ))
+            .push(Element::<'_, Message>::from(
+                ParamKnob::new(
+                    velocity_random_param_ptr,
+                    &mut self.velocity_random_knob_drag_state,
+                    &mut self.velocity_random_knob_last_y,
+                )
+                .size(40)
+                .label("Velocity")
+                .map(Message::ParamUpdate),
+            ))
+            .push(Element::<'_, Message>::from(
+                ParamKnob::new(
+                    groove_amount_param_ptr,
+                    &mut self.groove_amount_knob_drag_state,
+                    &mut self.groove_amount_knob_last_y,
+                )
+                .size(40)
+                .label("Groove")
+                .map(Message::ParamUpdate),
+            ));
+
+        let groove_template_row = Row::new()
+            .align_items(Alignment::Center)
+            .spacing(10)
+            .push(
+                TextInput::new(
+                    &mut self.groove_path_state,
+                    "Groove template (.mid)",
+                    &self.groove_path,
+                    Message::GroovePathChanged,
+                )
+                .size(14)
+                .padding(4)
+                .width(Length::Fill),
+            )
+            .push(
+                Button::new(&mut self.load_groove_state, Text::new("Load").size(14))
+                    .style(GenerateButton)
+                    .on_press(Message::LoadGroove),
+            )
+            .push(
+                Button::new(&mut self.clear_groove_state, Text::new("Clear").size(14))
+                    .style(WatingButton)
+                    .on_press(Message::ClearGroove),
+            );
+
+        //
+        //pour la quantification de la génération
+        //
+
+        let quantize_grid_pick_list = custom_pick_list(
+            &mut self.quantize_grid_state,
+            &GridDivision::ALL,
+            Some(self.params.quantize_grid.value()),
+            |selected| selected,
+        )
+        .map(Message::SelectQuantizeGrid);
+
+        let quantize_feel_pick_list = custom_pick_list(
+            &mut self.quantize_feel_state,
+            &GridFeel::ALL,
+            Some(self.params.quantize_feel.value()),
+            |selected| selected,
+        )
+        .map(Message::SelectQuantizeFeel);
+
+        let grid_row = Row::new()
+            .align_items(Alignment::Center)
+            .spacing(15)
+            .push(
+                Column::new()
+                    .spacing(5)
+                    .width(Length::Units(100))
+                    .push(quantize_grid_pick_list)
+                    .push(quantize_feel_pick_list),
+            )
+            .push(Element::<'_, Message>::from(
+                ParamKnob::new(
+                    quantize_strength_param_ptr,
+                    &mut self.quantize_strength_knob_drag_state,
+                    &mut self.quantize_strength_knob_last_y,
+                )
+                .size(40)
+                .label("Strength")
+                .map(Message::ParamUpdate),
+            ))
+            .push(Element::<'_, Message>::from(
+                ParamKnob::new(
+                    quantize_range_param_ptr,
+                    &mut self.quantize_range_knob_drag_state,
+                    &mut self.quantize_range_knob_last_y,
+                )
+                .size(40)
+                .label("Range")
+                .map(Message::ParamUpdate),
+            ))
+            .push(Element::<'_, Message>::from(
+                ParamKnob::new(
+                    note_length_param_ptr,
+                    &mut self.note_length_knob_drag_state,
+                    &mut self.note_length_knob_last_y,
+                )
+                .size(40)
+                .label("Length")
+                .map(Message::ParamUpdate),
+            ));
+
+        let mut length_row = Row::new().align_items(Alignment::Center).spacing(15);
+        for param in [
+            &self.params.quantize_lengths,
+            &self.params.legato,
+            &self.params.remove_overlaps,
+            &self.params.bypass_processing,
+        ] {
+            let param_ptr = param.as_ptr();
+            length_row = length_row.push(
+                Checkbox::new(param.value(), param.name(), move |value| {
+                    Message::SetBoolParam(param_ptr, value)
+                })
+                .size(14)
+                .text_size(14)
+                .spacing(4),
+            );
+        }
+
+        //
+        //pour le rendu audio de la génération
+        //
+
+        let render_format_pick_list = custom_pick_list(
+            &mut self.render_format_state,
+            &AudioFormat::ALL,
+            Some(self.params.render_format.value()),
+            |selected| selected,
+        )
+        .map(Message::SelectRenderFormat);
+
+        let render_progress = self
+            .render_progress
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let mut render_button = Button::new(&mut self.render_state, Text::new("Render").size(14))
+            .style(GenerateButton);
+        let render_status = if render_progress == render::RENDER_IDLE {
+            if self.download_available.load(std::sync::atomic::Ordering::SeqCst) {
+                render_button = render_button.on_press(Message::RenderAudio);
+            }
+            String::new()
+        } else {
+            format!("Rendering... {}%", render_progress)
+        };
+
+        let render_row = Row::new()
+            .align_items(Alignment::Center)
+            .spacing(15)
+            .push(Container::new(render_format_pick_list).width(Length::Units(80)))
+            .push(Element::<'_, Message>::from(
+                ParamKnob::new(
+                    render_tail_param_ptr,
+                    &mut self.render_tail_knob_drag_state,
+                    &mut self.render_tail_knob_last_y,
+                )
+                .size(40)
+                .label("Tail")
+                .map(Message::ParamUpdate),
+            ))
+            .push(
+                Checkbox::new(
+                    self.params.render_normalize.value(),
+                    "Normalize",
+                    move |value| Message::SetBoolParam(render_normalize_param_ptr, value),
+                )
+                .size(14)
+                .text_size(14)
+                .spacing(4),
+            )
+            .push(render_button)
+            .push(Text::new(render_status).size(13));
+
+        let export_row = Row::new()
+            .align_items(Alignment::Center)
+            .spacing(15)
+            .push(
+                Checkbox::new(
+                    self.params.export_musicxml.value(),
+                    "MusicXML",
+                    move |value| Message::SetBoolParam(export_musicxml_param_ptr, value),
+                )
+                .size(14)
+                .text_size(14)
+                .spacing(4),
+            )
+            .push(
+                Checkbox::new(
+                    self.params.export_abc.value(),
+                    "ABC",
+                    move |value| Message::SetBoolParam(export_abc_param_ptr, value),
+                )
+                .size(14)
+                .text_size(14)
+                .spacing(4),
+            )
+            .push(
+                Checkbox::new(
+                    self.params.export_note_list.value(),
+                    "Note list",
+                    move |value| Message::SetBoolParam(export_note_list_param_ptr, value),
+                )
+                .size(14)
+                .text_size(14)
+                .spacing(4),
+            )
+            .push(Text::new("Written next to the MIDI download").size(13));
+
+        let mut copy_abc_button =
+            Button::new(&mut self.copy_abc_state, Text::new("Copy ABC").size(14)).style(GenerateButton);
+        let mut copy_note_list_button =
+            Button::new(&mut self.copy_note_list_state, Text::new("Copy notes").size(14))
+                .style(GenerateButton);
+        if self.download_available.load(std::sync::atomic::Ordering::SeqCst) {
+            copy_abc_button = copy_abc_button.on_press(Message::CopyNotation(NotationFormat::Abc));
+            copy_note_list_button =
+                copy_note_list_button.on_press(Message::CopyNotation(NotationFormat::NoteList));
+        }
+        let copy_row = Row::new()
+            .align_items(Alignment::Center)
+            .spacing(15)
+            .push(copy_abc_button)
+            .push(copy_note_list_button)
+            .push(Text::new(self.notation_status.as_str()).size(13));
+
+        let folder_row = Row::new()
+            .align_items(Alignment::Center)
+            .spacing(10)
+            .push(
+                TextInput::new(
+                    &mut self.downloads_folder_state,
+                    &self.default_downloads_folder,
+                    &self.downloads_folder,
+                    Message::DownloadsFolderChanged,
+                )
+                .size(14)
+                .padding(4)
+                .width(Length::Fill)
+                .on_submit(Message::ApplyDownloadsFolder),
+            )
+            .push(
+                Button::new(&mut self.apply_folder_state, Text::new("Set").size(14))
+                    .style(GenerateButton)
+                    .on_press(Message::ApplyDownloadsFolder),
+            )
+            .push(
+                Button::new(&mut self.default_folder_state, Text::new("Default").size(14))
+                    .style(WatingButton)
+                    .on_press(Message::ResetDownloadsFolder),
+            );
+
+        let file_name_row = Row::new()
+            .align_items(Alignment::Center)
+            .spacing(10)
+            .push(
+                TextInput::new(
+                    &mut self.file_name_template_state,
+                    file_naming::DEFAULT_TEMPLATE,
+                    &self.file_name_template,
+                    Message::FileNameTemplateChanged,
+                )
+                .size(14)
+                .padding(4)
+                .width(Length::Fill),
+            )
+            .push(
+                Button::new(&mut self.reset_template_state, Text::new("Default").size(14))
+                    .style(WatingButton)
+                    .on_press(Message::ResetFileNameTemplate),
+            );
+
+        let sub_folder = self.params.sub_folder.value();
+        let mut sub_folder_row = Row::new()
+            .align_items(Alignment::Center)
+            .spacing(10)
+            .push(
+                Container::new(
+                    custom_pick_list(
+                        &mut self.sub_folder_state,
+                        &SubFolder::ALL,
+                        Some(sub_folder),
+                        |selected| selected,
+                    )
+                    .map(Message::SelectSubFolder),
+                )
+                .width(Length::Units(130)),
+            );
+        if sub_folder == SubFolder::Project {
+            sub_folder_row = sub_folder_row.push(
+                TextInput::new(
+                    &mut self.project_folder_state,
+                    "Untitled Project",
+                    &self.project_folder,
+                    Message::ProjectFolderChanged,
+                )
+                .size(14)
+                .padding(4)
+                .width(Length::Fill),
+            );
+        }
+
+        // Notification of the last download, above the download button
+        let download_popup: Element<'_, Message> = match &self.download_status {
+            DownloadStatus::Hidden => Space::with_height(Length::Units(0)).into(),
+            DownloadStatus::InProgress(progress) => Row::new()
+                .align_items(Alignment::Center)
+                .spacing(10)
+                .push(Text::new("Downloading...").size(13))
+                .push(
+                    ProgressBar::new(0.0..=100.0, *progress as f32)
+                        .height(Length::Units(8))
+                        .width(Length::Fill),
+                )
+                .padding(5)
+                .into(),
+            DownloadStatus::Failed(error) => {
+                let mut row = Row::new()
+                    .align_items(Alignment::Center)
+                    .spacing(10)
+                    .push(
+                        Text::new(format!("Download failed: {}", error))
+                            .size(13)
+                            .color(Color::from_rgb(0.9, 0.3, 0.3))
+                            .width(Length::Fill),
+                    );
+                if self.last_export.is_some() {
+                    row = row.push(
+                        Button::new(&mut self.retry_state, Text::new("Retry").size(13))
+                            .style(GenerateButton)
+                            .on_press(Message::RetryDownload),
+                    );
+                }
+                row.push(
+                    Button::new(&mut self.dismiss_state, Text::new("Dismiss").size(13))
+                        .style(WatingButton)
+                        .on_press(Message::DismissPopup),
+                )
+                .padding(5)
+                .into()
+            }
+            DownloadStatus::Saved(path) => {
+                let name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                let message = if self.popup_status.is_empty() {
+                    format!("Saved {}", name)
+                } else {
+                    format!("Saved {} - {}", name, self.popup_status)
+                };
+                Container::new(
+                    Row::new()
+                        .align_items(Alignment::Center)
+                        .spacing(10)
+                        .push(Text::new(message).size(13).width(Length::Fill))
+                        .push(
+                            Button::new(&mut self.reveal_state, Text::new("Reveal in folder").size(13))
+                                .style(GenerateButton)
+                                .on_press(Message::RevealSavedFile),
+                        )
+                        .push(
+                            Button::new(&mut self.copy_path_state, Text::new("Copy path").size(13))
+                                .style(WatingButton)
+                                .on_press(Message::CopySavedPath),
+                        )
+                        .push(
+                            Button::new(&mut self.dismiss_state, Text::new("Dismiss").size(13))
+                                .style(WatingButton)
+                                .on_press(Message::DismissPopup),
+                        ),
+                )
+                .padding(5)
+                .width(Length::Fill)
+                .into()
+            }
+        };
 
         let title = Text::new("Harmonia")
             .font(assets::NOTO_SANS_LIGHT)
@@ -310,7 +1697,25 @@ impl IcedEditor for HarmoniaEditor {
                 .label("BPM")
                 .map(Message::ParamUpdate),
             ))
-            .push(Space::with_width(10.into()));
+            .push(Space::with_width(10.into()))
+            // Transposition de la génération
+            .push(
+                Column::new()
+                    .align_items(Alignment::Center)
+                    .spacing(5)
+                    .push(Text::new("Follow key").size(13))
+                    .push(Container::new(transpose_pick_list).width(Length::Units(110))),
+            )
+            .push(Element::<'_, Message>::from(
+                ParamKnob::new(
+                    octave_param_ptr,
+                    &mut self.octave_knob_drag_state,
+                    &mut self.octave_knob_last_y,
+                )
+                .size(50)
+                .label("Octave")
+                .map(Message::ParamUpdate),
+            ));
             // Potentiomètre Time Signature
             /*.push(Element::<'_, Message>::from(
                 ParamKnob::new(
@@ -323,48 +1728,142 @@ impl IcedEditor for HarmoniaEditor {
                 .map(Message::ParamUpdate),
             ));*/
 
+        let instrument_row = Row::new()
+            .push(
+                Text::new("Selection de l'instrument")
+                    .height(20.into())
+                    .width(Length::Fill)
+                    .horizontal_alignment(alignment::Horizontal::Left)
+                    .vertical_alignment(alignment::Vertical::Center),
+            )
+            .push(_style_pick_list)
+            .align_items(Alignment::Center);
+
+        let key_selection_row = Row::new()
+            .align_items(Alignment::Center)
+            .push(
+                Text::new("Mode: ")
+                    .font(assets::NOTO_SANS_BOLD)
+                    .horizontal_alignment(alignment::Horizontal::Center),
+            )
+            .push(Space::with_width(Length::Units(20)))
+            .push(_mode_pick_list)
+            .push(Space::with_width(Length::Units(40)))
+            .push(
+                Text::new("Note: ")
+                    .font(assets::NOTO_SANS_BOLD)
+                    .horizontal_alignment(alignment::Horizontal::Center),
+            )
+            .push(Space::with_width(Length::Units(20)))
+            .push(_note_pick_list);
+
+        let page_content: Element<'_, Message> = match self.page {
+            Page::Generate => Column::new()
+                .align_items(Alignment::Center)
+                .push(instrument_row)
+                .push(Space::with_height(10.into()))
+                .push(key_selection_row)
+                .push(Space::with_height(10.into()))
+                .push(key_row)
+                .push(Space::with_height(10.into()))
+                .push(quantize_row)
+                .push(Space::with_height(20.into()))
+                .push(_central_element)
+                .push(Space::with_height(10.into()))
+                .push(playback_row)
+                .push(Space::with_height(10.into()))
+                .push(auto_generate_row)
+                .into(),
+            Page::Parts => Column::new()
+                .push(Text::new("Ensemble").font(assets::NOTO_SANS_BOLD))
+                .push(Space::with_height(5.into()))
+                .push(add_part_row)
+                .push(Space::with_height(15.into()))
+                .push(parts_column)
+                .push(Space::with_height(20.into()))
+                .push(Text::new("Drums").font(assets::NOTO_SANS_BOLD))
+                .push(Space::with_height(5.into()))
+                .push(drum_grid_column)
+                .into(),
+            Page::Edit => Column::new()
+                .push(Text::new("Quantize").font(assets::NOTO_SANS_BOLD))
+                .push(Space::with_height(5.into()))
+                .push(grid_row)
+                .push(Space::with_height(5.into()))
+                .push(length_row)
+                .push(Space::with_height(20.into()))
+                .push(Text::new("Groove").font(assets::NOTO_SANS_BOLD))
+                .push(Space::with_height(5.into()))
+                .push(groove_row)
+                .push(Space::with_height(5.into()))
+                .push(groove_template_row)
+                .push(Text::new(self.groove_status.as_str()).size(13))
+                .into(),
+            Page::Export => Column::new()
+                .push(Text::new("Render").font(assets::NOTO_SANS_BOLD))
+                .push(Space::with_height(5.into()))
+                .push(render_row)
+                .push(Space::with_height(20.into()))
+                .push(Text::new("Export").font(assets::NOTO_SANS_BOLD))
+                .push(Space::with_height(5.into()))
+                .push(export_row)
+                .push(Space::with_height(5.into()))
+                .push(copy_row)
+                .push(Space::with_height(20.into()))
+                .push(Text::new("Files").font(assets::NOTO_SANS_BOLD))
+                .push(Space::with_height(5.into()))
+                .push(folder_row)
+                .push(Text::new(self.folder_status.as_str()).size(13))
+                .push(Space::with_height(5.into()))
+                .push(file_name_row)
+                .push(
+                    Text::new("Tokens: {style} {key} {mode} {bpm} {date} {seed} {n}").size(13),
+                )
+                .push(Space::with_height(5.into()))
+                .push(sub_folder_row)
+                .into(),
+        };
+
         Container::new(
             Column::new()
                 .padding(25)
                 .align_items(Alignment::Center)
                 .push(Row::new().push(title).push(version))
-                .push(Space::with_height(20.into()))
                 .push(
                     Row::new()
-                        .push(
-                            Text::new("Selection de l'instrument")
-                                .height(20.into())
-                                .width(Length::Fill)
-                                .horizontal_alignment(alignment::Horizontal::Left)
-                                .vertical_alignment(alignment::Vertical::Center),
-                        )
-                        .push(_style_pick_list)
-                        .align_items(Alignment::Center),
-                )
-                .push(Space::with_height(10.into()))
-                .push(
-                    Row::new()
-                        .align_items(Alignment::Center)
-                        .push(
-                            Text::new("Mode: ")
-                                .font(assets::NOTO_SANS_BOLD)
-                                .horizontal_alignment(alignment::Horizontal::Center),
-                        )
-                        .push(Space::with_width(Length::Units(20)))
-                        .push(_mode_pick_list)
-                        .push(Space::with_width(Length::Units(40)))
-                        .push(
-                            Text::new("Note: ")
-                                .font(assets::NOTO_SANS_BOLD)
-                                .horizontal_alignment(alignment::Horizontal::Center),
-                        )
-                        .push(Space::with_width(Length::Units(20)))
-                        .push(_note_pick_list),
+                        .spacing(10)
+                        .push(page_button(
+                            &mut self.generate_page_state,
+                            "Generate",
+                            Page::Generate,
+                            self.page,
+                        ))
+                        .push(page_button(
+                            &mut self.parts_page_state,
+                            "Parts",
+                            Page::Parts,
+                            self.page,
+                        ))
+                        .push(page_button(
+                            &mut self.edit_page_state,
+                            "Edit",
+                            Page::Edit,
+                            self.page,
+                        ))
+                        .push(page_button(
+                            &mut self.export_page_state,
+                            "Export",
+                            Page::Export,
+                            self.page,
+                        ))
+                        .push(Space::with_width(Length::Fill))
+                        .push(load_meter(&self.telemetry)),
                 )
-                .push(Space::with_height(30.into()))
-                .push(_central_element)
+                .push(Space::with_height(20.into()))
+                .push(page_content)
                 .push(visual_debug_info)
                 .push(Space::new(Length::Fill, Length::Fill))
+                .push(download_popup)
                 .push(
                     Row::new()
                         .push(Space::with_width(Length::Fill))
@@ -415,6 +1914,191 @@ impl IcedEditor for HarmoniaEditor {
     }
 }
 
+impl HarmoniaEditor {
+    /// Queues a command for the audio thread, taken at its next block. Returns false when the
+    /// audio thread is not processing and the queue is full.
+    fn send(&self, command: bus::Command) -> bool {
+        match self.commands.lock() {
+            Ok(mut commands) => commands.push(command).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// Saves the ensemble with the project and sends it to the audio thread
+    fn apply_ensemble(&self) {
+        if let Ok(mut persisted) = self.params.ensemble.write() {
+            *persisted = self.ensemble.clone();
+        }
+        self.send(bus::Command::SetEnsemble(bus::ensemble(&self.ensemble)));
+    }
+
+    /// Moves the mute, solo and channel of the parts after `removed` one slot down, so that they
+    /// stay with their part, and resets the slot freed at the end of the `count` parts
+    fn shift_part_params(&self, removed: usize, count: usize) {
+        let parts = &self.params.parts;
+        let values: Vec<(bool, bool, i32)> = parts
+            .iter()
+            .map(|part| (part.mute.value(), part.solo.value(), part.channel.value()))
+            .collect();
+        let setter = ParamSetter::new(self.context.as_ref());
+        for slot in removed..count.min(MAX_PARTS) {
+            let (mute, solo, channel) = if slot + 1 < count {
+                values[slot + 1]
+            } else {
+                (
+                    parts[slot].mute.default_plain_value(),
+                    parts[slot].solo.default_plain_value(),
+                    parts[slot].channel.default_plain_value(),
+                )
+            };
+            setter.begin_set_parameter(&parts[slot].mute);
+            setter.set_parameter(&parts[slot].mute, mute);
+            setter.end_set_parameter(&parts[slot].mute);
+            setter.begin_set_parameter(&parts[slot].solo);
+            setter.set_parameter(&parts[slot].solo, solo);
+            setter.end_set_parameter(&parts[slot].solo);
+            setter.begin_set_parameter(&parts[slot].channel);
+            setter.set_parameter(&parts[slot].channel, channel);
+            setter.end_set_parameter(&parts[slot].channel);
+        }
+    }
+}
+
+/// Load of the audio thread relative to the duration of the blocks, averaged over the last
+/// second, with the peak of the last seconds
+fn load_meter<'a>(telemetry: &Telemetry) -> Element<'a, Message> {
+    let (average, peak, latency) = match telemetry.snapshot() {
+        Some(block) => (
+            block.average_load * 100.0,
+            block.peak_load * 100.0,
+            block.budget().as_secs_f64() * 1000.0,
+        ),
+        None => (0.0, 0.0, 0.0),
+    };
+    let color = if peak > 50.0 {
+        Color::from_rgb(0.9, 0.3, 0.3)
+    } else {
+        Color::WHITE
+    };
+
+    Column::new()
+        .width(Length::Units(140))
+        .spacing(2)
+        .push(
+            Text::new(format!("DSP {:.1} % | peak {:.1} %", average, peak))
+                .size(11)
+                .color(color),
+        )
+        .push(
+            ProgressBar::new(0.0..=100.0, average.min(100.0))
+                .height(Length::Units(4))
+                .width(Length::Fill),
+        )
+        .push(Text::new(format!("Buffer {:.1} ms", latency)).size(11))
+        .into()
+}
+
+/// Entries of the debug info sorted by name, one per line
+fn debug_text(debug_info: &ThreadSafeMap<String, String>) -> String {
+    let mut entries: Vec<(String, String)> = match debug_info.snapshot() {
+        Ok(entries) => entries.into_iter().collect(),
+        Err(err) => return err.to_string(),
+    };
+    entries.sort();
+    entries
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// State of the audio thread during its last block, for the debug panel
+fn telemetry_view<'a>(telemetry: &Telemetry) -> Element<'a, Message> {
+    let Some(block) = telemetry.snapshot() else {
+        return Text::new("No block processed yet").size(13).into();
+    };
+
+    let position = match (block.bar_number, block.pos_beats) {
+        (Some(bar), Some(beats)) => format!(" | bar {} | beat {:.2}", bar + 1, beats),
+        _ => String::new(),
+    };
+    let transport = format!(
+        "{} | {:.2} BPM | {}/{}{}",
+        if block.playing { "Playing" } else { "Stopped" },
+        block.tempo,
+        block.time_sig_numerator,
+        block.time_sig_denominator,
+        position
+    );
+    let audio = format!(
+        "{} Hz | {} samples per block | {} samples per beat",
+        block.sample_rate, block.samples, block.samples_per_beat
+    );
+    let load = block.load();
+    let process = format!(
+        "Block processed in {:.3} ms of {:.3} ms ({:.1} %, average {:.1} %, peak {:.1} %)",
+        block.process_time.as_secs_f64() * 1000.0,
+        block.budget().as_secs_f64() * 1000.0,
+        load * 100.0,
+        block.average_load * 100.0,
+        block.peak_load * 100.0
+    );
+    let midi = format!(
+        "MIDI events sent: {} | notes dropped: {}",
+        block.midi_sent, block.midi_dropped
+    );
+    let queues = Queue::ALL
+        .iter()
+        .map(|queue| {
+            format!(
+                "{} {}/{}",
+                queue,
+                telemetry.queued(*queue),
+                queue.capacity()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" | ");
+
+    let process_color = if load > 0.5 {
+        Color::from_rgb(0.9, 0.3, 0.3)
+    } else {
+        Color::WHITE
+    };
+    Column::new()
+        .spacing(2)
+        .push(Text::new(transport).size(13))
+        .push(Text::new(audio).size(13))
+        .push(Text::new(process).size(13).color(process_color))
+        .push(Text::new(midi).size(13))
+        .push(Text::new(format!("Queues: {}", queues)).size(13))
+        .into()
+}
+
+/// Where the files are written, or why they cannot be
+fn folder_status(folder: &std::path::Path) -> String {
+    match file_naming::check_folder(folder) {
+        Ok(()) => format!("Files are written in {}", folder.display()),
+        Err(err) => err,
+    }
+}
+
+fn page_button<'a>(
+    state: &'a mut button::State,
+    label: &str,
+    page: Page,
+    current: Page,
+) -> Element<'a, Message> {
+    let button = Button::new(state, Text::new(label).size(14))
+        .on_press(Message::SelectPage(page))
+        .width(Length::Units(80));
+    if page == current {
+        button.style(GenerateButton).into()
+    } else {
+        button.style(WatingButton).into()
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Note {
     C,
@@ -455,6 +2139,44 @@ impl std::fmt::Display for Note {
     }
 }
 
+impl Note {
+    pub const ALL: [Note; 12] = [
+        Note::C,
+        Note::CSharp,
+        Note::D,
+        Note::DSharp,
+        Note::E,
+        Note::F,
+        Note::FSharp,
+        Note::G,
+        Note::GSharp,
+        Note::A,
+        Note::ASharp,
+        Note::B,
+    ];
+
+    /// Pitch class of the note, 0 being C
+    pub fn pitch_class(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_pitch_class(pitch_class: u8) -> Self {
+        Note::ALL[(pitch_class % 12) as usize]
+    }
+}
+
+impl Mode {
+    pub const ALL: [Mode; 2] = [Mode::Major, Mode::Minor];
+
+    /// Intervals in semitones from the root for each degree of the scale
+    pub fn intervals(self) -> [u8; 7] {
+        match self {
+            Mode::Major => [0, 2, 4, 5, 7, 9, 11],
+            Mode::Minor => [0, 2, 3, 5, 7, 8, 10],
+        }
+    }
+}
+
 impl std::fmt::Display for Mode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(match self {
@@ -476,9 +2198,59 @@ pub enum Message {
     Download,
     DownloadProgress(u8),
     DownloadError(String),
+    DownloadComplete(PathBuf),
+    RetryDownload,
+    ApplyKey(Note, Mode),
+    ClearKeyCapture,
+    SelectQuantizeMode(QuantizeMode),
+    SelectTransposeMode(TransposeMode),
+    SelectSwingGrid(SwingGrid),
+    GroovePathChanged(String),
+    LoadGroove,
+    ClearGroove,
+    SelectPage(Page),
+    SelectQuantizeGrid(GridDivision),
+    SelectQuantizeFeel(GridFeel),
+    SetBoolParam(ParamPtr, bool),
+    ToggleScaleDegree(usize, bool),
+    SelectLaunchQuantize(LaunchQuantize),
+    SelectPartStyle(Style),
+    AddPart,
+    RemovePart(usize),
+    SelectRenderFormat(AudioFormat),
+    RenderAudio,
+    CopyNotation(NotationFormat),
+    Tick,
+    RevealSavedFile,
+    CopySavedPath,
+    DismissPopup,
+    DownloadsFolderChanged(String),
+    ApplyDownloadsFolder,
+    ResetDownloadsFolder,
+    FileNameTemplateChanged(String),
+    ResetFileNameTemplate,
+    SelectSubFolder(SubFolder),
+    ProjectFolderChanged(String),
+    NotationCopied(Option<String>),
+    PlayPreview,
+    StopPreview,
+    SelectLogLevel(Level),
+    OpenLogFolder,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+impl From<Event> for Message {
+    fn from(event: Event) -> Self {
+        match event {
+            Event::DownloadAvailable => Message::DownloadLinkAvailableEditor(true),
+            Event::DownloadProgress(progress) => Message::DownloadProgress(progress),
+            Event::DownloadComplete(path) => Message::DownloadComplete(path),
+            Event::DownloadError(error) => Message::DownloadError(error),
+            Event::NotationCopied(text) => Message::NotationCopied(text),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Style {
     // Pianos
     AcousticGrand,
@@ -624,6 +2396,12 @@ pub enum Style {
     Helicopter,
     Applause,
     Gunshot,
+    // Drum kits, played on the drum channel
+    StandardKit,
+    RoomKit,
+    PowerKit,
+    ElectronicKit,
+    BrushKit,
 }
 
 
@@ -758,9 +2536,84 @@ impl Style {
             125 => Some(Style::Helicopter),
             126 => Some(Style::Applause),
             127 => Some(Style::Gunshot),
+            128 => Some(Style::StandardKit),
+            129 => Some(Style::RoomKit),
+            130 => Some(Style::PowerKit),
+            131 => Some(Style::ElectronicKit),
+            132 => Some(Style::BrushKit),
             _ => None,
         }
     }
+
+    /// Kit played by the drum styles
+    pub fn drum_kit(self) -> Option<DrumKit> {
+        match self {
+            Style::StandardKit => Some(DrumKit::Standard),
+            Style::RoomKit => Some(DrumKit::Room),
+            Style::PowerKit => Some(DrumKit::Power),
+            Style::ElectronicKit => Some(DrumKit::Electronic),
+            Style::BrushKit => Some(DrumKit::Brush),
+            _ => None,
+        }
+    }
+
+    pub fn is_drums(self) -> bool {
+        self.drum_kit().is_some()
+    }
+
+    /// General MIDI program number of the instrument, or of the kit on the drum channel
+    pub fn program(self) -> u8 {
+        match self.drum_kit() {
+            Some(kit) => kit.program(),
+            None => self as u8,
+        }
+    }
+
+    /// Playable range of the instrument, as MIDI notes
+    pub fn note_range(self) -> (u8, u8) {
+        if self.is_drums() {
+            return drums::PERCUSSION_RANGE;
+        }
+
+        match self {
+            Style::Violin => (55, 103),
+            Style::Viola => (48, 91),
+            Style::Cello => (36, 76),
+            Style::Contrabass => (28, 67),
+            Style::Timpani => (40, 57),
+            Style::Trumpet | Style::MutedTrumpet => (54, 86),
+            Style::Trombone => (40, 72),
+            Style::Tuba => (28, 58),
+            Style::FrenchHorn => (34, 77),
+            Style::SopranoSax => (56, 87),
+            Style::AltoSax => (49, 81),
+            Style::TenorSax => (44, 76),
+            Style::BaritoneSax => (36, 69),
+            Style::Oboe => (58, 91),
+            Style::EnglishHorn => (52, 81),
+            Style::Bassoon => (34, 75),
+            Style::Clarinet => (50, 94),
+            Style::Piccolo => (74, 108),
+            Style::Recorder => (72, 98),
+            _ => match self.program() {
+                // Pianos
+                0..=7 => (21, 108),
+                // Chromatic Percussion
+                8..=15 => (48, 108),
+                // Organs
+                16..=23 => (36, 96),
+                // Guitars
+                24..=31 => (40, 88),
+                // Basses
+                32..=39 => (28, 67),
+                // Strings, Ensemble and Brass
+                40..=63 => (28, 96),
+                // Reed and Pipe
+                64..=79 => (60, 96),
+                _ => (24, 108),
+            },
+        }
+    }
 }
 
 
@@ -895,6 +2748,11 @@ impl std::fmt::Display for Style {
             Style::Helicopter => "Helicopter",
             Style::Applause => "Applause",
             Style::Gunshot => "Gunshot",
+            Style::StandardKit => "Standard Kit",
+            Style::RoomKit => "Room Kit",
+            Style::PowerKit => "Power Kit",
+            Style::ElectronicKit => "Electronic Kit",
+            Style::BrushKit => "Brush Kit",
         })
     }
 }
@@ -0,0 +1,211 @@
+use crate::drums::DRUM_CHANNEL;
+use crate::editor::Style;
+use crate::preview::MusicEvent;
+use nih_plug::prelude::*;
+
+/// Maximum number of parts generated in a single request
+pub const MAX_PARTS: usize = 4;
+
+#[derive(Params)]
+pub struct PartParams {
+    #[id = "mute"]
+    pub mute: BoolParam,
+
+    #[id = "solo"]
+    pub solo: BoolParam,
+
+    /// Output channel of the part from 1 to 16, 0 for the channel assigned at generation
+    #[id = "channel"]
+    pub channel: IntParam,
+}
+
+impl PartParams {
+    pub fn new(part: usize) -> Self {
+        Self {
+            mute: BoolParam::new(format!("Part {} Mute", part), false),
+            solo: BoolParam::new(format!("Part {} Solo", part), false),
+            channel: IntParam::new(
+                format!("Part {} Channel", part),
+                0,
+                IntRange::Linear { min: 0, max: 16 },
+            )
+            .with_value_to_string(Arc::new(|value| match value {
+                0 => String::from("Auto"),
+                channel => channel.to_string(),
+            })),
+        }
+    }
+}
+
+/// Parts heard in the preview. When a part is soloed, only the soloed parts are heard.
+pub fn audible_parts(parts: &[PartParams; MAX_PARTS]) -> [bool; MAX_PARTS] {
+    let any_solo = parts.iter().any(|part| part.solo.value());
+    std::array::from_fn(|i| {
+        if any_solo {
+            parts[i].solo.value()
+        } else {
+            !parts[i].mute.value()
+        }
+    })
+}
+
+/// MIDI channel of a part, the drum channel being skipped
+pub fn part_channel(part: usize) -> u8 {
+    let channel = part.min(15) as u8;
+    if channel >= DRUM_CHANNEL {
+        (channel + 1).min(15)
+    } else {
+        channel
+    }
+}
+
+/// MIDI channel of a part playing `style`, the drum kits being played on the drum channel
+pub fn style_channel(part: usize, style: Style) -> u8 {
+    if style.is_drums() {
+        DRUM_CHANNEL
+    } else {
+        part_channel(part)
+    }
+}
+
+/// Routes the events of every part, identified by their track, to the channel of the part
+pub fn assign_channels(events: &mut [MusicEvent], parts: &[Style]) {
+    for event in events.iter_mut() {
+        let part = event.track as usize;
+        event.channel = match parts.get(part) {
+            Some(style) => style_channel(part, *style),
+            None => part_channel(part),
+        };
+    }
+}
+
+/// Output channel and program of every part, indexed by the `track` of the events
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Routing {
+    /// `None` keeps the channel of the events
+    pub channels: [Option<u8>; MAX_PARTS],
+    /// Program sent on the channel of the part before it is played, `None` to send nothing
+    pub programs: [Option<u8>; MAX_PARTS],
+}
+
+impl Routing {
+    pub fn new(params: &[PartParams; MAX_PARTS], parts: &[Style], send_programs: bool) -> Self {
+        let channels = std::array::from_fn(|i| match params[i].channel.value() {
+            0 => parts.get(i).map(|style| style_channel(i, *style)),
+            channel => Some((channel - 1) as u8),
+        });
+        let programs = std::array::from_fn(|i| {
+            parts
+                .get(i)
+                .filter(|_| send_programs)
+                .map(|style| style.program())
+        });
+
+        Routing { channels, programs }
+    }
+
+    /// Output channel of an event
+    pub fn channel(&self, event: &MusicEvent) -> u8 {
+        self.channels
+            .get(event.track as usize)
+            .copied()
+            .flatten()
+            .unwrap_or(event.channel & 0x0F)
+    }
+
+    pub fn apply_all(&self, events: &[MusicEvent]) -> Vec<MusicEvent> {
+        events
+            .iter()
+            .map(|event| MusicEvent {
+                channel: self.channel(event),
+                ..*event
+            })
+            .collect()
+    }
+
+    /// Program Change messages to send before playing, as `(channel, program)`
+    pub fn program_changes(&self) -> impl Iterator<Item = (u8, u8)> + '_ {
+        self.channels
+            .iter()
+            .zip(self.programs.iter())
+            .filter_map(|(channel, program)| Some(((*channel)?, (*program)?)))
+    }
+}
+
+impl Default for Routing {
+    fn default() -> Self {
+        Routing {
+            channels: [None; MAX_PARTS],
+            programs: [None; MAX_PARTS],
+        }
+    }
+}
+
+/// Playable range of every part, as MIDI notes. The drums and the parts not generated keep the
+/// whole MIDI range.
+pub fn note_ranges(parts: &[Style]) -> [(u8, u8); MAX_PARTS] {
+    std::array::from_fn(|part| match parts.get(part) {
+        Some(style) if !style.is_drums() => style.note_range(),
+        _ => (0, 127),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(track: u8) -> MusicEvent {
+        MusicEvent {
+            channel: 3,
+            duration: 1.0,
+            note: 60,
+            time: 0.0,
+            track,
+            velocity: 100,
+        }
+    }
+
+    #[test]
+    fn parts_skip_the_drum_channel() {
+        let channels: Vec<_> = [0, 1, 8, 9, 10, 14, 15, 20].map(part_channel).into();
+        assert_eq!(channels, [0, 1, 8, 10, 11, 15, 15, 15]);
+    }
+
+    #[test]
+    fn events_are_routed_to_their_part() {
+        let mut events = [event(0), event(1), event(2), event(3)];
+        assign_channels(
+            &mut events,
+            &[Style::AcousticBass, Style::StandardKit, Style::Violin],
+        );
+        let channels: Vec<_> = events.iter().map(|event| event.channel).collect();
+        assert_eq!(channels, [0, DRUM_CHANNEL, 2, 3]);
+    }
+
+    #[test]
+    fn every_melodic_part_has_its_own_range() {
+        assert_eq!(
+            note_ranges(&[Style::Violin, Style::PowerKit, Style::Tuba]),
+            [(55, 103), (0, 127), (28, 58), (0, 127)]
+        );
+        assert_eq!(note_ranges(&[]), [(0, 127); MAX_PARTS]);
+    }
+
+    #[test]
+    fn routing_overrides_the_channels_and_sends_the_programs() {
+        let routing = Routing {
+            channels: [Some(4), None, Some(DRUM_CHANNEL), None],
+            programs: [Some(32), Some(40), Some(24), None],
+        };
+        let events = routing.apply_all(&[event(0), event(1), event(2), event(5)]);
+        let channels: Vec<_> = events.iter().map(|event| event.channel).collect();
+        assert_eq!(channels, [4, 3, DRUM_CHANNEL, 3]);
+
+        // A part needs a channel for its program to be sent
+        let programs: Vec<_> = routing.program_changes().collect();
+        assert_eq!(programs, [(4, 32), (DRUM_CHANNEL, 24)]);
+
+        assert_eq!(Routing::default().channel(&event(0)), 3);
+        assert_eq!(Routing::default().program_changes().count(), 0);
+    }
+}
@@ -0,0 +1,400 @@
+use nih_plug::prelude::Enum;
+use std::fs::OpenOptions;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Template of the names of the exported files, used when the template of the user is empty
+pub const DEFAULT_TEMPLATE: &str = "{date}_{style}_{key}-{mode}_{bpm}bpm_{n}";
+
+/// Longest file name written, leaving room for the extension and the collision suffix below the
+/// 255 bytes allowed by most filesystems
+const MAX_NAME_LEN: usize = 120;
+
+/// Names reserved by Windows whatever their extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Sub-folder of the downloads folder the files are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubFolder {
+    None,
+    /// One folder per project, named in the editor and saved with the project of the host
+    Project,
+    /// One folder per day
+    Day,
+}
+
+impl Enum for SubFolder {
+    fn variants() -> &'static [&'static str] {
+        &["None", "Per Project", "Per Day"]
+    }
+
+    fn to_index(self) -> usize {
+        match self {
+            SubFolder::None => 0,
+            SubFolder::Project => 1,
+            SubFolder::Day => 2,
+        }
+    }
+
+    fn from_index(index: usize) -> Self {
+        match index {
+            1 => SubFolder::Project,
+            2 => SubFolder::Day,
+            _ => SubFolder::None,
+        }
+    }
+
+    fn ids() -> Option<&'static [&'static str]> {
+        None
+    }
+}
+
+impl SubFolder {
+    pub const ALL: [SubFolder; 3] = [SubFolder::None, SubFolder::Project, SubFolder::Day];
+}
+
+impl std::fmt::Display for SubFolder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SubFolder::None => "No sub-folder",
+            SubFolder::Project => "Per project",
+            SubFolder::Day => "Per day",
+        })
+    }
+}
+
+/// Values of the tokens of the template
+pub struct NameFields {
+    /// Styles of the parts joined together
+    pub style: String,
+    pub key: String,
+    pub mode: String,
+    pub bpm: f64,
+    /// Date of the export as `YYYY-MM-DD`
+    pub date: String,
+    pub seed: u64,
+}
+
+/// Today's date as `YYYY-MM-DD`, in UTC
+pub fn today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Date of a number of days since 1970-01-01 in the proleptic Gregorian calendar
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Replaces the tokens of the template. Unknown tokens are kept as they are, `{n}` being the
+/// number of the file.
+pub fn fill_template(template: &str, fields: &NameFields, n: u32) -> String {
+    let bpm = if fields.bpm.fract() == 0.0 {
+        format!("{}", fields.bpm)
+    } else {
+        format!("{:.1}", fields.bpm)
+    };
+
+    template
+        .replace("{style}", &fields.style)
+        .replace("{key}", &fields.key)
+        .replace("{mode}", &fields.mode)
+        .replace("{bpm}", &bpm)
+        .replace("{date}", &fields.date)
+        .replace("{seed}", &format!("{:08x}", fields.seed as u32))
+        .replace("{n}", &n.to_string())
+}
+
+/// Makes a name valid on Windows, macOS and Linux. The characters forbidden by one of them are
+/// replaced by `_`, the trailing dots and spaces are removed and the reserved names of Windows
+/// are suffixed.
+pub fn sanitize(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    if sanitized.len() > MAX_NAME_LEN {
+        let mut end = MAX_NAME_LEN;
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+        sanitized.truncate(end);
+    }
+
+    // Names starting with a dot are hidden on macOS and Linux
+    let trimmed = sanitized.trim_matches(|c: char| c == '.' || c.is_whitespace());
+    if trimmed.is_empty() {
+        return String::from("Harmonia");
+    }
+
+    let stem = trimmed.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        format!("{}_", trimmed)
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// Path of a new file in `folder`, named from the template with the extension `extension`.
+/// None of the `extensions` written next to it may exist yet: `{n}` is incremented until the
+/// name is free, and a number is appended when the name does not change with `{n}`.
+///
+/// The file and the ones next to it are created empty, so the name stays taken until they are
+/// written, even by another thread.
+pub fn unique_path(
+    folder: &Path,
+    template: &str,
+    fields: &NameFields,
+    extension: &str,
+    extensions: &[&str],
+) -> Result<PathBuf, String> {
+    let template = if template.trim().is_empty() {
+        DEFAULT_TEMPLATE
+    } else {
+        template
+    };
+
+    let first = sanitize(&fill_template(template, fields, 1));
+    let mut n = 1;
+    loop {
+        let mut stem = sanitize(&fill_template(template, fields, n));
+        if n > 1 && stem == first {
+            stem = format!("{}_{}", first, n);
+        }
+        let paths: Vec<PathBuf> = std::iter::once(extension)
+            .chain(extensions.iter().copied())
+            .map(|extension| folder.join(format!("{}.{}", stem, extension)))
+            .collect();
+        if create_new_files(&paths)? {
+            return Ok(paths[0].clone());
+        }
+        n += 1;
+    }
+}
+
+/// Creates all the files, or none of them when one already exists
+fn create_new_files(paths: &[PathBuf]) -> Result<bool, String> {
+    for (index, path) in paths.iter().enumerate() {
+        let created = OpenOptions::new().write(true).create_new(true).open(path);
+        if let Err(err) = created {
+            for path in &paths[..index] {
+                let _ = std::fs::remove_file(path);
+            }
+            return match err.kind() {
+                ErrorKind::AlreadyExists => Ok(false),
+                _ => Err(format!("Cannot create {}: {}", path.display(), err)),
+            };
+        }
+    }
+    Ok(true)
+}
+
+/// Folder of the exports when the user has not chosen one, `Harmonia` in the downloads folder
+/// of the user
+pub fn default_downloads_folder() -> PathBuf {
+    match dirs::download_dir() {
+        Some(path) => path.join("Harmonia"),
+        None => match dirs::home_dir() {
+            Some(path) => path.join("Downloads").join("Harmonia"),
+            None => std::env::current_dir()
+                .unwrap_or_default()
+                .join("Downloads"),
+        },
+    }
+}
+
+/// Folder chosen by the user, the default one when the path is empty
+pub fn downloads_folder(chosen: &str) -> PathBuf {
+    if chosen.trim().is_empty() {
+        default_downloads_folder()
+    } else {
+        PathBuf::from(chosen.trim())
+    }
+}
+
+/// Creates the folder if it is missing and checks files can be written in it
+pub fn check_folder(folder: &Path) -> Result<(), String> {
+    std::fs::create_dir_all(folder)
+        .map_err(|err| format!("Cannot create {}: {}", folder.display(), err))?;
+
+    let probe = folder.join(".harmonia-write-test");
+    std::fs::write(&probe, b"")
+        .map_err(|err| format!("Cannot write in {}: {}", folder.display(), err))?;
+    let _ = std::fs::remove_file(&probe);
+    Ok(())
+}
+
+/// Folder the files are written in, created if needed
+pub fn export_folder(
+    downloads_folder: &Path,
+    sub_folder: SubFolder,
+    project: &str,
+) -> Result<PathBuf, String> {
+    let folder = match sub_folder {
+        SubFolder::None => downloads_folder.to_path_buf(),
+        SubFolder::Project if project.trim().is_empty() => {
+            downloads_folder.join("Untitled Project")
+        }
+        SubFolder::Project => downloads_folder.join(sanitize(project)),
+        SubFolder::Day => downloads_folder.join(today()),
+    };
+    std::fs::create_dir_all(&folder)
+        .map_err(|err| format!("Cannot create {}: {}", folder.display(), err))?;
+    Ok(folder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Folder removed with everything in it once the test is done
+    struct TempFolder(PathBuf);
+
+    impl TempFolder {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("harmonia-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempFolder(path)
+        }
+    }
+
+    impl Drop for TempFolder {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn fields() -> NameFields {
+        NameFields {
+            style: String::from("Jazz+Bass"),
+            key: String::from("F#"),
+            mode: String::from("Minor"),
+            bpm: 92.5,
+            date: String::from("2024-02-29"),
+            seed: 0x1_2345_6789,
+        }
+    }
+
+    #[test]
+    fn dates_are_counted_from_1970() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19_783), (2024, 3, 1));
+    }
+
+    #[test]
+    fn templates_are_filled() {
+        assert_eq!(
+            fill_template(DEFAULT_TEMPLATE, &fields(), 3),
+            "2024-02-29_Jazz+Bass_F#-Minor_92.5bpm_3"
+        );
+        assert_eq!(
+            fill_template(
+                "{seed}_{bpm}_{unknown}",
+                &NameFields {
+                    bpm: 120.0,
+                    ..fields()
+                },
+                1
+            ),
+            "23456789_120_{unknown}"
+        );
+    }
+
+    #[test]
+    fn names_are_sanitized() {
+        assert_eq!(sanitize("a<b>c:d\"e/f\\g|h?i*j"), "a_b_c_d_e_f_g_h_i_j");
+        assert_eq!(sanitize("tab\there\n"), "tab_here_");
+        assert_eq!(sanitize("  .hidden. . "), "hidden");
+        assert_eq!(sanitize(" ... "), "Harmonia");
+        assert_eq!(sanitize(""), "Harmonia");
+        assert_eq!(sanitize("con"), "con_");
+        assert_eq!(sanitize("LPT1.backup"), "LPT1.backup_");
+        assert_eq!(sanitize("CONSOLE"), "CONSOLE");
+
+        // Long names are cut on a character boundary
+        let long = "é".repeat(100);
+        let sanitized = sanitize(&long);
+        assert_eq!(sanitized.len(), MAX_NAME_LEN);
+        assert!(long.starts_with(&sanitized));
+    }
+
+    #[test]
+    fn paths_do_not_overwrite_files() {
+        let folder = TempFolder::new("unique-path");
+        let path =
+            |template: &str| unique_path(&folder.0, template, &fields(), "mid", &["wav"]).unwrap();
+
+        // The name is taken as soon as it is given, before the files are written
+        assert_eq!(path("take {n}"), folder.0.join("take 1.mid"));
+        assert!(folder.0.join("take 1.wav").is_file());
+        assert_eq!(path("take {n}"), folder.0.join("take 2.mid"));
+
+        // The files written next to the MIDI file are taken into account
+        fs::write(folder.0.join("take 3.wav"), b"wav").unwrap();
+        assert_eq!(path("take {n}"), folder.0.join("take 4.mid"));
+        assert!(!folder.0.join("take 3.mid").exists());
+        assert_eq!(fs::read(folder.0.join("take 3.wav")).unwrap(), b"wav");
+
+        assert_eq!(path("{key}"), folder.0.join("F#.mid"));
+        assert_eq!(path("{key}"), folder.0.join("F#_2.mid"));
+
+        assert_eq!(
+            path("  "),
+            folder.0.join("2024-02-29_Jazz+Bass_F#-Minor_92.5bpm_1.mid")
+        );
+    }
+
+    #[test]
+    fn sub_folders_are_created() {
+        let folder = TempFolder::new("export-folder");
+        assert_eq!(
+            export_folder(&folder.0, SubFolder::None, "Song").unwrap(),
+            folder.0
+        );
+
+        let project = export_folder(&folder.0, SubFolder::Project, "Song: Take 2").unwrap();
+        assert_eq!(project, folder.0.join("Song_ Take 2"));
+        assert!(project.is_dir());
+
+        let untitled = export_folder(&folder.0, SubFolder::Project, " ").unwrap();
+        assert_eq!(untitled, folder.0.join("Untitled Project"));
+
+        let day = export_folder(&folder.0, SubFolder::Day, "").unwrap();
+        assert_eq!(day, folder.0.join(today()));
+        assert!(check_folder(&day).is_ok());
+        assert_eq!(fs::read_dir(&day).unwrap().count(), 0);
+    }
+}
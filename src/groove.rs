@@ -0,0 +1,353 @@
+use crate::preview::MusicEvent;
+use nih_plug::prelude::Enum;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Length of a step of the groove templates, in beats
+const TEMPLATE_STEP: f64 = 0.25;
+/// Largest number of steps of the groove templates, a bar of 8/4 in sixteenth notes
+const MAX_TEMPLATE_STEPS: usize = 32;
+/// Largest velocity change applied by the velocity randomization
+const MAX_VELOCITY_RANDOM: f32 = 40.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwingGrid {
+    Eighth,
+    Sixteenth,
+}
+
+impl Enum for SwingGrid {
+    fn variants() -> &'static [&'static str] {
+        &["1/8", "1/16"]
+    }
+
+    fn to_index(self) -> usize {
+        match self {
+            SwingGrid::Eighth => 0,
+            SwingGrid::Sixteenth => 1,
+        }
+    }
+
+    fn from_index(index: usize) -> Self {
+        match index {
+            1 => SwingGrid::Sixteenth,
+            _ => SwingGrid::Eighth,
+        }
+    }
+
+    fn ids() -> Option<&'static [&'static str]> {
+        None
+    }
+}
+
+impl SwingGrid {
+    pub const ALL: [SwingGrid; 2] = [SwingGrid::Eighth, SwingGrid::Sixteenth];
+
+    /// Length of the grid, in beats
+    pub fn beats(self) -> f64 {
+        match self {
+            SwingGrid::Eighth => 0.5,
+            SwingGrid::Sixteenth => 0.25,
+        }
+    }
+}
+
+impl std::fmt::Display for SwingGrid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(Self::variants()[self.to_index()])
+    }
+}
+
+/// Number of sixteenth notes in a bar of the time signature, the longest bars being cut to
+/// `MAX_TEMPLATE_STEPS`
+pub fn bar_steps(time_sig_numerator: i32, time_sig_denominator: i32) -> usize {
+    let steps = (time_sig_numerator as f64 * 16.0 / time_sig_denominator.max(1) as f64).round();
+    (steps.max(1.0) as usize).min(MAX_TEMPLATE_STEPS)
+}
+
+/// Timing and velocity of every sixteenth note of a bar, extracted from a MIDI file. The
+/// template repeats every bar of the time signature it has been learned in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GrooveTemplate {
+    /// Number of sixteenth notes in a bar, only the first `steps` values are used
+    pub steps: usize,
+    /// Offset from the grid, in beats
+    pub offsets: [f64; MAX_TEMPLATE_STEPS],
+    /// Velocity relative to the average velocity of the template
+    pub velocities: [f32; MAX_TEMPLATE_STEPS],
+}
+
+impl GrooveTemplate {
+    /// Averages the timing and velocity of the notes falling on each step of the bars of the
+    /// time signature. Returns `None` if there is no note to learn from.
+    pub fn from_events(
+        events: &[MusicEvent],
+        time_sig_numerator: i32,
+        time_sig_denominator: i32,
+    ) -> Option<Self> {
+        if events.is_empty() {
+            return None;
+        }
+
+        let steps = bar_steps(time_sig_numerator, time_sig_denominator);
+        let mut offsets = [0.0; MAX_TEMPLATE_STEPS];
+        let mut velocities = [0.0; MAX_TEMPLATE_STEPS];
+        let mut counts = [0u32; MAX_TEMPLATE_STEPS];
+        for event in events {
+            let step = (event.time / TEMPLATE_STEP).round();
+            let slot = (step as i64).rem_euclid(steps as i64) as usize;
+            offsets[slot] += event.time - step * TEMPLATE_STEP;
+            velocities[slot] += event.velocity as f32;
+            counts[slot] += 1;
+        }
+
+        let average_velocity = events
+            .iter()
+            .map(|event| event.velocity as f32)
+            .sum::<f32>()
+            / events.len() as f32;
+        for slot in 0..MAX_TEMPLATE_STEPS {
+            if counts[slot] == 0 {
+                velocities[slot] = 1.0;
+                continue;
+            }
+            offsets[slot] /= counts[slot] as f64;
+            velocities[slot] /= counts[slot] as f32 * average_velocity.max(1.0);
+        }
+
+        Some(GrooveTemplate {
+            steps,
+            offsets,
+            velocities,
+        })
+    }
+}
+
+/// Everything needed to apply the groove to a generation, built from the plugin parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GrooveSettings {
+    /// Between 0 (straight) and 1 (triplet feel)
+    pub swing: f32,
+    pub swing_grid: SwingGrid,
+    /// Largest random timing offset, in milliseconds
+    pub humanize_ms: f32,
+    /// Between 0 and 1
+    pub velocity_random: f32,
+    pub template: Option<GrooveTemplate>,
+    /// Between 0 and 1
+    pub template_amount: f32,
+    pub bpm: f64,
+    /// Seed of the randomization, so the preview and the export get the same result
+    pub seed: u64,
+}
+
+impl GrooveSettings {
+    pub fn apply(&self, events: &mut [MusicEvent]) {
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let humanize_beats = self.humanize_ms as f64 / 1000.0 * self.bpm / 60.0;
+        let grid = self.swing_grid.beats();
+
+        for event in events.iter_mut() {
+            let mut time = event.time;
+            let mut velocity = event.velocity as f32;
+
+            // Swing delays the notes on the off-beats of the grid
+            let step = (event.time / grid).round();
+            if (step as i64) % 2 != 0 {
+                time += self.swing as f64 * grid / 3.0;
+            }
+
+            if let Some(template) = &self.template {
+                let step = (event.time / TEMPLATE_STEP).round();
+                let slot = (step as i64).rem_euclid(template.steps.max(1) as i64) as usize;
+                time += template.offsets[slot] * self.template_amount as f64;
+                velocity *= 1.0 + (template.velocities[slot] - 1.0) * self.template_amount;
+            }
+
+            // The random values are always drawn so changing one amount does not change the
+            // other randomization
+            let timing_random: f64 = rng.gen_range(-1.0..=1.0);
+            let velocity_random: f32 = rng.gen_range(-1.0..=1.0);
+            time += timing_random * humanize_beats;
+            velocity += velocity_random * self.velocity_random * MAX_VELOCITY_RANDOM;
+
+            event.time = time.max(0.0);
+            event.velocity = velocity.round().clamp(1.0, 127.0) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(time: f64, velocity: u8) -> MusicEvent {
+        MusicEvent {
+            channel: 0,
+            duration: 0.25,
+            note: 60,
+            time,
+            track: 0,
+            velocity,
+        }
+    }
+
+    fn settings() -> GrooveSettings {
+        GrooveSettings {
+            swing: 0.0,
+            swing_grid: SwingGrid::Eighth,
+            humanize_ms: 0.0,
+            velocity_random: 0.0,
+            template: None,
+            template_amount: 0.0,
+            bpm: 120.0,
+            seed: 1,
+        }
+    }
+
+    #[test]
+    fn swing_delays_the_off_beats() {
+        let mut events: Vec<_> = (0..4).map(|step| event(step as f64 * 0.5, 100)).collect();
+        GrooveSettings {
+            swing: 1.0,
+            ..settings()
+        }
+        .apply(&mut events);
+        let times: Vec<_> = events.iter().map(|event| event.time).collect();
+        assert_eq!(times, [0.0, 0.5 + 0.5 / 3.0, 1.0, 1.5 + 0.5 / 3.0]);
+
+        let mut events: Vec<_> = (0..4).map(|step| event(step as f64 * 0.25, 100)).collect();
+        GrooveSettings {
+            swing: 0.5,
+            swing_grid: SwingGrid::Sixteenth,
+            ..settings()
+        }
+        .apply(&mut events);
+        let times: Vec<_> = events.iter().map(|event| event.time).collect();
+        assert_eq!(times, [0.0, 0.25 + 0.125 / 3.0, 0.5, 0.75 + 0.125 / 3.0]);
+    }
+
+    #[test]
+    fn randomization_is_repeated_with_the_same_seed() {
+        let notes: Vec<_> = (0..32).map(|step| event(step as f64 * 0.25, 64)).collect();
+        let groove = GrooveSettings {
+            humanize_ms: 20.0,
+            velocity_random: 0.5,
+            ..settings()
+        };
+        let apply = |groove: GrooveSettings| {
+            let mut events = notes.clone();
+            groove.apply(&mut events);
+            events
+                .iter()
+                .map(|event| (event.time, event.velocity))
+                .collect::<Vec<_>>()
+        };
+
+        let events = apply(groove);
+        assert_eq!(events, apply(groove));
+        assert_ne!(events, apply(GrooveSettings { seed: 2, ..groove }));
+        // 20 ms at 120 bpm is 0.04 beat, and half of the velocity randomization is 20
+        for (&(time, velocity), note) in events.iter().zip(&notes) {
+            assert!((time - note.time).abs() <= 0.04 + 1e-9);
+            assert!(velocity.abs_diff(note.velocity) <= 20);
+        }
+
+        // Changing the velocity amount keeps the timing
+        let other = apply(GrooveSettings {
+            velocity_random: 1.0,
+            ..groove
+        });
+        assert!(other.iter().zip(&events).all(|(a, b)| a.0 == b.0));
+    }
+
+    #[test]
+    fn notes_are_kept_in_the_midi_range() {
+        let mut events = [event(0.01, 127), event(0.5, 1), event(1.0, 64)];
+        GrooveSettings {
+            humanize_ms: 100.0,
+            velocity_random: 1.0,
+            template: Some(GrooveTemplate {
+                steps: 16,
+                offsets: [-0.1; MAX_TEMPLATE_STEPS],
+                velocities: [1.0; MAX_TEMPLATE_STEPS],
+            }),
+            template_amount: 1.0,
+            ..settings()
+        }
+        .apply(&mut events);
+        assert!(events.iter().all(|event| event.time >= 0.0));
+        assert!(events
+            .iter()
+            .all(|event| (1..=127).contains(&event.velocity)));
+    }
+
+    #[test]
+    fn templates_average_each_sixteenth() {
+        assert_eq!(GrooveTemplate::from_events(&[], 4, 4), None);
+
+        // Two bars played late on the second sixteenth, louder on the beat
+        let events = [
+            event(0.0, 120),
+            event(0.3, 60),
+            event(4.0, 120),
+            event(4.3, 60),
+        ];
+        let template = GrooveTemplate::from_events(&events, 4, 4).unwrap();
+        assert_eq!(template.steps, 16);
+        assert!(template.offsets[0].abs() < 1e-9);
+        assert!((template.offsets[1] - 0.05).abs() < 1e-9);
+        assert!((template.velocities[0] - 4.0 / 3.0).abs() < 1e-6);
+        assert!((template.velocities[1] - 2.0 / 3.0).abs() < 1e-6);
+        // Steps without notes are left alone
+        assert_eq!(template.offsets[2], 0.0);
+        assert_eq!(template.velocities[2], 1.0);
+
+        let mut events = [event(0.0, 90), event(0.25, 90), event(0.5, 90)];
+        GrooveSettings {
+            template: Some(template),
+            template_amount: 0.5,
+            ..settings()
+        }
+        .apply(&mut events);
+        let times: Vec<_> = events.iter().map(|event| event.time).collect();
+        let velocities: Vec<_> = events.iter().map(|event| event.velocity).collect();
+        assert!((times[1] - 0.275).abs() < 1e-9);
+        assert_eq!([times[0], times[2]], [0.0, 0.5]);
+        assert_eq!(velocities, [105, 75, 90]);
+    }
+
+    #[test]
+    fn templates_follow_the_time_signature() {
+        assert_eq!(bar_steps(4, 4), 16);
+        assert_eq!(bar_steps(3, 4), 12);
+        assert_eq!(bar_steps(6, 8), 12);
+        assert_eq!(bar_steps(7, 8), 14);
+        assert_eq!(bar_steps(5, 4), 20);
+        assert_eq!(bar_steps(12, 4), MAX_TEMPLATE_STEPS);
+        assert_eq!(bar_steps(0, 0), 1);
+
+        // Two bars of 3/4 played late on the first beat of the bar only
+        let events = [
+            event(0.1, 90),
+            event(1.0, 90),
+            event(3.1, 90),
+            event(4.0, 90),
+        ];
+        let template = GrooveTemplate::from_events(&events, 3, 4).unwrap();
+        assert_eq!(template.steps, 12);
+        assert!((template.offsets[0] - 0.1).abs() < 1e-9);
+        assert_eq!(template.offsets[4], 0.0);
+
+        // The second bar of 3/4 starts on the fourth beat
+        let mut events = [event(3.0, 90), event(4.0, 90)];
+        GrooveSettings {
+            template: Some(template),
+            template_amount: 1.0,
+            ..settings()
+        }
+        .apply(&mut events);
+        assert!((events[0].time - 3.1).abs() < 1e-9);
+        assert_eq!(events[1].time, 4.0);
+    }
+}
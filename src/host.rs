@@ -0,0 +1,90 @@
+use crate::Harmonia;
+use nih_plug::prelude::*;
+
+/// State of the host transport at the start of a block
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HostTransport {
+    pub playing: bool,
+    pub tempo: Option<f64>,
+    pub time_sig_numerator: Option<i32>,
+    pub time_sig_denominator: Option<i32>,
+    /// Position in quarter notes
+    pub pos_beats: Option<f64>,
+    pub bar_start_pos_beats: Option<f64>,
+    pub bar_number: Option<i32>,
+}
+
+/// What the processing of a block needs from the host. The transport of nih-plug cannot be
+/// built outside of it, so the blocks are processed through this trait and the real-time
+/// tests can drive them with a fake host.
+pub trait Host {
+    fn transport(&self) -> HostTransport;
+    fn next_event(&mut self) -> Option<NoteEvent<()>>;
+    fn send_event(&mut self, event: NoteEvent<()>);
+}
+
+/// Counts the events sent through another host
+pub struct CountingHost<'a, H> {
+    host: &'a mut H,
+    sent: u32,
+}
+
+impl<'a, H: Host> CountingHost<'a, H> {
+    pub fn new(host: &'a mut H) -> Self {
+        CountingHost { host, sent: 0 }
+    }
+
+    /// Number of events sent since the host was wrapped
+    pub fn sent(&self) -> u32 {
+        self.sent
+    }
+}
+
+impl<H: Host> Host for CountingHost<'_, H> {
+    fn transport(&self) -> HostTransport {
+        self.host.transport()
+    }
+
+    fn next_event(&mut self) -> Option<NoteEvent<()>> {
+        self.host.next_event()
+    }
+
+    fn send_event(&mut self, event: NoteEvent<()>) {
+        self.sent += 1;
+        self.host.send_event(event);
+    }
+}
+
+/// The process context given by nih-plug to `Plugin::process`
+pub struct ContextHost<'a, C> {
+    context: &'a mut C,
+}
+
+impl<'a, C: ProcessContext<Harmonia>> ContextHost<'a, C> {
+    pub fn new(context: &'a mut C) -> Self {
+        ContextHost { context }
+    }
+}
+
+impl<C: ProcessContext<Harmonia>> Host for ContextHost<'_, C> {
+    fn transport(&self) -> HostTransport {
+        let transport = self.context.transport();
+        HostTransport {
+            playing: transport.playing,
+            tempo: transport.tempo,
+            time_sig_numerator: transport.time_sig_numerator,
+            time_sig_denominator: transport.time_sig_denominator,
+            pos_beats: transport.pos_beats(),
+            bar_start_pos_beats: transport.bar_start_pos_beats(),
+            bar_number: transport.bar_number(),
+        }
+    }
+
+    fn next_event(&mut self) -> Option<NoteEvent<()>> {
+        self.context.next_event()
+    }
+
+    fn send_event(&mut self, event: NoteEvent<()>) {
+        self.context.send_event(event);
+    }
+}
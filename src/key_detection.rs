@@ -0,0 +1,220 @@
+use crate::editor::{Mode, Note};
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Krumhansl-Kessler key profiles, starting from the tonic
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// Weight given to every note-on, so that very short notes still count
+const ONSET_WEIGHT: f32 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyCandidate {
+    pub root: Note,
+    pub mode: Mode,
+    /// Pearson correlation between the captured notes and the key profile, in `[-1, 1]`
+    pub correlation: f32,
+}
+
+impl KeyCandidate {
+    /// Confidence in `[0, 1]` to display in the editor
+    pub fn confidence(&self) -> f32 {
+        self.correlation.clamp(0.0, 1.0)
+    }
+}
+
+impl std::fmt::Display for KeyCandidate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} ({:.0}%)",
+            self.root,
+            self.mode,
+            self.confidence() * 100.0
+        )
+    }
+}
+
+/// Estimates the key from a pitch class histogram. The candidates are sorted from the most to
+/// the least likely, and the result is empty if nothing has been captured yet.
+pub fn detect_key(histogram: &[f32; 12]) -> Vec<KeyCandidate> {
+    if histogram.iter().all(|weight| *weight <= 0.0) {
+        return Vec::new();
+    }
+
+    let mut candidates = Vec::with_capacity(24);
+    for root in Note::ALL {
+        for mode in Mode::ALL {
+            let profile = match mode {
+                Mode::Major => &MAJOR_PROFILE,
+                Mode::Minor => &MINOR_PROFILE,
+            };
+
+            // The profile is rotated so that its tonic lands on the candidate root
+            let rotated: [f32; 12] = std::array::from_fn(|pitch_class| {
+                profile[(pitch_class + 12 - root.pitch_class() as usize) % 12]
+            });
+
+            candidates.push(KeyCandidate {
+                root,
+                mode,
+                correlation: correlation(histogram, &rotated),
+            });
+        }
+    }
+
+    candidates.sort_by(|a, b| b.correlation.total_cmp(&a.correlation));
+    candidates
+}
+
+fn correlation(x: &[f32; 12], y: &[f32; 12]) -> f32 {
+    let mean_x = x.iter().sum::<f32>() / 12.0;
+    let mean_y = y.iter().sum::<f32>() / 12.0;
+
+    let mut covariance = 0.0;
+    let mut variance_x = 0.0;
+    let mut variance_y = 0.0;
+    for (a, b) in x.iter().zip(y) {
+        covariance += (a - mean_x) * (b - mean_y);
+        variance_x += (a - mean_x).powi(2);
+        variance_y += (b - mean_y).powi(2);
+    }
+
+    if variance_x <= 0.0 || variance_y <= 0.0 {
+        return 0.0;
+    }
+    covariance / (variance_x * variance_y).sqrt()
+}
+
+/// Pitch class histogram filled by the audio thread from the incoming MIDI notes and read by the
+/// editor. The weights are stored as `f32` bits so both sides can access it without locking.
+pub struct NoteCapture {
+    weights: [AtomicU32; 12],
+}
+
+impl NoteCapture {
+    pub fn new() -> Self {
+        NoteCapture {
+            weights: std::array::from_fn(|_| AtomicU32::new(0.0f32.to_bits())),
+        }
+    }
+
+    pub fn add(&self, pitch_class: u8, weight: f32) {
+        let slot = &self.weights[(pitch_class % 12) as usize];
+        let _ = slot.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f32::from_bits(bits) + weight).to_bits())
+        });
+    }
+
+    pub fn histogram(&self) -> [f32; 12] {
+        std::array::from_fn(|i| f32::from_bits(self.weights[i].load(Ordering::Relaxed)))
+    }
+
+    pub fn clear(&self) {
+        for slot in &self.weights {
+            slot.store(0.0f32.to_bits(), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Keeps track of the held notes on the audio thread so their duration can be added to the
+/// [`NoteCapture`] when they are released.
+pub struct NoteTracker {
+    started_at: [Option<u64>; 128],
+}
+
+impl NoteTracker {
+    pub fn new() -> Self {
+        NoteTracker {
+            started_at: [None; 128],
+        }
+    }
+
+    pub fn note_on(&mut self, note: u8, sample_pos: u64) {
+        self.started_at[(note & 127) as usize] = Some(sample_pos);
+    }
+
+    pub fn note_off(&mut self, capture: &NoteCapture, note: u8, sample_pos: u64, sample_rate: f32) {
+        if let Some(start) = self.started_at[(note & 127) as usize].take() {
+            let seconds = sample_pos.saturating_sub(start) as f32 / sample_rate;
+            capture.add(note % 12, ONSET_WEIGHT + seconds);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Histogram of a scale played up and down, with the notes of the tonic chord held longer
+    fn histogram(root: Note, intervals: &[u8], chord: [u8; 3]) -> [f32; 12] {
+        let mut histogram = [0.0; 12];
+        for interval in intervals {
+            histogram[((root.pitch_class() + interval) % 12) as usize] += 1.0;
+        }
+        for interval in chord {
+            histogram[((root.pitch_class() + interval) % 12) as usize] += 2.0;
+        }
+        histogram
+    }
+
+    fn best(histogram: &[f32; 12]) -> (Note, Mode) {
+        let candidate = detect_key(histogram)[0];
+        (candidate.root, candidate.mode)
+    }
+
+    #[test]
+    fn major_scales_are_detected() {
+        let major = [0, 2, 4, 5, 7, 9, 11];
+        for root in [Note::C, Note::D, Note::FSharp, Note::ASharp] {
+            assert_eq!(
+                best(&histogram(root, &major, [0, 4, 7])),
+                (root, Mode::Major)
+            );
+        }
+    }
+
+    #[test]
+    fn minor_scales_are_detected() {
+        let harmonic_minor = [0, 2, 3, 5, 7, 8, 11];
+        for root in [Note::A, Note::E, Note::CSharp] {
+            assert_eq!(
+                best(&histogram(root, &harmonic_minor, [0, 3, 7])),
+                (root, Mode::Minor)
+            );
+        }
+    }
+
+    #[test]
+    fn every_key_is_a_candidate() {
+        let candidates = detect_key(&histogram(Note::G, &[0, 4, 7], [0, 4, 7]));
+        assert_eq!(candidates.len(), 24);
+        assert!(candidates
+            .windows(2)
+            .all(|pair| pair[0].correlation >= pair[1].correlation));
+        assert!(candidates[0].confidence() > 0.5);
+        assert!(detect_key(&[0.0; 12]).is_empty());
+    }
+
+    #[test]
+    fn held_notes_weigh_more() {
+        let capture = NoteCapture::new();
+        let mut tracker = NoteTracker::new();
+        tracker.note_on(60, 0);
+        tracker.note_on(67, 0);
+        tracker.note_off(&capture, 60, 44100, 44100.0);
+        tracker.note_off(&capture, 67, 4410, 44100.0);
+        // Released twice, counted once
+        tracker.note_off(&capture, 67, 44100, 44100.0);
+
+        let histogram = capture.histogram();
+        assert!((histogram[0] - (1.0 + ONSET_WEIGHT)).abs() < 1e-6);
+        assert!((histogram[7] - (0.1 + ONSET_WEIGHT)).abs() < 1e-6);
+        capture.clear();
+        assert_eq!(capture.histogram(), [0.0; 12]);
+    }
+}
@@ -1,28 +1,65 @@
-
-// TODO:
-// - faire l'ouverture du dossier a l'emplacement du telechargement
-// - fair la popup au moment du telechargement
-
-
 mod ui;
 use nih_plug::prelude::*;
+use bus::{Command, Event, GenerationRequest, Job, Reply};
+use drums::DrumRow;
+use ensemble::{PartParams, Routing, MAX_PARTS};
+use file_naming::SubFolder;
+use groove::{GrooveSettings, GrooveTemplate, SwingGrid};
+use key_detection::{NoteCapture, NoteTracker};
+use logging::{Level, RtRecord};
 use nih_plug_iced::IcedState;
-use requester::Requester;
+use player::{LaunchQuantize, PreviewPlayer, TransportInfo};
+use preview::{Generation, MusicEvent, Processing};
+use quantize::{GridDivision, GridFeel, QuantizeSettings};
+use render::AudioFormat;
+use scale_quantizer::{QuantizeMode, ScaleDegreeParams, ScaleQuantizer};
+use telemetry::{BlockSnapshot, LoadMeter, Queue, Telemetry};
 use std::f32::consts::PI;
-use std::fs;
 use std::path::PathBuf;
-use std::sync::{mpsc, Arc};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 use thread_safe_map::ThreadSafeMap;
-use open;
+use transform::{Transform, TransposeMode};
+use worker::{SharedState, Worker};
+use host::{ContextHost, CountingHost, Host};
 
 // This is a shortened version of the gain example with most comments removed, check out
 // https://github.com/robbert-vdh/nih-plug/blob/master/plugins/examples/gain/src/lib.rs to get
 // started
-use crate::editor::Message;
-use crate::editor::Style;
+use crate::editor::{Mode, Note, Style};
+mod bus;
+pub mod cli;
+mod drums;
 mod editor;
+mod ensemble;
+mod file_naming;
+mod groove;
+mod host;
+mod key_detection;
+mod logging;
+mod midi_file;
+mod musicxml;
+mod notation;
+mod player;
+mod preview;
+mod quantize;
+mod render;
 mod requester;
+// nih-plug only checks the allocations in debug builds
+#[cfg(all(test, debug_assertions))]
+mod rt_safety;
+mod scale_quantizer;
+mod spsc;
+mod telemetry;
 mod thread_safe_map;
+mod transform;
+mod worker;
+
+/// Server the generations are requested from
+const API_URL: &str = "https://harmonia-api.home.spyr.dev";
+/// Replaced generations kept while the job queue is full, past which the replies of the worker
+/// wait in their queue
+const MAX_RELEASED_GENERATIONS: usize = 4;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TimeSignature {
@@ -64,26 +101,74 @@ pub struct Harmonia {
     ///
     /// This is stored as voltage gain.
     current_tempo: f64,
-    scale: String,
+    root: Note,
+    mode: Mode,
     time_sig_numerator: i32,
     time_sig_denominator: i32,
 
     phase: f32,
     sample_rate: f32,
     samples_per_beat: usize,
+    /// Number of samples processed since the plugin was initialized
+    sample_pos: u64,
 
-    selected_style: Option<Style>,
+    // Notes played into the plugin, used to guess the key of the track
+    note_capture: Arc<NoteCapture>,
+    note_tracker: NoteTracker,
 
-    download_link: Option<String>,
-    downloads_folder: Option<PathBuf>,
-    debug_info: ThreadSafeMap<String, String>,
+    /// Drums of the last generation, shown in the editor
+    drum_grid: Arc<RwLock<Vec<DrumRow>>>,
 
-    download_available: Arc<std::sync::atomic::AtomicBool>,
+    scale_quantizer: ScaleQuantizer,
 
+    selected_style: Option<Style>,
+    /// Styles of the parts generated together, the selected style is used when it is empty
+    ensemble: [Option<Style>; MAX_PARTS],
+
+    /// The last generation, played through the MIDI output and saved on download
+    generation: Option<Generation>,
+    /// Generations replaced on the audio thread, kept until the job queue has room to send them
+    /// to the worker
+    released_generations: Vec<Generation>,
+    player: PreviewPlayer,
+    /// Whether the host transport was playing during the previous block
+    was_playing: bool,
+    /// Bar of the host during the previous block, for the automatic generation
+    last_bar: Option<i32>,
+    /// Value of the `Generate` parameter during the previous block
+    last_generate_param: bool,
+    /// Set when a trigger asks for a new generation during the block
+    generate_requested: bool,
+    groove_template: Option<GrooveTemplate>,
+    /// Processing the events loaded in the player have been computed with, or requested from
+    /// the worker
+    applied_processing: Option<Processing>,
+    /// State of the audio thread shown in the debug panel
+    telemetry: Arc<Telemetry>,
+    load_meter: LoadMeter,
+    /// MIDI events sent to the host since the plugin was created
+    midi_sent: u64,
+    /// Notes of the preview the player could not play since the plugin was created
+    midi_dropped: u64,
+    debug_info: ThreadSafeMap<String, String>,
 
-    requester: Requester,
-    message_receiver: mpsc::Receiver<Message>,
-    message_sender: mpsc::Sender<Message>,
+    download_available: Arc<std::sync::atomic::AtomicBool>,
+    /// Progress of the audio render, shared with the editor
+    render_progress: Arc<std::sync::atomic::AtomicU8>,
+
+
+    /// Commands of the editor, drained at every block
+    commands: spsc::Consumer<Command>,
+    /// Handed to every editor opened, only one of them being open at a time
+    command_sender: Arc<Mutex<spsc::Producer<Command>>>,
+    jobs: spsc::Producer<Job>,
+    replies: spsc::Consumer<Reply>,
+    /// Kept by the plugin so the events wait for the editor to be opened
+    editor_events: Arc<Mutex<spsc::Consumer<Event>>>,
+    /// Records written in the log by the worker
+    log: spsc::Producer<RtRecord>,
+    /// Requests the generations and writes the exports, see `bus` for the threads involved
+    worker: Worker,
 }
 
 #[derive(Params)]
@@ -101,42 +186,230 @@ struct HarmoniaParams {
     #[persist = "editor-state"]
     editor_state: Arc<IcedState>,
 
+    /// Folder the files are exported in, the default folder when empty
+    #[persist = "downloads-folder"]
+    pub downloads_folder: Arc<RwLock<String>>,
+
+    /// Template of the names of the exported files, see `file_naming::fill_template`
+    #[persist = "file-name-template"]
+    pub file_name_template: Arc<RwLock<String>>,
+
+    /// Name of the sub-folder of the project, saved with the project of the host
+    #[persist = "project-folder"]
+    pub project_folder: Arc<RwLock<String>>,
+
+    /// Styles of the parts generated together, saved with the project of the host
+    #[persist = "ensemble"]
+    pub ensemble: Arc<RwLock<Vec<Style>>>,
+
+    #[id = "sub_folder"]
+    pub sub_folder: EnumParam<SubFolder>,
+
     #[id = "bpm"]
     bpm: FloatParam,
 
     scale: String,
 
     time_signature: EnumParam<TimeSignature>,
+
+    /// Snaps the incoming notes to the selected scale when the plugin is used as a note effect
+    #[id = "scale_quantize"]
+    pub scale_quantize: EnumParam<QuantizeMode>,
+
+    #[nested(array, group = "Parts")]
+    pub parts: [PartParams; MAX_PARTS],
+
+    /// Sends the program of every part when the preview starts, and writes it in the exports
+    #[id = "program_change"]
+    pub program_change: BoolParam,
+
+    /// Writes a MusicXML score next to the downloaded MIDI file
+    #[id = "export_musicxml"]
+    pub export_musicxml: BoolParam,
+
+    /// Writes an ABC tune next to the downloaded MIDI file
+    #[id = "export_abc"]
+    pub export_abc: BoolParam,
+
+    /// Writes the list of the notes next to the downloaded MIDI file
+    #[id = "export_note_list"]
+    pub export_note_list: BoolParam,
+
+    #[id = "render_format"]
+    pub render_format: EnumParam<AudioFormat>,
+
+    /// Time rendered after the last note, in seconds
+    #[id = "render_tail"]
+    pub render_tail: FloatParam,
+
+    #[id = "render_normalize"]
+    pub render_normalize: BoolParam,
+
+    #[nested(array, group = "Scale Degrees")]
+    pub scale_degrees: [ScaleDegreeParams; 7],
+
+    /// Moves the generated part to the key selected in the editor without generating it again
+    #[id = "transpose_mode"]
+    pub transpose_mode: EnumParam<TransposeMode>,
+
+    #[id = "transpose_octaves"]
+    pub transpose_octaves: IntParam,
+
+    #[id = "swing"]
+    pub swing: FloatParam,
+
+    #[id = "swing_grid"]
+    pub swing_grid: EnumParam<SwingGrid>,
+
+    #[id = "humanize_timing"]
+    pub humanize_timing: FloatParam,
+
+    #[id = "velocity_random"]
+    pub velocity_random: FloatParam,
+
+    /// How much the groove template loaded in the editor is applied
+    #[id = "groove_amount"]
+    pub groove_amount: FloatParam,
+
+    #[id = "quantize_grid"]
+    pub quantize_grid: EnumParam<GridDivision>,
+
+    #[id = "quantize_feel"]
+    pub quantize_feel: EnumParam<GridFeel>,
+
+    #[id = "quantize_strength"]
+    pub quantize_strength: FloatParam,
+
+    /// Only the notes close enough to the grid are quantized
+    #[id = "quantize_range"]
+    pub quantize_range: FloatParam,
+
+    #[id = "quantize_lengths"]
+    pub quantize_lengths: BoolParam,
+
+    #[id = "legato"]
+    pub legato: BoolParam,
+
+    #[id = "note_length"]
+    pub note_length: FloatParam,
+
+    #[id = "remove_overlaps"]
+    pub remove_overlaps: BoolParam,
+
+    /// Plays and saves the generation without the quantization and the groove
+    #[id = "bypass_processing"]
+    pub bypass_processing: BoolParam,
+
+    #[id = "launch_quantize"]
+    pub launch_quantize: EnumParam<LaunchQuantize>,
+
+    /// Number of bars used by the `Next N Bars` launch mode
+    #[id = "launch_bars"]
+    pub launch_bars: IntParam,
+
+    /// Repeats the preview while the transport is playing
+    #[id = "loop_playback"]
+    pub loop_playback: BoolParam,
+
+    /// Generates automatically while the transport is playing, every N bars, 0 to disable
+    #[id = "generate_every"]
+    pub generate_every: IntParam,
+
+    /// Incoming note starting a generation instead of being played, -1 to disable
+    #[id = "trigger_key"]
+    pub trigger_key: IntParam,
+
+    /// Starts a generation when switched on, so it can be automated by the host
+    #[id = "generate"]
+    pub generate: BoolParam,
 }
 
 impl Default for Harmonia {
     fn default() -> Self {
-        let (message_sender, message_receiver) = mpsc::channel();
+        Self::new(String::from(API_URL))
+    }
+}
+
+impl Harmonia {
+    /// Creates the plugin and starts its worker, sending the requests to `api_url`
+    fn new(api_url: String) -> Self {
+        let params = Arc::new(HarmoniaParams::default());
+        let debug_info = ThreadSafeMap::new();
+        let telemetry = Arc::new(Telemetry::new());
+        let drum_grid = Arc::new(RwLock::new(Vec::new()));
+        let download_available = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let render_progress = Arc::new(std::sync::atomic::AtomicU8::new(render::RENDER_IDLE));
+
+        let (command_sender, commands) = spsc::channel(bus::COMMAND_CAPACITY);
+        let (jobs, worker_jobs) = spsc::channel(bus::JOB_CAPACITY);
+        let (worker_replies, replies) = spsc::channel(bus::REPLY_CAPACITY);
+        let (worker_events, editor_events) = spsc::channel(bus::EVENT_CAPACITY);
+        let (log, worker_log) = spsc::channel(logging::RT_CAPACITY);
+        let worker = Worker::spawn(
+            api_url,
+            params.clone(),
+            SharedState {
+                drum_grid: drum_grid.clone(),
+                download_available: download_available.clone(),
+                render_progress: render_progress.clone(),
+                telemetry: telemetry.clone(),
+                debug_info: debug_info.clone(),
+            },
+            worker_jobs,
+            worker_replies,
+            worker_events,
+            worker_log,
+        );
+        logging::log_info!("Harmonia {} started", Self::VERSION);
+
         Self {
-            params: Arc::new(HarmoniaParams::default()),
+            params,
 
             current_tempo: 120.0,
             time_sig_numerator: 4,
-            scale: String::from("C#"),
+            root: Note::CSharp,
+            mode: Mode::Major,
             time_sig_denominator: 0,
             phase: 0.0,
             sample_rate: 44100.0,
             samples_per_beat: 0,
+            sample_pos: 0,
 
-            selected_style: None,
+            note_capture: Arc::new(NoteCapture::new()),
+            note_tracker: NoteTracker::new(),
 
-            download_available: Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            drum_grid,
 
-            download_link: None,
-            downloads_folder: None,
-            debug_info: ThreadSafeMap::new(),
+            scale_quantizer: ScaleQuantizer::new(),
 
-            requester: Requester::new(
-                String::from("https://harmonia-api.home.spyr.dev"),
-                message_sender.clone(),
-            ),
-            message_sender,
-            message_receiver,
+            selected_style: None,
+            ensemble: [None; MAX_PARTS],
+
+            download_available,
+            render_progress,
+
+            generation: None,
+            released_generations: Vec::with_capacity(MAX_RELEASED_GENERATIONS),
+            player: PreviewPlayer::new(),
+            was_playing: false,
+            last_bar: None,
+            last_generate_param: false,
+            generate_requested: false,
+            groove_template: None,
+            applied_processing: None,
+            telemetry,
+            load_meter: LoadMeter::new(),
+            midi_sent: 0,
+            midi_dropped: 0,
+            debug_info,
+
+            commands,
+            command_sender: Arc::new(Mutex::new(command_sender)),
+            jobs,
+            replies,
+            editor_events: Arc::new(Mutex::new(editor_events)),
+            log,
+            worker,
         }
     }
 }
@@ -162,6 +435,106 @@ impl Default for HarmoniaParams {
                 "Time Signature",
                 TimeSignature::FourFour, // Utilisez une valeur de type TimeSignature comme valeur par défaut
             ),
+            scale_quantize: EnumParam::new("Scale Quantize", QuantizeMode::Off),
+            scale_degrees: std::array::from_fn(|i| ScaleDegreeParams::new(i + 1)),
+            parts: std::array::from_fn(|i| PartParams::new(i + 1)),
+            program_change: BoolParam::new("Program Change", true),
+            downloads_folder: Arc::new(RwLock::new(String::new())),
+            file_name_template: Arc::new(RwLock::new(String::from(file_naming::DEFAULT_TEMPLATE))),
+            project_folder: Arc::new(RwLock::new(String::new())),
+            ensemble: Arc::new(RwLock::new(Vec::new())),
+            sub_folder: EnumParam::new("Sub-folder", SubFolder::None),
+            export_musicxml: BoolParam::new("MusicXML", true),
+            export_abc: BoolParam::new("ABC", false),
+            export_note_list: BoolParam::new("Note List", false),
+            render_format: EnumParam::new("Render Format", AudioFormat::Wav),
+            render_tail: FloatParam::new("Render Tail", 2.0, FloatRange::Linear { min: 0.0, max: 10.0 })
+                .with_unit(" s")
+                .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            render_normalize: BoolParam::new("Normalize", true),
+            transpose_mode: EnumParam::new("Transpose", TransposeMode::Off),
+            transpose_octaves: IntParam::new("Octave", 0, IntRange::Linear { min: -3, max: 3 }),
+            swing: FloatParam::new("Swing", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_unit("%")
+                .with_value_to_string(formatters::v2s_f32_percentage(0))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            swing_grid: EnumParam::new("Swing Grid", SwingGrid::Sixteenth),
+            humanize_timing: FloatParam::new(
+                "Humanize Timing",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 50.0 },
+            )
+            .with_unit(" ms")
+            .with_step_size(0.1),
+            velocity_random: FloatParam::new(
+                "Velocity Random",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            groove_amount: FloatParam::new(
+                "Groove Amount",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            quantize_grid: EnumParam::new("Quantize Grid", GridDivision::Sixteenth),
+            quantize_feel: EnumParam::new("Quantize Feel", GridFeel::Straight),
+            quantize_strength: FloatParam::new(
+                "Quantize Strength",
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            quantize_range: FloatParam::new(
+                "Quantize Range",
+                1.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            quantize_lengths: BoolParam::new("Quantize Lengths", false),
+            legato: BoolParam::new("Legato", false),
+            note_length: FloatParam::new(
+                "Note Length",
+                1.0,
+                FloatRange::Linear { min: 0.1, max: 2.0 },
+            )
+            .with_unit("%")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            remove_overlaps: BoolParam::new("Remove Overlaps", false),
+            bypass_processing: BoolParam::new("Bypass Processing", false),
+
+            launch_quantize: EnumParam::new("Launch", LaunchQuantize::NextBar),
+            launch_bars: IntParam::new("Launch Bars", 4, IntRange::Linear { min: 1, max: 16 }),
+            loop_playback: BoolParam::new("Loop", true),
+
+            generate_every: IntParam::new("Generate Every", 0, IntRange::Linear { min: 0, max: 32 })
+                .with_value_to_string(Arc::new(|value| match value {
+                    0 => String::from("Off"),
+                    1 => String::from("1 bar"),
+                    bars => format!("{} bars", bars),
+                })),
+            trigger_key: IntParam::new("Trigger Key", -1, IntRange::Linear { min: -1, max: 127 })
+                .with_value_to_string({
+                    let note_name = formatters::v2s_i32_note_formatter();
+                    Arc::new(move |value| {
+                        if value < 0 {
+                            String::from("Off")
+                        } else {
+                            note_name(value)
+                        }
+                    })
+                }),
+            generate: BoolParam::new("Generate", false),
 
             gain: FloatParam::new(
                 "Gain",
@@ -188,6 +561,18 @@ impl Default for HarmoniaParams {
     }
 }
 
+impl HarmoniaParams {
+    /// Folder chosen in the editor, or the default one
+    fn downloads_folder(&self) -> PathBuf {
+        let chosen = self
+            .downloads_folder
+            .read()
+            .map(|folder| folder.clone())
+            .unwrap_or_default();
+        file_naming::downloads_folder(&chosen)
+    }
+}
+
 impl Plugin for Harmonia {
     const NAME: &'static str = "Harmonia";
     const VENDOR: &'static str = "Romain Spychala";
@@ -211,8 +596,9 @@ impl Plugin for Harmonia {
         names: PortNames::const_default(),
     }];
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::None;
-    const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
+    // nih-plug only exposes a single note port, the parts are told apart by their channel
+    const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
 
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
@@ -243,21 +629,21 @@ impl Plugin for Harmonia {
 
         self.samples_per_beat = (self.sample_rate * 60.0 / self.current_tempo as f32) as usize;
 
-        // Creating the folder containing the generated files
-        let folder = match dirs::download_dir() {
-            Some(path) => path.join("Harmonia"),
-            None => match dirs::home_dir() {
-                Some(path) => path.join("Downloads").join("Harmonia"),
-                None => PathBuf::from(std::env::current_dir().unwrap_or_default()).join("Downloads"),
-            },
+        // Creating the folder containing the generated files. A folder that cannot be written
+        // is reported when exporting, the plugin itself keeps working.
+        let folder = self.params.downloads_folder();
+        let status = match file_naming::check_folder(&folder) {
+            Ok(()) => folder.to_string_lossy().to_string(),
+            Err(err) => {
+                logging::log_warn!("{}", err);
+                err
+            }
         };
+        _ = self.debug_info.insert(String::from("Folder"), status);
 
-        if let Ok(_) = fs::create_dir_all(&folder) {
-            _ = self
-                .debug_info
-                .insert(String::from("Folder"), folder.to_string_lossy().to_string());
-
-            self.downloads_folder = Some(folder);
+        // The ensemble saved with the project, the editor sends the later changes
+        if let Ok(parts) = self.params.ensemble.read() {
+            self.ensemble = bus::ensemble(&parts);
         }
 
         true
@@ -266,6 +652,7 @@ impl Plugin for Harmonia {
     fn reset(&mut self) {
         // Reset buffers and envelopes here. This can be called from the audio thread and may not
         // allocate. You can remove this function if you do not need it.
+        self.scale_quantizer.reset();
     }
 
     // Call the GUI
@@ -274,9 +661,13 @@ impl Plugin for Harmonia {
             self.params.clone(),
             self.params.editor_state.clone(),
             self.debug_info.clone(),
-            self.message_sender.clone(),
-            self.message_sender.clone(),
+            self.telemetry.clone(),
+            self.command_sender.clone(),
             self.download_available.clone(),
+            self.note_capture.clone(),
+            self.drum_grid.clone(),
+            self.render_progress.clone(),
+            self.editor_events.clone(),
         )
     }
 
@@ -286,13 +677,59 @@ impl Plugin for Harmonia {
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        let transport = context.transport();
+        self.process_block(&mut ContextHost::new(context), buffer.samples());
+        ProcessStatus::Normal
+    }
+}
+
+impl ClapPlugin for Harmonia {
+    const CLAP_ID: &'static str = "dev.spyr.harmonia";
+    const CLAP_DESCRIPTION: Option<&'static str> =
+        Some("The power of music generation inside your DAW");
+    const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
+    const CLAP_SUPPORT_URL: Option<&'static str> = None;
+
+    const CLAP_FEATURES: &'static [ClapFeature] = &[
+        ClapFeature::Instrument,
+        ClapFeature::NoteEffect,
+        ClapFeature::Utility,
+    ];
+}
+
+impl Vst3Plugin for Harmonia {
+    const VST3_CLASS_ID: [u8; 16] = *b"HarmoniaEpitech!";
+
+    const VST3_SUBCATEGORIES: &'static [Vst3SubCategory] = &[
+        Vst3SubCategory::Instrument,
+        Vst3SubCategory::Generator,
+        Vst3SubCategory::Tools,
+    ];
+}
+
+impl Harmonia {
+    /// Processes a block of `samples` samples. Nothing in here may allocate, free or wait for a
+    /// lock, see `rt_safety` for the tests enforcing it.
+    fn process_block(&mut self, host: &mut impl Host, samples: usize) {
+        let started = Instant::now();
+        let host = &mut CountingHost::new(host);
+        let transport = host.transport();
+        let transport_info = TransportInfo {
+            playing: transport.playing,
+            pos_beats: transport.pos_beats,
+            bar_start_pos_beats: transport.bar_start_pos_beats,
+            bar_number: transport.bar_number,
+            bar_length: player::bar_length(
+                transport.time_sig_numerator.unwrap_or(self.time_sig_numerator),
+                transport.time_sig_denominator.unwrap_or(self.time_sig_denominator),
+            ),
+        };
 
         if let Some(tempo) = transport.tempo {
             if (tempo - self.current_tempo).abs() > f64::EPSILON {
                 self.current_tempo = tempo;
                 self.samples_per_beat =
                     (self.sample_rate * 60.0 / self.current_tempo as f32) as usize;
+                self.log(Level::Debug, "Tempo changed to", Some(tempo));
             }
         }
 
@@ -308,114 +745,448 @@ impl Plugin for Harmonia {
             }
         }
 
-        if self.params.debug.value() {
-            let _ = self.debug_info.insert(
-                String::from("Transport"),
-                format!(
-                    "Playing: {}, Tempo: {}, Samples per Beat: {}",
-                    transport.playing, self.current_tempo, self.samples_per_beat
-                ),
-            );
-        }
-
-        let time_sig_value = self.params.time_signature.value();
-
-
-        // Receive messages
-        match self.message_receiver.try_recv() {
-            Ok(message) => {
-                println!("Just received a message, {:?} lib.rs", message);
-
-                match message {
-
-                    Message::Generate => {
-                        println!("Generating message");
-                        println!("current tempo: {:?}", self.current_tempo);
-                        println!("selected_style: {:?}", self.selected_style);
-                        println!("sample rate: {:?}", self.time_sig_numerator);
-                        println!("sample rate: {:?}", self.time_sig_denominator);
-
-                        match self.requester.generate(
-                            self.current_tempo,
-                            self.selected_style,
-                            self.scale.clone(),
-                            self.time_sig_numerator,
-                            self.time_sig_denominator,
-                        ) {
-                            Ok(download_link) => {
-                                self.download_link = Some(download_link);
-                                self.download_available.store(true, std::sync::atomic::Ordering::SeqCst);
-                                let _ = self.message_sender.send(Message::DownloadLinkAvailableEditor(true));
-                            },
-                            Err(message) => {
-                                self.download_link = None;
-                                println!("error generating: {}", message)
-                            }
-                        }
-                        println!("download link: {:?}", self.download_link);
-                    }
-
-                    Message::Download => match &self.download_link {
-                        Some(link) => {
-                            self.requester.download_midi(link.clone(), self.downloads_folder.as_ref().unwrap());
-                            open::that(self.downloads_folder.as_ref().unwrap()).unwrap();
-                        }
-                        None => {
-                            println!("No download link available");
-                        }
-                    },
-                    Message::DownloadProgress(progress) => {
-                        println!("Download progress: {}", progress)
-                    }
-                    Message::DownloadError(error) => {
-                        println!("Error downloading: {}", error);
-                    }
-                    Message::SelectStyle(style) => {
-                        self.selected_style = Some(style);
-                    }
-                    Message::SelectMode(mode) => {
-                        println!("mode selctionner : {}", mode)
+        // Capture the incoming notes for the key detection, and snap them to the scale before
+        // sending them back out
+        let quantize_mode = self.params.scale_quantize.value();
+        let allowed = scale_quantizer::scale_pitch_classes(
+            self.root,
+            self.mode,
+            scale_quantizer::enabled_degrees(&self.params.scale_degrees),
+        );
+        let trigger_key = self.params.trigger_key.value();
+        while let Some(event) = host.next_event() {
+            match event {
+                // The trigger key starts a generation and is not played
+                NoteEvent::NoteOn { note, .. } if note as i32 == trigger_key => {
+                    self.generate_requested = true;
+                    self.log(Level::Debug, "Generation triggered by the note", Some(note as f64));
+                }
+                NoteEvent::NoteOff { note, .. } if note as i32 == trigger_key => {}
+                NoteEvent::NoteOn {
+                    timing,
+                    voice_id,
+                    channel,
+                    note,
+                    velocity,
+                } => {
+                    self.note_tracker.note_on(note, self.sample_pos + timing as u64);
+
+                    let quantized =
+                        self.scale_quantizer.note_on(channel, note, &allowed, quantize_mode);
+                    if let Some(note) = quantized {
+                        host.send_event(NoteEvent::NoteOn {
+                            timing,
+                            voice_id,
+                            channel,
+                            note,
+                            velocity,
+                        });
                     }
-                    Message::SelectNote(note) => {
-                        println!("note selectionner : {}", note);
-                        self.scale = note.to_string();
+                }
+                NoteEvent::NoteOff {
+                    timing,
+                    voice_id,
+                    channel,
+                    note,
+                    velocity,
+                } => {
+                    self.note_tracker.note_off(
+                        &self.note_capture,
+                        note,
+                        self.sample_pos + timing as u64,
+                        self.sample_rate,
+                    );
+
+                    if let Some(note) = self.scale_quantizer.note_off(channel, note) {
+                        host.send_event(NoteEvent::NoteOff {
+                            timing,
+                            voice_id,
+                            channel,
+                            note,
+                            velocity,
+                        });
                     }
-                    Message::ParamUpdate(params) => {
-                        println!("paramètre update : {:?}", params)
+                }
+                // The polyphonic expressions go to the note played for the incoming one.
+                // `PolyModulation` targets the voice id, which is left as it is.
+                mut event => {
+                    if let Some((channel, note)) = scale_quantizer::expression_note(&mut event) {
+                        match self.scale_quantizer.mapped_note(channel, *note) {
+                            Some(mapped) => *note = mapped,
+                            None => continue,
+                        }
                     }
-                    _ => {}
+                    host.send_event(event);
                 }
             }
-            Err(mpsc::TryRecvError::Disconnected) => println!("Got disconnected from the channel"),
-            Err(mpsc::TryRecvError::Empty) => (),
         }
 
-        ProcessStatus::Normal
+        // Play the last generation, the worker processes it again when the parameters change
+        let processing = self.processing();
+        if self.applied_processing != Some(processing)
+            && (self.generation.is_none() || self.send_job(Job::Process(processing)))
+        {
+            self.applied_processing = Some(processing);
+        }
+        // The preview stops with the transport, it is started again with the Play button
+        if self.was_playing && !transport_info.playing {
+            self.player.stop();
+            self.log(Level::Debug, "Transport stopped", None);
+        }
+        self.was_playing = transport_info.playing;
+
+        // Automatic generation, requested one bar early so the result starts on the Nth bar
+        let bar = player::bar_index(&transport_info).filter(|_| transport_info.playing);
+        let every = self.params.generate_every.value();
+        if let Some(bar) = bar {
+            if every > 0 && self.last_bar != Some(bar) && (bar + 1).rem_euclid(every) == 0 {
+                self.generate_requested = true;
+                self.log(Level::Debug, "Automatic generation at bar", Some(bar as f64));
+            }
+        }
+        self.last_bar = bar;
+
+        let generate_param = self.params.generate.value();
+        if generate_param && !self.last_generate_param {
+            self.generate_requested = true;
+        }
+        self.last_generate_param = generate_param;
+
+        if let Some(generation) = &self.generation {
+            self.player.set_looping(
+                self.params.loop_playback.value(),
+                player::bar_length(generation.time_sig_numerator, generation.time_sig_denominator),
+            );
+        }
+        self.player.set_audible_tracks(ensemble::audible_parts(&self.params.parts));
+        self.player.set_routing(self.routing());
+        let transform = self.transform();
+        // The current tempo is read every block, so the preview follows the tempo changes
+        let beats_per_sample = self.current_tempo / 60.0 / self.sample_rate as f64;
+        self.player.process(
+            host,
+            samples,
+            beats_per_sample,
+            &transport_info,
+            &transform,
+        );
+
+        self.sample_pos += samples as u64;
+
+        // Drain the queues, a block takes every command sent since the previous one
+        self.telemetry.set_queued(Queue::Commands, self.commands.queued());
+        self.telemetry.set_queued(Queue::Replies, self.replies.queued());
+        while let Some(command) = self.commands.pop() {
+            self.handle_command(command, &transport_info);
+        }
+        // A reply replaces the generation and the clips of the player, it waits in its queue
+        // until they can be kept
+        while self.released_generations.len() < MAX_RELEASED_GENERATIONS
+            && self.player.can_release()
+        {
+            let Some(reply) = self.replies.pop() else {
+                break;
+            };
+            match reply {
+                Reply::Generated {
+                    generation,
+                    events,
+                    processing,
+                    at_bar,
+                } => {
+                    self.receive_generation(generation, events, processing, &transport_info, at_bar);
+                }
+                // The processing requested last is already recorded in `applied_processing`
+                Reply::Processed { events, .. } => {
+                    self.player.replace(events);
+                }
+            }
+        }
+
+        if self.generate_requested {
+            self.generate_requested = false;
+            self.generate(true);
+        }
+
+        self.send_releases();
+
+        self.midi_sent += host.sent() as u64;
+        self.midi_dropped += self.player.take_dropped() as u64;
+        self.telemetry.set_queued(Queue::Jobs, self.jobs.queued());
+        self.telemetry.set_queued(Queue::Log, self.log.queued());
+        let mut block = BlockSnapshot {
+            playing: transport.playing,
+            tempo: self.current_tempo,
+            samples_per_beat: self.samples_per_beat,
+            time_sig_numerator: self.time_sig_numerator,
+            time_sig_denominator: self.time_sig_denominator,
+            pos_beats: transport.pos_beats,
+            bar_number: transport.bar_number,
+            sample_rate: self.sample_rate,
+            samples,
+            process_time: started.elapsed(),
+            average_load: 0.0,
+            peak_load: 0.0,
+            midi_sent: self.midi_sent,
+            midi_dropped: self.midi_dropped,
+        };
+        self.load_meter.record(block.load(), block.budget());
+        block.average_load = self.load_meter.average();
+        block.peak_load = self.load_meter.peak();
+        self.telemetry.publish(&block);
     }
-}
 
-impl ClapPlugin for Harmonia {
-    const CLAP_ID: &'static str = "dev.spyr.harmonia";
-    const CLAP_DESCRIPTION: Option<&'static str> =
-        Some("The power of music generation inside your DAW");
-    const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
-    const CLAP_SUPPORT_URL: Option<&'static str> = None;
+    /// Local processing of the last generation, from the current parameters
+    fn processing(&self) -> Processing {
+        let (bpm, seed) = match &self.generation {
+            Some(generation) => (generation.bpm, generation.humanize_seed),
+            None => (self.current_tempo, 0),
+        };
 
-    const CLAP_FEATURES: &'static [ClapFeature] = &[
-        ClapFeature::Instrument,
-        ClapFeature::NoteEffect,
-        ClapFeature::Utility,
-    ];
-}
+        Processing {
+            bypass: self.params.bypass_processing.value(),
+            quantize: QuantizeSettings {
+                grid: quantize::grid_beats(
+                    self.params.quantize_grid.value(),
+                    self.params.quantize_feel.value(),
+                ),
+                strength: self.params.quantize_strength.value(),
+                range: self.params.quantize_range.value(),
+                quantize_lengths: self.params.quantize_lengths.value(),
+                legato: self.params.legato.value(),
+                length_scale: self.params.note_length.value(),
+                remove_overlaps: self.params.remove_overlaps.value(),
+            },
+            groove: GrooveSettings {
+                swing: self.params.swing.value(),
+                swing_grid: self.params.swing_grid.value(),
+                humanize_ms: self.params.humanize_timing.value(),
+                velocity_random: self.params.velocity_random.value(),
+                template: self.groove_template,
+                template_amount: self.params.groove_amount.value(),
+                bpm,
+                seed,
+            },
+        }
+    }
 
-impl Vst3Plugin for Harmonia {
-    const VST3_CLASS_ID: [u8; 16] = *b"HarmoniaEpitech!";
+    /// Styles to generate, either the ensemble or the selected style alone
+    fn parts(&self) -> [Option<Style>; MAX_PARTS] {
+        if self.ensemble.iter().all(Option::is_none) {
+            let mut parts = [None; MAX_PARTS];
+            parts[0] = self.selected_style;
+            parts
+        } else {
+            self.ensemble
+        }
+    }
 
-    const VST3_SUBCATEGORIES: &'static [Vst3SubCategory] = &[
-        Vst3SubCategory::Instrument,
-        Vst3SubCategory::Generator,
-        Vst3SubCategory::Tools,
-    ];
+    /// Output channels and programs of the parts of the last generation
+    fn routing(&self) -> Routing {
+        let parts: &[Style] = match &self.generation {
+            Some(generation) => &generation.parts,
+            None => &[],
+        };
+
+        Routing::new(&self.params.parts, parts, self.params.program_change.value())
+    }
+
+    /// Asks the worker for a new generation, played when it is received. With `at_bar`, the
+    /// preview already playing is replaced on the next bar at the earliest.
+    fn generate(&mut self, at_bar: bool) {
+        let job = Job::Generate {
+            request: self.generation_request(),
+            processing: self.processing(),
+            at_bar,
+        };
+        self.send_job(job);
+    }
+
+    /// Request of the next generation, in the key selected in the editor
+    fn generation_request(&self) -> GenerationRequest {
+        GenerationRequest {
+            parts: self.parts(),
+            root: self.root,
+            mode: self.mode,
+            bpm: self.current_tempo,
+            time_sig_numerator: self.time_sig_numerator,
+            time_sig_denominator: self.time_sig_denominator,
+        }
+    }
+
+    /// Plays a generation sent by the worker, with its events processed with `processing`. The
+    /// previous one is kept to be sent back to the worker, so it is not freed on the audio thread.
+    fn receive_generation(
+        &mut self,
+        generation: Generation,
+        events: Vec<MusicEvent>,
+        processing: Processing,
+        transport: &TransportInfo,
+        at_bar: bool,
+    ) {
+        if let Some(previous) = self.generation.replace(generation) {
+            self.released_generations.push(previous);
+        }
+        self.log(Level::Info, "Generation received", None);
+        self.present(events, transport, at_bar);
+        self.applied_processing = Some(processing);
+    }
+
+    /// Queues a job for the worker, returns false when the worker is too far behind and the job
+    /// has been dropped
+    fn send_job(&mut self, job: Job) -> bool {
+        if self.jobs.push(job).is_err() {
+            self.log(Level::Warn, "The job queue is full, a job has been dropped", None);
+            return false;
+        }
+        self.worker.wake();
+        true
+    }
+
+    /// Sends the generations and the clips replaced on the audio thread to the worker, which frees
+    /// them. Those the job queue has no room for are kept and sent on a later block.
+    fn send_releases(&mut self) {
+        let mut sent = false;
+        while !self.jobs.is_full() {
+            let job = if let Some(generation) = self.released_generations.pop() {
+                Job::Release(generation)
+            } else if let Some(events) = self.player.take_released() {
+                Job::ReleaseEvents(events)
+            } else {
+                break;
+            };
+            // Only this thread pushes jobs, so the queue still has room
+            let pushed = self.jobs.push(job).is_ok();
+            debug_assert!(pushed, "the job queue filled up while sending a release");
+            sent = true;
+        }
+        if sent {
+            self.worker.wake();
+        }
+    }
+
+    /// Queues a record the worker writes in the log, dropped when the worker is too far behind
+    fn log(&mut self, level: Level, message: &'static str, value: Option<f64>) {
+        let _ = self.log.push(RtRecord {
+            level,
+            message,
+            value,
+        });
+    }
+
+    fn handle_command(&mut self, command: Command, transport: &TransportInfo) {
+        match command {
+            Command::Generate => {
+                self.generate(false);
+            }
+            Command::Download => {
+                let job = Job::Download {
+                    processing: self.processing(),
+                    transform: self.transform(),
+                    routing: self.routing(),
+                };
+                self.send_job(job);
+            }
+            Command::RenderAudio => {
+                let job = Job::RenderAudio {
+                    processing: self.processing(),
+                    transform: self.transform(),
+                    sample_rate: self.sample_rate,
+                };
+                self.send_job(job);
+            }
+            Command::CopyNotation(format) => {
+                let job = Job::CopyNotation {
+                    format,
+                    processing: self.processing(),
+                    transform: self.transform(),
+                };
+                self.send_job(job);
+            }
+            Command::SelectStyle(style) => {
+                self.selected_style = Some(style);
+            }
+            Command::SetEnsemble(parts) => {
+                self.ensemble = parts;
+            }
+            Command::PlayPreview => {
+                self.launch_preview(transport);
+            }
+            Command::StopPreview => {
+                self.player.stop();
+            }
+            Command::SetGrooveTemplate(template) => {
+                self.groove_template = template;
+            }
+            Command::SelectMode(mode) => {
+                self.mode = mode;
+            }
+            Command::SelectNote(note) => {
+                self.root = note;
+            }
+        }
+    }
+
+    /// Plays the events of a new generation. A preview already playing keeps going until the
+    /// launch point so the performance never stops.
+    fn present(&mut self, events: Vec<MusicEvent>, transport: &TransportInfo, at_bar: bool) {
+        if !(self.player.is_playing() && transport.playing) {
+            self.player.load(events);
+            self.launch_preview(transport);
+            return;
+        }
+
+        let launch = match self.params.launch_quantize.value() {
+            LaunchQuantize::Immediately | LaunchQuantize::NextBeat if at_bar => {
+                LaunchQuantize::NextBar
+            }
+            launch => launch,
+        };
+        self.player.queue(
+            events,
+            player::launch_position(launch, self.params.launch_bars.value(), transport),
+        );
+    }
+
+    /// Starts the preview, on the bar grid of the host when its transport is playing
+    fn launch_preview(&mut self, transport: &TransportInfo) {
+        if self.generation.is_none() {
+            return;
+        }
+
+        let launch = self.params.launch_quantize.value();
+        if launch == LaunchQuantize::Immediately || !transport.playing {
+            self.player.start();
+        } else {
+            self.player.schedule(player::launch_position(
+                launch,
+                self.params.launch_bars.value(),
+                transport,
+            ));
+        }
+    }
+
+    /// Transformation from the key of the last generation to the key selected in the editor
+    fn transform(&self) -> Transform {
+        let (from_root, from_mode, ranges) = match &self.generation {
+            Some(generation) => (
+                generation.root,
+                generation.mode,
+                ensemble::note_ranges(&generation.parts),
+            ),
+            None => (self.root, self.mode, [(0, 127); MAX_PARTS]),
+        };
+
+        Transform {
+            mode: self.params.transpose_mode.value(),
+            from_root,
+            from_mode,
+            to_root: self.root,
+            to_mode: self.mode,
+            octaves: self.params.transpose_octaves.value(),
+            ranges,
+        }
+    }
 }
 
 nih_export_clap!(Harmonia);
@@ -0,0 +1,344 @@
+use crate::file_naming;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Log of the plugin, shared by every instance loaded in the host. The records are written in a
+// file of the data folder of the user, since the standard output is not visible in most hosts,
+// and the last ones are kept in memory for the debug panel.
+//
+// The audio thread cannot format a message nor wait for the lock of the log. It queues
+// `RtRecord`s instead, written by the worker.
+
+/// Size of the log file before it is rotated
+const MAX_FILE_LEN: u64 = 1024 * 1024;
+/// Number of rotated files kept next to the current one
+const ROTATED_FILES: usize = 3;
+/// Records kept in memory for the debug panel
+const RECENT_CAPACITY: usize = 200;
+/// Records of the audio thread waiting for the worker, the newer ones are dropped
+pub const RT_CAPACITY: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub const ALL: [Level; 4] = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Padded, so the messages of the log file are aligned
+        f.pad(match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub level: Level,
+    /// Seconds since 1970-01-01, in UTC
+    pub time: u64,
+    /// Module the record comes from
+    pub target: &'static str,
+    pub message: String,
+}
+
+impl Record {
+    /// Time of the day of the record, as `HH:MM:SS`
+    pub fn time_of_day(&self) -> String {
+        let seconds = self.time % 86400;
+        format!(
+            "{:02}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = file_naming::civil_from_days((self.time / 86400) as i64);
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {} {:5} {}: {}",
+            year,
+            month,
+            day,
+            self.time_of_day(),
+            self.level,
+            self.target,
+            self.message
+        )
+    }
+}
+
+/// Record of the audio thread, queued without allocating
+#[derive(Debug, Clone, Copy)]
+pub struct RtRecord {
+    pub level: Level,
+    pub message: &'static str,
+    /// Written after the message when there is one
+    pub value: Option<f64>,
+}
+
+struct Logger {
+    /// The less important records are ignored
+    level: Level,
+    /// `None` when the log folder cannot be written, the records are then only kept in memory
+    file: Option<LogFile>,
+    recent: VecDeque<Record>,
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl LogFile {
+    fn open(path: PathBuf) -> Option<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .ok()?;
+        let len = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        Some(LogFile { path, file, len })
+    }
+
+    /// Moves `harmonia.log` to `harmonia.1.log`, and the older files one step further
+    fn rotate(&mut self) {
+        for index in (1..ROTATED_FILES).rev() {
+            let _ = fs::rename(
+                rotated_path(&self.path, index),
+                rotated_path(&self.path, index + 1),
+            );
+        }
+        let _ = fs::rename(&self.path, rotated_path(&self.path, 1));
+        if let Ok(file) = File::create(&self.path) {
+            self.file = file;
+            self.len = 0;
+        }
+    }
+
+    fn write(&mut self, record: &Record) {
+        if self.len >= MAX_FILE_LEN {
+            self.rotate();
+        }
+        let line = format!("{}\n", record);
+        if self.file.write_all(line.as_bytes()).is_ok() {
+            self.len += line.len() as u64;
+        }
+    }
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    path.with_extension(format!("{}.log", index))
+}
+
+fn logger() -> &'static Mutex<Logger> {
+    static LOGGER: OnceLock<Mutex<Logger>> = OnceLock::new();
+    LOGGER.get_or_init(|| {
+        let file = log_folder().and_then(|folder| {
+            fs::create_dir_all(&folder).ok()?;
+            LogFile::open(folder.join("harmonia.log"))
+        });
+        Mutex::new(Logger {
+            level: if cfg!(debug_assertions) {
+                Level::Debug
+            } else {
+                Level::Info
+            },
+            file,
+            recent: VecDeque::with_capacity(RECENT_CAPACITY),
+        })
+    })
+}
+
+/// Folder of the log files, in the data folder of the user. The tests do not write in it.
+pub fn log_folder() -> Option<PathBuf> {
+    if cfg!(test) {
+        return None;
+    }
+    dirs::data_dir().map(|folder| folder.join("Harmonia").join("logs"))
+}
+
+/// Records a message, use the `log_*` macros rather than calling it directly. Must not be
+/// called from the audio thread.
+pub fn log(level: Level, target: &'static str, args: fmt::Arguments<'_>) {
+    let Ok(mut logger) = logger().lock() else {
+        return;
+    };
+    if level > logger.level {
+        return;
+    }
+
+    let record = Record {
+        level,
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        target,
+        message: args.to_string(),
+    };
+    if cfg!(debug_assertions) {
+        eprintln!("{}", record);
+    }
+    if let Some(file) = &mut logger.file {
+        file.write(&record);
+    }
+    if logger.recent.len() == RECENT_CAPACITY {
+        logger.recent.pop_front();
+    }
+    logger.recent.push_back(record);
+}
+
+/// Records a message queued by the audio thread
+pub fn log_rt(record: RtRecord) {
+    match record.value {
+        Some(value) => log(
+            record.level,
+            "audio",
+            format_args!("{} {}", record.message, value),
+        ),
+        None => log(record.level, "audio", format_args!("{}", record.message)),
+    }
+}
+
+/// Last records at least as important as `level`, the oldest first
+pub fn recent(level: Level, count: usize) -> Vec<Record> {
+    let Ok(logger) = logger().lock() else {
+        return Vec::new();
+    };
+    let mut records: Vec<Record> = logger
+        .recent
+        .iter()
+        .rev()
+        .filter(|record| record.level <= level)
+        .take(count)
+        .cloned()
+        .collect();
+    records.reverse();
+    records
+}
+
+macro_rules! log_error {
+    ($($arg:tt)+) => {
+        $crate::logging::log($crate::logging::Level::Error, module_path!(), format_args!($($arg)+))
+    };
+}
+
+macro_rules! log_warn {
+    ($($arg:tt)+) => {
+        $crate::logging::log($crate::logging::Level::Warn, module_path!(), format_args!($($arg)+))
+    };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)+) => {
+        $crate::logging::log($crate::logging::Level::Info, module_path!(), format_args!($($arg)+))
+    };
+}
+
+macro_rules! log_debug {
+    ($($arg:tt)+) => {
+        $crate::logging::log($crate::logging::Level::Debug, module_path!(), format_args!($($arg)+))
+    };
+}
+
+pub(crate) use {log_debug, log_error, log_info, log_warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_written_on_one_line() {
+        let record = Record {
+            level: Level::Warn,
+            // 2024-02-29 13:05:09
+            time: 19_782 * 86400 + 13 * 3600 + 5 * 60 + 9,
+            target: "harmonia::worker",
+            message: String::from("Request failed"),
+        };
+        assert_eq!(record.time_of_day(), "13:05:09");
+        assert_eq!(
+            record.to_string(),
+            "2024-02-29 13:05:09 WARN  harmonia::worker: Request failed"
+        );
+    }
+
+    #[test]
+    fn full_files_are_rotated() {
+        let folder =
+            std::env::temp_dir().join(format!("harmonia-{}-log-rotation", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        let path = folder.join("harmonia.log");
+        assert_eq!(rotated_path(&path, 2), folder.join("harmonia.2.log"));
+
+        let record = |message: &str| Record {
+            level: Level::Info,
+            time: 0,
+            target: "test",
+            message: message.to_string(),
+        };
+        let mut file = LogFile::open(path.clone()).unwrap();
+        for index in 0..=ROTATED_FILES + 1 {
+            file.write(&record(&index.to_string()));
+            // Fills the file so the next record rotates it
+            file.len = MAX_FILE_LEN;
+        }
+        file.write(&record("last"));
+
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+        assert!(read(&path).ends_with(": last\n"));
+        assert!(read(&rotated_path(&path, 1)).ends_with(": 4\n"));
+        assert!(read(&rotated_path(&path, ROTATED_FILES)).ends_with(": 2\n"));
+        assert!(!rotated_path(&path, ROTATED_FILES + 1).exists());
+        let _ = fs::remove_dir_all(&folder);
+    }
+
+    #[test]
+    fn recent_records_are_filtered_by_level() {
+        // Other tests log at the same time, so only the records of this one are looked at
+        let target = "harmonia::logging::tests";
+        log(Level::Error, target, format_args!("first"));
+        log(Level::Info, target, format_args!("second"));
+        log_rt(RtRecord {
+            level: Level::Warn,
+            message: "Block took",
+            value: Some(1.5),
+        });
+        log(Level::Warn, target, format_args!("third"));
+
+        let messages = |level| -> Vec<String> {
+            recent(level, RECENT_CAPACITY)
+                .into_iter()
+                .filter(|record| record.target == target)
+                .map(|record| record.message)
+                .collect()
+        };
+        assert_eq!(messages(Level::Warn), ["first", "third"]);
+        assert_eq!(messages(Level::Info), ["first", "second", "third"]);
+        assert!(recent(Level::Warn, RECENT_CAPACITY)
+            .iter()
+            .any(|record| record.target == "audio" && record.message == "Block took 1.5"));
+    }
+}
@@ -0,0 +1,528 @@
+use crate::editor::Mode;
+use crate::musicxml::{self, ScoreInfo};
+use crate::preview::MusicEvent;
+
+/// Resolution of the written files
+pub const TICKS_PER_BEAT: u16 = 480;
+
+// Events happening on the same tick are written in this order, so a note can be released and
+// played again on the same tick
+const META_PRIORITY: u8 = 0;
+const NOTE_OFF_PRIORITY: u8 = 1;
+const NOTE_ON_PRIORITY: u8 = 2;
+
+/// Largest value of a variable length quantity, which is at most four bytes long
+const MAX_VAR_LEN: u32 = 0x0FFF_FFFF;
+
+pub fn beats_to_ticks(beats: f64) -> u32 {
+    (beats.max(0.0) * TICKS_PER_BEAT as f64).round() as u32
+}
+
+#[derive(Default)]
+pub struct Track {
+    events: Vec<(u32, u8, Vec<u8>)>,
+}
+
+impl Track {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn meta(&mut self, tick: u32, kind: u8, data: &[u8]) {
+        let mut bytes = vec![0xFF, kind];
+        write_var_len(&mut bytes, data.len() as u32);
+        bytes.extend_from_slice(data);
+        self.events.push((tick, META_PRIORITY, bytes));
+    }
+
+    pub fn text(&mut self, tick: u32, text: &str) {
+        self.meta(tick, 0x01, text.as_bytes());
+    }
+
+    pub fn copyright(&mut self, tick: u32, text: &str) {
+        self.meta(tick, 0x02, text.as_bytes());
+    }
+
+    /// Name of the track, or of the whole sequence on the first track
+    pub fn name(&mut self, tick: u32, name: &str) {
+        self.meta(tick, 0x03, name.as_bytes());
+    }
+
+    pub fn tempo(&mut self, tick: u32, bpm: f64) {
+        let micros = (60_000_000.0 / bpm.max(1.0)).round() as u32;
+        self.meta(tick, 0x51, &micros.to_be_bytes()[1..]);
+    }
+
+    pub fn time_signature(&mut self, tick: u32, numerator: i32, denominator: i32) {
+        // The denominator is stored as a power of two
+        let denominator = if denominator > 0 { denominator } else { 4 };
+        let power = (denominator as u32).trailing_zeros() as u8;
+        self.meta(tick, 0x58, &[numerator.max(1) as u8, power, 24, 8]);
+    }
+
+    /// Key signature, `fifths` being negative for the flat keys
+    pub fn key_signature(&mut self, tick: u32, fifths: i32, mode: Mode) {
+        let minor = match mode {
+            Mode::Major => 0,
+            Mode::Minor => 1,
+        };
+        self.meta(tick, 0x59, &[fifths.clamp(-7, 7) as i8 as u8, minor]);
+    }
+
+    pub fn program_change(&mut self, tick: u32, channel: u8, program: u8) {
+        self.events.push((
+            tick,
+            META_PRIORITY,
+            vec![0xC0 | (channel & 0x0F), program & 0x7F],
+        ));
+    }
+
+    pub fn note(&mut self, event: &MusicEvent) {
+        let channel = event.channel & 0x0F;
+        let start = beats_to_ticks(event.time);
+        let end = beats_to_ticks(event.time + event.duration).max(start + 1);
+        self.events.push((
+            start,
+            NOTE_ON_PRIORITY,
+            vec![
+                0x90 | channel,
+                event.note & 0x7F,
+                event.velocity.clamp(1, 127),
+            ],
+        ));
+        self.events.push((
+            end,
+            NOTE_OFF_PRIORITY,
+            vec![0x80 | channel, event.note & 0x7F, 0],
+        ));
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut events: Vec<&(u32, u8, Vec<u8>)> = self.events.iter().collect();
+        events.sort_by_key(|(tick, priority, _)| (*tick, *priority));
+
+        let mut data = Vec::new();
+        let mut last_tick = 0;
+        for (tick, _, bytes) in events {
+            write_var_len(&mut data, tick - last_tick);
+            data.extend_from_slice(bytes);
+            last_tick = *tick;
+        }
+        // End of track
+        data.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+        let mut chunk = b"MTrk".to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        chunk.extend_from_slice(&data);
+        chunk
+    }
+}
+
+/// A type 1 standard MIDI file, the first track holding the tempo, time signature, key
+/// signature and the description of the generation
+#[derive(Default)]
+pub struct MidiFile {
+    pub tracks: Vec<Track>,
+}
+
+impl MidiFile {
+    /// Builds a file from preview events, with one track per `track` value of the events named
+    /// after its style. The program of a track, indexed by its `track` value, is set at the
+    /// start of the track. The `description` is written in a text event so the file still says
+    /// how it was generated once it has left the plugin.
+    pub fn from_events(
+        events: &[MusicEvent],
+        programs: &[Option<u8>],
+        info: &ScoreInfo,
+        description: &str,
+    ) -> Self {
+        let mut conductor = Track::new();
+        conductor.name(0, "Harmonia");
+        conductor.copyright(0, "Generated with Harmonia");
+        conductor.text(0, description);
+        conductor.tempo(0, info.bpm);
+        conductor.time_signature(0, info.time_sig_numerator, info.time_sig_denominator);
+        conductor.key_signature(0, musicxml::key_fifths(info.root, info.mode), info.mode);
+
+        let mut tracks = vec![conductor];
+        for track_id in musicxml::tracks(events) {
+            let mut track = Track::new();
+            track.name(0, &musicxml::part_name(info, track_id));
+            let first = events.iter().find(|event| event.track == track_id);
+            if let (Some(first), Some(Some(program))) = (first, programs.get(track_id as usize)) {
+                track.program_change(0, first.channel, *program);
+            }
+            for event in events.iter().filter(|event| event.track == track_id) {
+                track.note(event);
+            }
+            tracks.push(track);
+        }
+
+        MidiFile { tracks }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&(self.tracks.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&TICKS_PER_BEAT.to_be_bytes());
+        for track in &self.tracks {
+            bytes.extend_from_slice(&track.encode());
+        }
+        bytes
+    }
+}
+
+/// Writes a variable length quantity. Larger values than the format can hold are clamped, which
+/// only happens to events more than half a million beats apart.
+fn write_var_len(bytes: &mut Vec<u8>, value: u32) {
+    let mut value = value.min(MAX_VAR_LEN);
+    let mut buffer = [0u8; 4];
+    let mut len = 0;
+    loop {
+        buffer[len] = (value & 0x7F) as u8;
+        len += 1;
+        value >>= 7;
+        if value == 0 {
+            break;
+        }
+    }
+    for i in (0..len).rev() {
+        let continuation = if i > 0 { 0x80 } else { 0 };
+        bytes.push(buffer[i] | continuation);
+    }
+}
+
+/// Notes and time signature of a standard MIDI file
+#[derive(Debug)]
+pub struct MidiNotes {
+    /// Notes with their time and duration in beats, sorted by time
+    pub notes: Vec<MusicEvent>,
+    /// First time signature of the file, 4/4 when there is none
+    pub time_sig_numerator: i32,
+    pub time_sig_denominator: i32,
+}
+
+/// Reads the notes and the time signature of a standard MIDI file
+pub fn read_midi(bytes: &[u8]) -> Result<MidiNotes, String> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(4)? != b"MThd" {
+        return Err(String::from("Not a MIDI file"));
+    }
+    let header_len = reader.u32()? as usize;
+    let header = reader.take(header_len)?;
+    if header.len() < 6 {
+        return Err(String::from("Invalid MIDI header"));
+    }
+    let track_count = u16::from_be_bytes([header[2], header[3]]);
+    let division = u16::from_be_bytes([header[4], header[5]]);
+    if division & 0x8000 != 0 || division == 0 {
+        return Err(String::from("SMPTE time division is not supported"));
+    }
+    let ticks_per_beat = division as f64;
+
+    let mut notes = Vec::new();
+    let mut time_signature = None;
+    for track_id in 0..track_count {
+        let chunk_type = reader.take(4)?;
+        let chunk_len = reader.u32()? as usize;
+        let chunk = reader.take(chunk_len)?;
+        if chunk_type != b"MTrk" {
+            continue;
+        }
+
+        let mut track = Reader {
+            bytes: chunk,
+            pos: 0,
+        };
+        let mut tick = 0u32;
+        let mut running_status = 0u8;
+        let mut held: [[Option<(u32, u8)>; 128]; 16] = [[None; 128]; 16];
+        while track.pos < track.bytes.len() {
+            tick = tick
+                .checked_add(track.var_len()?)
+                .ok_or_else(|| String::from("MIDI track too long"))?;
+
+            let mut status = track.u8()?;
+            if status < 0x80 {
+                // Running status, the byte we just read is the first data byte
+                status = running_status;
+                track.pos -= 1;
+            }
+
+            match status {
+                0xFF => {
+                    let kind = track.u8()?;
+                    let len = track.var_len()? as usize;
+                    let data = track.take(len)?;
+                    // The denominator is written as a power of two
+                    if kind == 0x58 && len >= 2 && data[1] < 16 && time_signature.is_none() {
+                        time_signature = Some((data[0] as i32, 1i32 << data[1]));
+                    }
+                }
+                0xF0 | 0xF7 => {
+                    let len = track.var_len()? as usize;
+                    track.take(len)?;
+                }
+                0x80..=0xEF => {
+                    running_status = status;
+                    let channel = status & 0x0F;
+                    let data_len = match status & 0xF0 {
+                        0xC0 | 0xD0 => 1,
+                        _ => 2,
+                    };
+                    let data = track.take(data_len)?;
+
+                    let kind = status & 0xF0;
+                    if kind == 0x90 && data[1] > 0 {
+                        held[channel as usize][(data[0] & 0x7F) as usize] = Some((tick, data[1]));
+                    } else if kind == 0x80 || kind == 0x90 {
+                        let note = data[0] & 0x7F;
+                        if let Some((start, velocity)) =
+                            held[channel as usize][note as usize].take()
+                        {
+                            notes.push(MusicEvent {
+                                channel,
+                                duration: (tick - start) as f64 / ticks_per_beat,
+                                note,
+                                time: start as f64 / ticks_per_beat,
+                                track: track_id.min(u8::MAX as u16) as u8,
+                                velocity,
+                            });
+                        }
+                    }
+                }
+                _ => return Err(format!("Unexpected status byte {:#04x}", status)),
+            }
+        }
+    }
+
+    notes.sort_by(|a, b| a.time.total_cmp(&b.time));
+    let (time_sig_numerator, time_sig_denominator) = time_signature.unwrap_or((4, 4));
+    Ok(MidiNotes {
+        notes,
+        time_sig_numerator,
+        time_sig_denominator,
+    })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos + len;
+        let slice = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or_else(|| String::from("Unexpected end of MIDI file"))?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn var_len(&mut self) -> Result<u32, String> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(String::from("Invalid variable length quantity"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::{Note, Style};
+
+    /// Header of a type 1 file with a single track, at 480 ticks per beat
+    fn header() -> Vec<u8> {
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&[0, 1, 0, 1, 0x01, 0xE0]);
+        bytes
+    }
+
+    fn with_track(data: &[u8]) -> Vec<u8> {
+        let mut bytes = header();
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    fn event(track: u8, channel: u8, note: u8, time: f64, duration: f64) -> MusicEvent {
+        MusicEvent {
+            channel,
+            duration,
+            note,
+            time,
+            track,
+            velocity: 64 + note / 2,
+        }
+    }
+
+    #[test]
+    fn written_notes_are_read_back() {
+        let events = [
+            event(0, 0, 60, 0.0, 1.0),
+            event(0, 0, 64, 0.0, 0.5),
+            // Played again on the tick it is released
+            event(0, 0, 60, 1.0, 0.25),
+            event(1, 1, 36, 0.5, 2.0 / 3.0),
+            event(1, 1, 127, 3.75, 0.25),
+        ];
+        let info = ScoreInfo {
+            parts: &[Style::AcousticGrand, Style::AcousticBass],
+            root: Note::D,
+            mode: Mode::Minor,
+            bpm: 96.0,
+            time_sig_numerator: 3,
+            time_sig_denominator: 4,
+        };
+        let file = MidiFile::from_events(&events, &[Some(0), Some(32)], &info, "description");
+        assert_eq!(file.tracks.len(), 3);
+
+        let mut notes = read_midi(&file.encode()).unwrap().notes;
+        notes.sort_by(|a, b| a.time.total_cmp(&b.time).then(a.note.cmp(&b.note)));
+        assert_eq!(notes.len(), events.len());
+        for (note, event) in notes
+            .iter()
+            .zip([events[0], events[1], events[3], events[2], events[4]])
+        {
+            // The first track holds the tempo and the signatures
+            assert_eq!(note.track, event.track + 1);
+            assert_eq!((note.channel, note.note), (event.channel, event.note));
+            assert_eq!(note.velocity, event.velocity);
+            assert!((note.time - event.time).abs() < 1e-9);
+            assert!((note.duration - event.duration).abs() < 1.0 / TICKS_PER_BEAT as f64);
+        }
+    }
+
+    #[test]
+    fn the_time_signature_is_read_back() {
+        let info = ScoreInfo {
+            parts: &[Style::AcousticGrand],
+            root: Note::C,
+            mode: Mode::Major,
+            bpm: 120.0,
+            time_sig_numerator: 6,
+            time_sig_denominator: 8,
+        };
+        let file = MidiFile::from_events(&[event(0, 0, 60, 0.0, 1.0)], &[None], &info, "");
+        let midi = read_midi(&file.encode()).unwrap();
+        assert_eq!(midi.notes.len(), 1);
+        assert_eq!((midi.time_sig_numerator, midi.time_sig_denominator), (6, 8));
+
+        // A file without time signature is in 4/4
+        let midi = read_midi(&with_track(&[0x00, 0xFF, 0x2F, 0x00])).unwrap();
+        assert_eq!((midi.time_sig_numerator, midi.time_sig_denominator), (4, 4));
+    }
+
+    #[test]
+    fn meta_events_describe_the_generation() {
+        let info = ScoreInfo {
+            parts: &[Style::AcousticBass],
+            root: Note::D,
+            mode: Mode::Minor,
+            bpm: 96.0,
+            time_sig_numerator: 6,
+            time_sig_denominator: 8,
+        };
+        let events = [event(0, 2, 40, 0.0, 1.0), event(1, 3, 60, 0.0, 1.0)];
+        let file = MidiFile::from_events(&events, &[Some(32)], &info, "seed 1");
+        let tracks: Vec<_> = file.tracks.iter().map(Track::encode).collect();
+        let contains = |track: usize, bytes: &[u8]| {
+            tracks[track]
+                .windows(bytes.len())
+                .any(|window| window == bytes)
+        };
+        let meta = |kind: u8, data: &[u8]| [&[0xFF, kind, data.len() as u8], data].concat();
+
+        assert!(contains(0, &meta(0x03, b"Harmonia")));
+        assert!(contains(0, &meta(0x01, b"seed 1")));
+        // 625000 microseconds per beat
+        assert!(contains(0, &meta(0x51, &[0x09, 0x89, 0x68])));
+        assert!(contains(0, &meta(0x58, &[6, 3, 24, 8])));
+        // One flat, minor
+        assert!(contains(0, &meta(0x59, &[0xFF, 1])));
+
+        let bass = Style::AcousticBass.to_string();
+        assert!(contains(1, &meta(0x03, bass.as_bytes())));
+        assert!(contains(1, &[0x00, 0xC2, 32]));
+        // The second part has no style nor program
+        assert!(contains(2, &meta(0x03, b"Part 2")));
+        assert!(!tracks[2].iter().any(|byte| byte & 0xF0 == 0xC0));
+    }
+
+    #[test]
+    fn variable_lengths_are_read_back() {
+        for value in [0, 0x7F, 0x80, 0x3FFF, 0x4000, 0x0FFF_FFFF] {
+            let mut bytes = Vec::new();
+            write_var_len(&mut bytes, value);
+            let mut reader = Reader {
+                bytes: &bytes,
+                pos: 0,
+            };
+            assert_eq!(reader.var_len(), Ok(value));
+            assert_eq!(reader.pos, bytes.len());
+        }
+
+        let mut bytes = Vec::new();
+        write_var_len(&mut bytes, u32::MAX);
+        assert_eq!(bytes, [0xFF, 0xFF, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        let error = |bytes: &[u8]| read_midi(bytes).unwrap_err();
+        assert_eq!(error(b"RIFF\0\0\0\x06"), "Not a MIDI file");
+        assert_eq!(error(&header()[..10]), "Unexpected end of MIDI file");
+
+        let mut smpte = header();
+        smpte[12] = 0xE7;
+        assert_eq!(error(&smpte), "SMPTE time division is not supported");
+
+        // A note on cut in the middle, then a data byte without a running status
+        assert_eq!(
+            error(&with_track(&[0x00, 0x90, 0x3C])),
+            "Unexpected end of MIDI file"
+        );
+        assert_eq!(
+            error(&with_track(&[0x00, 0x3C, 0x40])),
+            "Unexpected status byte 0x00"
+        );
+        assert_eq!(
+            error(&with_track(&[0xFF, 0xFF, 0xFF, 0xFF, 0x7F])),
+            "Invalid variable length quantity"
+        );
+    }
+
+    #[test]
+    fn overflowing_delta_times_are_rejected() {
+        // Seventeen text events 0x0FFFFFFF ticks apart go past `u32::MAX` ticks
+        let mut data = Vec::new();
+        for _ in 0..17 {
+            data.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0x7F, 0xFF, 0x01, 0x00]);
+        }
+        assert_eq!(
+            read_midi(&with_track(&data)).unwrap_err(),
+            "MIDI track too long"
+        );
+    }
+}
@@ -0,0 +1,681 @@
+use crate::editor::{Mode, Note, Style};
+use crate::preview::MusicEvent;
+use std::fmt::Write;
+
+/// Divisions of a quarter note, enough for sixteenth notes and eighth note triplets
+pub(crate) const DIVISIONS: i64 = 12;
+
+/// Note values that can be written, longest first, as (divisions, type, dotted, triplet)
+pub(crate) const NOTE_VALUES: [(i64, &str, bool, bool); 12] = [
+    (48, "whole", false, false),
+    (36, "half", true, false),
+    (24, "half", false, false),
+    (18, "quarter", true, false),
+    (12, "quarter", false, false),
+    (9, "eighth", true, false),
+    (8, "quarter", false, true),
+    (6, "eighth", false, false),
+    (4, "eighth", false, true),
+    (3, "16th", false, false),
+    (2, "16th", false, true),
+    (1, "32nd", false, true),
+];
+
+/// Length of the beats the triplets are grouped in, a quarter note
+const TRIPLET_BEAT: i64 = DIVISIONS;
+/// Grid of the beats played as triplets, eighth note triplets
+const TRIPLET_GRID: i64 = DIVISIONS / 3;
+/// Grid of the other beats, sixteenth notes
+const STRAIGHT_GRID: i64 = DIVISIONS / 4;
+
+/// Everything the score needs besides the events
+pub struct ScoreInfo<'a> {
+    pub parts: &'a [Style],
+    pub root: Note,
+    pub mode: Mode,
+    pub bpm: f64,
+    pub time_sig_numerator: i32,
+    pub time_sig_denominator: i32,
+}
+
+/// Position of the key in the circle of fifths, negative for the flat keys. The enharmonic key
+/// with the fewest accidentals is chosen, F# major being preferred to Gb major.
+pub fn key_fifths(root: Note, mode: Mode) -> i32 {
+    let major_root = match mode {
+        Mode::Major => root.pitch_class(),
+        // The relative major shares the key signature
+        Mode::Minor => (root.pitch_class() + 3) % 12,
+    };
+    let fifths = (major_root as i32 * 7) % 12;
+    if fifths > 6 {
+        fifths - 12
+    } else {
+        fifths
+    }
+}
+
+/// Step, alteration and octave of a MIDI note, with flats in the flat keys
+pub fn spell(note: u8, fifths: i32) -> (char, i32, i32) {
+    const SHARPS: [(char, i32); 12] = [
+        ('C', 0),
+        ('C', 1),
+        ('D', 0),
+        ('D', 1),
+        ('E', 0),
+        ('F', 0),
+        ('F', 1),
+        ('G', 0),
+        ('G', 1),
+        ('A', 0),
+        ('A', 1),
+        ('B', 0),
+    ];
+    const FLATS: [(char, i32); 12] = [
+        ('C', 0),
+        ('D', -1),
+        ('D', 0),
+        ('E', -1),
+        ('E', 0),
+        ('F', 0),
+        ('G', -1),
+        ('G', 0),
+        ('A', -1),
+        ('A', 0),
+        ('B', -1),
+        ('B', 0),
+    ];
+
+    let (step, alter) = if fifths < 0 {
+        FLATS[(note % 12) as usize]
+    } else {
+        SHARPS[(note % 12) as usize]
+    };
+    (step, alter, note as i32 / 12 - 1)
+}
+
+/// A chord or a rest of a part, in divisions from the start of the clip
+pub(crate) struct Slice {
+    pub start: i64,
+    pub end: i64,
+    /// Empty for a rest
+    pub notes: Vec<u8>,
+}
+
+/// Turns the overlapping events of a part into a line of chords and rests. Chords are cut when
+/// the next one starts, and snapped to the grid of their beat, see `snap`.
+pub(crate) fn slices(events: &[&MusicEvent], measure_len: i64, total: i64) -> Vec<Slice> {
+    let to_divisions = |beats: f64| (beats * DIVISIONS as f64).round() as i64;
+
+    let mut onsets: Vec<(i64, i64, Vec<u8>)> = Vec::new();
+    for event in events {
+        let start = to_divisions(event.time);
+        let end = to_divisions(event.time + event.duration).max(start + 1);
+        match onsets.last_mut() {
+            Some((onset, onset_end, notes)) if *onset == start => {
+                *onset_end = (*onset_end).max(end);
+                if !notes.contains(&event.note) {
+                    notes.push(event.note);
+                }
+            }
+            _ => onsets.push((start, end, vec![event.note])),
+        }
+    }
+
+    let mut slices = Vec::new();
+    let mut position = 0;
+    for (i, (start, end, notes)) in onsets.iter().enumerate() {
+        let next_start = onsets.get(i + 1).map_or(total, |next| next.0);
+        if *start > position {
+            slices.push(Slice {
+                start: position,
+                end: *start,
+                notes: Vec::new(),
+            });
+        }
+        let end = (*end).min(next_start);
+        let mut notes = notes.clone();
+        notes.sort_unstable();
+        slices.push(Slice {
+            start: *start,
+            end,
+            notes,
+        });
+        position = end;
+    }
+    if position < total {
+        slices.push(Slice {
+            start: position,
+            end: total,
+            notes: Vec::new(),
+        });
+    }
+    snap(slices, measure_len)
+}
+
+/// Start and end of the beat starting at or containing `position`, the beats being counted from
+/// the start of its measure. The last beat of a measure can be shorter.
+fn beat_bounds(position: i64, measure_len: i64) -> (i64, i64) {
+    let measure_start = position - position % measure_len;
+    let beat_start = position - (position - measure_start) % TRIPLET_BEAT;
+    (
+        beat_start,
+        (beat_start + TRIPLET_BEAT).min(measure_start + measure_len),
+    )
+}
+
+/// Whether a beat is played as a triplet, when the `boundaries` of the slices falling inside of
+/// it are closer to the eighth note triplets than to the sixteenth notes
+fn is_triplet_beat(beat_start: i64, beat_end: i64, boundaries: &[i64]) -> bool {
+    if beat_end - beat_start != TRIPLET_BEAT {
+        return false;
+    }
+
+    let distance = |offset: i64, grid: i64| (offset % grid).min(grid - offset % grid);
+    let first = boundaries.partition_point(|boundary| *boundary <= beat_start);
+    let (straight, triplet) = boundaries[first..]
+        .iter()
+        .take_while(|boundary| **boundary < beat_end)
+        .fold((0, 0), |(straight, triplet), boundary| {
+            let offset = boundary - beat_start;
+            (
+                straight + distance(offset, STRAIGHT_GRID),
+                triplet + distance(offset, TRIPLET_GRID),
+            )
+        });
+    triplet < straight
+}
+
+/// Moves the boundaries of the slices to the sixteenth notes, or to the eighth note triplets in
+/// the beats played as triplets, so every beat can be written with or without a triplet
+/// bracket. The slices shorter than the grid are dropped.
+fn snap(slices: Vec<Slice>, measure_len: i64) -> Vec<Slice> {
+    let boundaries: Vec<i64> = slices.iter().map(|slice| slice.end).collect();
+
+    let mut snapped = Vec::with_capacity(slices.len());
+    let mut position = 0;
+    for slice in slices {
+        let (beat_start, beat_end) = beat_bounds(slice.end, measure_len);
+        let grid = if is_triplet_beat(beat_start, beat_end, &boundaries) {
+            TRIPLET_GRID
+        } else {
+            STRAIGHT_GRID
+        };
+        let offset = slice.end - beat_start;
+        let end = (beat_start + (offset + grid / 2) / grid * grid).min(beat_end);
+        if end > position {
+            snapped.push(Slice {
+                start: position,
+                end,
+                notes: slice.notes,
+            });
+            position = end;
+        }
+    }
+    snapped
+}
+
+/// Part of a slice written in a measure. The slices are cut at the barlines and around the
+/// beats played as triplets.
+pub(crate) struct Piece<'a> {
+    pub notes: &'a [u8],
+    pub start: i64,
+    pub end: i64,
+    /// Tied to the previous piece of the same chord
+    pub tie_stop: bool,
+    /// Tied to the next piece of the same chord
+    pub tie_start: bool,
+    /// Index of the piece in the triplet of its beat, and number of pieces of the triplet
+    pub triplet: Option<(usize, usize)>,
+}
+
+/// Pieces of the slices written in the measure starting at `measure_start`
+pub(crate) fn measure_pieces(
+    slices: &[Slice],
+    measure_start: i64,
+    measure_len: i64,
+) -> Vec<Piece<'_>> {
+    let measure_end = measure_start + measure_len;
+    let boundaries: Vec<i64> = slices.iter().map(|slice| slice.end).collect();
+    let triplet_beat = |position: i64| {
+        let (beat_start, beat_end) = beat_bounds(position, measure_len);
+        is_triplet_beat(beat_start, beat_end, &boundaries).then_some((beat_start, beat_end))
+    };
+
+    let mut pieces = Vec::new();
+    // Beat of the triplet of each piece
+    let mut beats = Vec::new();
+    for slice in slices
+        .iter()
+        .filter(|slice| slice.start < measure_end && slice.end > measure_start)
+    {
+        let end = slice.end.min(measure_end);
+        let mut start = slice.start.max(measure_start);
+        while start < end {
+            let triplet = triplet_beat(start);
+            let piece_end = match triplet {
+                Some((_, beat_end)) => beat_end.min(end),
+                // Up to the next beat played as a triplet
+                None => {
+                    let mut piece_end = end;
+                    let mut beat = beat_bounds(start, measure_len).1;
+                    while beat < end {
+                        if triplet_beat(beat).is_some() {
+                            piece_end = beat;
+                            break;
+                        }
+                        beat = beat_bounds(beat, measure_len).1;
+                    }
+                    piece_end
+                }
+            };
+
+            let tied = !slice.notes.is_empty();
+            pieces.push(Piece {
+                notes: &slice.notes,
+                start,
+                end: piece_end,
+                tie_stop: tied && start > slice.start,
+                tie_start: tied && piece_end < slice.end,
+                triplet: None,
+            });
+            beats.push(triplet.map(|(beat_start, _)| beat_start));
+            start = piece_end;
+        }
+    }
+
+    let mut first = 0;
+    while first < pieces.len() {
+        let beat = beats[first];
+        let count = beats[first..]
+            .iter()
+            .take_while(|other| **other == beat)
+            .count();
+        if beat.is_some() {
+            for (position, piece) in pieces[first..first + count].iter_mut().enumerate() {
+                piece.triplet = Some((position, count));
+            }
+        }
+        first += count;
+    }
+    pieces
+}
+
+/// Splits a duration into note values that can be written, tied together, only using the
+/// triplet values inside a triplet
+fn note_values(mut duration: i64, triplet: bool) -> Vec<(i64, &'static str, bool, bool)> {
+    let mut values = Vec::new();
+    while duration > 0 {
+        let value = NOTE_VALUES
+            .iter()
+            .filter(|value| value.3 == triplet)
+            .find(|(divisions, ..)| *divisions <= duration)
+            .copied()
+            .unwrap_or(NOTE_VALUES[NOTE_VALUES.len() - 1]);
+        values.push(value);
+        duration -= value.0;
+    }
+    values
+}
+
+/// Ties and triplet bracket of a written note
+#[derive(Clone, Copy)]
+struct Notations {
+    tie_start: bool,
+    tie_stop: bool,
+    /// `start` or `stop` for the first and last notes of a triplet
+    tuplet: Option<&'static str>,
+}
+
+impl Notations {
+    /// Writes the notations of a rest or of a note of a chord. The ties are not written for the
+    /// rests, nor the bracket for the other notes of the chord.
+    fn write(self, xml: &mut String, tied: bool, tuplet: bool) {
+        let tuplet = self.tuplet.filter(|_| tuplet);
+        let tie_start = self.tie_start && tied;
+        let tie_stop = self.tie_stop && tied;
+        if !(tie_start || tie_stop || tuplet.is_some()) {
+            return;
+        }
+
+        xml.push_str("        <notations>\n");
+        if tie_stop {
+            xml.push_str("          <tied type=\"stop\"/>\n");
+        }
+        if tie_start {
+            xml.push_str("          <tied type=\"start\"/>\n");
+        }
+        if let Some(tuplet) = tuplet {
+            let _ = writeln!(
+                xml,
+                "          <tuplet type=\"{}\" bracket=\"yes\"/>",
+                tuplet
+            );
+        }
+        xml.push_str("        </notations>\n");
+    }
+}
+
+fn write_note(
+    xml: &mut String,
+    notes: &[u8],
+    value: (i64, &str, bool, bool),
+    notations: Notations,
+    fifths: i32,
+    drums: bool,
+) {
+    let (duration, kind, dotted, triplet) = value;
+    if notes.is_empty() {
+        let _ = writeln!(
+            xml,
+            "      <note>\n        <rest/>\n        <duration>{}</duration>",
+            duration
+        );
+        write_value(xml, kind, dotted, triplet);
+        notations.write(xml, false, true);
+        xml.push_str("      </note>\n");
+        return;
+    }
+
+    for (index, note) in notes.iter().enumerate() {
+        xml.push_str("      <note>\n");
+        if index > 0 {
+            xml.push_str("        <chord/>\n");
+        }
+        let (step, alter, octave) = spell(*note, fifths);
+        if drums {
+            let _ = writeln!(
+                xml,
+                "        <unpitched>\n          <display-step>{}</display-step>\n          <display-octave>{}</display-octave>\n        </unpitched>",
+                step, octave
+            );
+        } else {
+            let _ = writeln!(xml, "        <pitch>\n          <step>{}</step>", step);
+            if alter != 0 {
+                let _ = writeln!(xml, "          <alter>{}</alter>", alter);
+            }
+            let _ = writeln!(
+                xml,
+                "          <octave>{}</octave>\n        </pitch>",
+                octave
+            );
+        }
+        let _ = writeln!(xml, "        <duration>{}</duration>", duration);
+        if notations.tie_stop {
+            xml.push_str("        <tie type=\"stop\"/>\n");
+        }
+        if notations.tie_start {
+            xml.push_str("        <tie type=\"start\"/>\n");
+        }
+        write_value(xml, kind, dotted, triplet);
+        // The bracket is only written once for a chord
+        notations.write(xml, true, index == 0);
+        xml.push_str("      </note>\n");
+    }
+}
+
+fn write_value(xml: &mut String, kind: &str, dotted: bool, triplet: bool) {
+    let _ = writeln!(xml, "        <type>{}</type>", kind);
+    if dotted {
+        xml.push_str("        <dot/>\n");
+    }
+    if triplet {
+        xml.push_str(
+            "        <time-modification>\n          <actual-notes>3</actual-notes>\n          <normal-notes>2</normal-notes>\n        </time-modification>\n",
+        );
+    }
+}
+
+/// Time signature of the score and length and number of its measures, in divisions
+pub(crate) fn measures(events: &[MusicEvent], info: &ScoreInfo) -> (i32, i32, i64, i64) {
+    let numerator = info.time_sig_numerator.max(1);
+    let denominator = if info.time_sig_denominator > 0 {
+        info.time_sig_denominator
+    } else {
+        4
+    };
+    let measure_len = numerator as i64 * DIVISIONS * 4 / denominator as i64;
+
+    let clip_end = events
+        .iter()
+        .map(|event| ((event.time + event.duration) * DIVISIONS as f64).round() as i64)
+        .max()
+        .unwrap_or(0);
+    let measure_count = ((clip_end + measure_len - 1) / measure_len).max(1);
+    (numerator, denominator, measure_len, measure_count)
+}
+
+/// Tracks of the events, each of them written as a part
+pub(crate) fn tracks(events: &[MusicEvent]) -> Vec<u8> {
+    let mut tracks: Vec<u8> = events.iter().map(|event| event.track).collect();
+    tracks.sort_unstable();
+    tracks.dedup();
+    tracks
+}
+
+/// Events of a track sorted by time
+pub(crate) fn track_events(events: &[MusicEvent], track: u8) -> Vec<&MusicEvent> {
+    let mut track_events: Vec<&MusicEvent> =
+        events.iter().filter(|event| event.track == track).collect();
+    track_events.sort_by(|a, b| a.time.total_cmp(&b.time));
+    track_events
+}
+
+pub(crate) fn part_name(info: &ScoreInfo, track: u8) -> String {
+    info.parts
+        .get(track as usize)
+        .map(|style| style.to_string())
+        .unwrap_or_else(|| format!("Part {}", track + 1))
+}
+
+pub(crate) fn is_drum_part(info: &ScoreInfo, track: u8) -> bool {
+    info.parts
+        .get(track as usize)
+        .is_some_and(|style| style.is_drums())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Writes the events as a MusicXML score, one part per `track` of the events
+pub fn to_musicxml(events: &[MusicEvent], info: &ScoreInfo) -> String {
+    let fifths = key_fifths(info.root, info.mode);
+    let (numerator, denominator, measure_len, measure_count) = measures(events, info);
+    let total = measure_count * measure_len;
+    let tracks = tracks(events);
+
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 3.1 Partwise//EN\" \"http://www.musicxml.org/dtds/partwise.dtd\">\n<score-partwise version=\"3.1\">\n  <part-list>\n",
+    );
+    for track in &tracks {
+        let name = part_name(info, *track);
+        let _ = writeln!(
+            xml,
+            "    <score-part id=\"P{}\">\n      <part-name>{}</part-name>\n    </score-part>",
+            track + 1,
+            escape(&name)
+        );
+    }
+    xml.push_str("  </part-list>\n");
+
+    for track in &tracks {
+        let drums = is_drum_part(info, *track);
+        let part_events = track_events(events, *track);
+        let average_note = part_events
+            .iter()
+            .map(|event| event.note as u32)
+            .sum::<u32>()
+            / part_events.len().max(1) as u32;
+
+        let _ = writeln!(xml, "  <part id=\"P{}\">", track + 1);
+        let slices = slices(&part_events, measure_len, total);
+        for measure in 0..measure_count {
+            let measure_start = measure * measure_len;
+            let _ = writeln!(xml, "    <measure number=\"{}\">", measure + 1);
+            if measure == 0 {
+                let clef = if drums {
+                    "<sign>percussion</sign>"
+                } else if average_note < 60 {
+                    "<sign>F</sign>\n          <line>4</line>"
+                } else {
+                    "<sign>G</sign>\n          <line>2</line>"
+                };
+                let mode = match info.mode {
+                    Mode::Major => "major",
+                    Mode::Minor => "minor",
+                };
+                let _ = writeln!(
+                    xml,
+                    "      <attributes>\n        <divisions>{}</divisions>\n        <key>\n          <fifths>{}</fifths>\n          <mode>{}</mode>\n        </key>\n        <time>\n          <beats>{}</beats>\n          <beat-type>{}</beat-type>\n        </time>\n        <clef>\n          {}\n        </clef>\n      </attributes>",
+                    DIVISIONS, fifths, mode, numerator, denominator, clef
+                );
+                let _ = writeln!(
+                    xml,
+                    "      <direction placement=\"above\">\n        <direction-type>\n          <metronome>\n            <beat-unit>quarter</beat-unit>\n            <per-minute>{}</per-minute>\n          </metronome>\n        </direction-type>\n        <sound tempo=\"{}\"/>\n      </direction>",
+                    info.bpm.round(),
+                    info.bpm.round()
+                );
+            }
+
+            // The chords crossing the barline or a triplet are split and tied
+            for piece in measure_pieces(&slices, measure_start, measure_len) {
+                let values = note_values(piece.end - piece.start, piece.triplet.is_some());
+                for (index, value) in values.iter().enumerate() {
+                    let tuplet = match piece.triplet {
+                        Some((0, _)) if index == 0 => Some("start"),
+                        Some((position, count))
+                            if position + 1 == count && index + 1 == values.len() =>
+                        {
+                            Some("stop")
+                        }
+                        _ => None,
+                    };
+                    let notations = Notations {
+                        tie_start: piece.tie_start || index + 1 < values.len(),
+                        tie_stop: piece.tie_stop || index > 0,
+                        tuplet,
+                    };
+                    write_note(&mut xml, piece.notes, *value, notations, fifths, drums);
+                }
+            }
+            xml.push_str("    </measure>\n");
+        }
+        xml.push_str("  </part>\n");
+    }
+
+    xml.push_str("</score-partwise>\n");
+    xml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(note: u8, time: f64, duration: f64) -> MusicEvent {
+        MusicEvent {
+            channel: 0,
+            duration,
+            note,
+            time,
+            track: 0,
+            velocity: 100,
+        }
+    }
+
+    fn info() -> ScoreInfo<'static> {
+        ScoreInfo {
+            parts: &[Style::AcousticGrand],
+            root: Note::C,
+            mode: Mode::Major,
+            bpm: 120.0,
+            time_sig_numerator: 4,
+            time_sig_denominator: 4,
+        }
+    }
+
+    /// Start, end and triplet of the pieces of the first measure
+    type Pieces = Vec<(i64, i64, Option<(usize, usize)>)>;
+
+    fn pieces(events: &[MusicEvent]) -> Pieces {
+        let events: Vec<&MusicEvent> = events.iter().collect();
+        let slices = slices(&events, 48, 48);
+        measure_pieces(&slices, 0, 48)
+            .iter()
+            .map(|piece| (piece.start, piece.end, piece.triplet))
+            .collect()
+    }
+
+    #[test]
+    fn keys_are_placed_in_the_circle_of_fifths() {
+        assert_eq!(key_fifths(Note::C, Mode::Major), 0);
+        assert_eq!(key_fifths(Note::F, Mode::Major), -1);
+        assert_eq!(key_fifths(Note::FSharp, Mode::Major), 6);
+        assert_eq!(key_fifths(Note::E, Mode::Minor), 1);
+        assert_eq!(key_fifths(Note::C, Mode::Minor), -3);
+    }
+
+    #[test]
+    fn triplets_are_grouped_by_beat() {
+        let third = 1.0 / 3.0;
+        let events = [
+            event(60, 0.0, third),
+            event(62, third, third),
+            event(64, 2.0 * third, third),
+            event(65, 1.0, 1.0),
+        ];
+        assert_eq!(
+            pieces(&events),
+            vec![
+                (0, 4, Some((0, 3))),
+                (4, 8, Some((1, 3))),
+                (8, 12, Some((2, 3))),
+                (12, 24, None),
+                (24, 48, None),
+            ]
+        );
+
+        let xml = to_musicxml(&events, &info());
+        assert_eq!(xml.matches("<tuplet type=\"start\"").count(), 1);
+        assert_eq!(xml.matches("<tuplet type=\"stop\"").count(), 1);
+        assert_eq!(xml.matches("<time-modification>").count(), 3);
+    }
+
+    #[test]
+    fn notes_crossing_a_triplet_are_tied() {
+        // A quarter note triplet then a note held from the last eighth note triplet
+        let third = 1.0 / 3.0;
+        let events = [
+            event(60, 0.0, 2.0 * third),
+            event(62, 2.0 * third, 4.0 * third),
+        ];
+        assert_eq!(
+            pieces(&events),
+            vec![
+                (0, 8, Some((0, 2))),
+                (8, 12, Some((1, 2))),
+                (12, 24, None),
+                (24, 48, None),
+            ]
+        );
+
+        let xml = to_musicxml(&events, &info());
+        assert_eq!(xml.matches("<tied type=\"start\"/>").count(), 1);
+        assert_eq!(xml.matches("<tied type=\"stop\"/>").count(), 1);
+    }
+
+    #[test]
+    fn straight_notes_are_snapped_to_sixteenths() {
+        // A late eighth note is not taken for a triplet
+        let events = [event(60, 0.0, 0.55), event(62, 0.55, 0.45)];
+        assert_eq!(
+            pieces(&events),
+            vec![(0, 6, None), (6, 12, None), (12, 48, None)]
+        );
+
+        let xml = to_musicxml(&events, &info());
+        assert!(!xml.contains("<tuplet"));
+        assert!(!xml.contains("<time-modification>"));
+    }
+}
@@ -0,0 +1,360 @@
+use crate::drums;
+use crate::editor::Mode;
+use crate::musicxml::{self, ScoreInfo, DIVISIONS, NOTE_VALUES};
+use crate::preview::MusicEvent;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Divisions of the unit note length of the ABC tunes, a sixteenth note
+const ABC_UNIT: i64 = DIVISIONS / 4;
+
+/// Measures written on each line of the ABC tunes
+const ABC_MEASURES_PER_LINE: i64 = 4;
+
+/// Abbreviations of the note values of `NOTE_VALUES` in the note list
+const NOTE_VALUE_NAMES: [&str; 12] = [
+    "w", "h.", "h", "q.", "q", "e.", "q3", "e", "e3", "s", "s3", "t3",
+];
+
+/// Text formats a generation can be copied as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotationFormat {
+    Abc,
+    NoteList,
+}
+
+impl NotationFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            NotationFormat::Abc => "abc",
+            NotationFormat::NoteList => "txt",
+        }
+    }
+
+    pub fn write(self, events: &[MusicEvent], info: &ScoreInfo) -> String {
+        match self {
+            NotationFormat::Abc => to_abc(events, info),
+            NotationFormat::NoteList => to_note_list(events, info),
+        }
+    }
+}
+
+impl std::fmt::Display for NotationFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            NotationFormat::Abc => "ABC",
+            NotationFormat::NoteList => "Note list",
+        })
+    }
+}
+
+/// Name of the key of the ABC header, from its position in the circle of fifths
+fn abc_key(fifths: i32, mode: Mode) -> &'static str {
+    const MAJOR: [&str; 12] = [
+        "Db", "Ab", "Eb", "Bb", "F", "C", "G", "D", "A", "E", "B", "F#",
+    ];
+    const MINOR: [&str; 12] = [
+        "Bbm", "Fm", "Cm", "Gm", "Dm", "Am", "Em", "Bm", "F#m", "C#m", "G#m", "D#m",
+    ];
+
+    let index = (fifths + 5).clamp(0, 11) as usize;
+    match mode {
+        Mode::Major => MAJOR[index],
+        Mode::Minor => MINOR[index],
+    }
+}
+
+/// Alteration of a step in the key signature
+fn key_alter(step: char, fifths: i32) -> i32 {
+    if fifths > 0 && "FCGDAEB".chars().take(fifths as usize).any(|s| s == step) {
+        1
+    } else if fifths < 0 && "BEADGCF".chars().take(-fifths as usize).any(|s| s == step) {
+        -1
+    } else {
+        0
+    }
+}
+
+/// Length of a note in units, omitted for a single unit. The slices are snapped to the
+/// sixteenth notes, or to the eighth note triplets written half as long again.
+fn abc_length(duration: i64) -> String {
+    match duration / ABC_UNIT {
+        1 => String::new(),
+        units => units.to_string(),
+    }
+}
+
+/// Marker put before the notes of a triplet, three notes in the time of two
+fn abc_triplet(count: usize) -> String {
+    if count == 3 {
+        String::from("(3")
+    } else {
+        format!("(3:2:{}", count)
+    }
+}
+
+/// Writes a note in the ABC syntax. An accidental is only written when the alteration differs
+/// from the key signature or from the previous note of the measure on the same step.
+fn write_abc_pitch(
+    abc: &mut String,
+    note: u8,
+    fifths: i32,
+    accidentals: &mut HashMap<(char, i32), i32>,
+) {
+    let (step, alter, octave) = musicxml::spell(note, fifths);
+    let current = *accidentals
+        .get(&(step, octave))
+        .unwrap_or(&key_alter(step, fifths));
+    if alter != current {
+        abc.push_str(match alter {
+            1 => "^",
+            -1 => "_",
+            _ => "=",
+        });
+        accidentals.insert((step, octave), alter);
+    }
+
+    if octave >= 5 {
+        abc.push(step.to_ascii_lowercase());
+        for _ in 5..octave {
+            abc.push('\'');
+        }
+    } else {
+        abc.push(step);
+        for _ in octave..4 {
+            abc.push(',');
+        }
+    }
+}
+
+/// Writes the events as an ABC tune, one voice per `track` of the events
+pub fn to_abc(events: &[MusicEvent], info: &ScoreInfo) -> String {
+    let fifths = musicxml::key_fifths(info.root, info.mode);
+    let (numerator, denominator, measure_len, measure_count) = musicxml::measures(events, info);
+    let total = measure_count * measure_len;
+
+    let mut abc = String::new();
+    let _ = writeln!(
+        abc,
+        "X:1\nT:Harmonia\nM:{}/{}\nL:1/16",
+        numerator, denominator
+    );
+    let _ = writeln!(abc, "Q:1/4={}", info.bpm.round());
+    let _ = writeln!(abc, "K:{}", abc_key(fifths, info.mode));
+
+    for track in musicxml::tracks(events) {
+        let part_events = musicxml::track_events(events, track);
+        let clef = if musicxml::is_drum_part(info, track) {
+            "perc"
+        } else if part_events
+            .iter()
+            .map(|event| event.note as u32)
+            .sum::<u32>()
+            < 60 * part_events.len() as u32
+        {
+            "bass"
+        } else {
+            "treble"
+        };
+        let _ = writeln!(
+            abc,
+            "V:{} name=\"{}\" clef={}",
+            track + 1,
+            musicxml::part_name(info, track).replace('"', "'"),
+            clef
+        );
+
+        let slices = musicxml::slices(&part_events, measure_len, total);
+        for measure in 0..measure_count {
+            let measure_start = measure * measure_len;
+            let mut accidentals = HashMap::new();
+
+            // The chords crossing the barline or a triplet are split and tied
+            for piece in musicxml::measure_pieces(&slices, measure_start, measure_len) {
+                let mut duration = piece.end - piece.start;
+                if let Some((position, count)) = piece.triplet {
+                    if position == 0 {
+                        abc.push_str(&abc_triplet(count));
+                    }
+                    duration = duration * 3 / 2;
+                }
+                match piece.notes {
+                    [] => abc.push('z'),
+                    [note] => write_abc_pitch(&mut abc, *note, fifths, &mut accidentals),
+                    notes => {
+                        abc.push('[');
+                        for note in notes {
+                            write_abc_pitch(&mut abc, *note, fifths, &mut accidentals);
+                        }
+                        abc.push(']');
+                    }
+                }
+                abc.push_str(&abc_length(duration));
+                if piece.tie_start {
+                    abc.push('-');
+                }
+                abc.push(' ');
+            }
+
+            if measure + 1 == measure_count {
+                abc.push_str("|]\n");
+            } else if (measure + 1) % ABC_MEASURES_PER_LINE == 0 {
+                abc.push_str("|\n");
+            } else {
+                abc.push_str("| ");
+            }
+        }
+    }
+    abc
+}
+
+/// Name of a note in the note list, the percussion of the General MIDI map for the drums
+fn note_name(note: u8, fifths: i32, drums: bool) -> String {
+    if drums {
+        if let Some(name) = drums::percussion_name(note) {
+            return name.to_string();
+        }
+    }
+
+    let (step, alter, octave) = musicxml::spell(note, fifths);
+    let accidental = match alter {
+        1 => "#",
+        -1 => "b",
+        _ => "",
+    };
+    format!("{}{}{}", step, accidental, octave)
+}
+
+/// Note value of a duration in beats, or the duration itself when it has no name
+fn note_value_name(duration: f64) -> String {
+    let divisions = duration * DIVISIONS as f64;
+    NOTE_VALUES
+        .iter()
+        .position(|(value, ..)| (*value as f64 - divisions).abs() < 0.05)
+        .map(|index| NOTE_VALUE_NAMES[index].to_string())
+        .unwrap_or_else(|| format!("{} beats", format_number(duration)))
+}
+
+/// Writes a number with at most two decimals and without trailing zeros
+fn format_number(value: f64) -> String {
+    let text = format!("{:.2}", value);
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Writes the events as a list of notes readable without any software, one line per note such
+/// as `bar 1 beat 1: C4 q vel 90`
+pub fn to_note_list(events: &[MusicEvent], info: &ScoreInfo) -> String {
+    let fifths = musicxml::key_fifths(info.root, info.mode);
+    let (numerator, denominator, ..) = musicxml::measures(events, info);
+    // Beats of the time signature and measures in quarter notes, like the events
+    let beat_len = 4.0 / denominator as f64;
+    let measure_len = numerator as f64 * beat_len;
+
+    let mut list = String::new();
+    let _ = writeln!(
+        list,
+        "{} {}, {} BPM, {}/{}",
+        info.root,
+        info.mode,
+        format_number(info.bpm),
+        numerator,
+        denominator
+    );
+
+    for track in musicxml::tracks(events) {
+        let drums = musicxml::is_drum_part(info, track);
+        let _ = writeln!(list, "\n[{}]", musicxml::part_name(info, track));
+        for event in musicxml::track_events(events, track) {
+            let bar = (event.time / measure_len + 1e-6).floor();
+            let beat = (event.time - bar * measure_len) / beat_len + 1.0;
+            let _ = writeln!(
+                list,
+                "bar {} beat {}: {} {} vel {}",
+                bar as i64 + 1,
+                format_number(beat),
+                note_name(event.note, fifths, drums),
+                note_value_name(event.duration),
+                event.velocity
+            );
+        }
+    }
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::{Note, Style};
+
+    fn event(note: u8, time: f64, duration: f64) -> MusicEvent {
+        MusicEvent {
+            channel: 0,
+            duration,
+            note,
+            time,
+            track: 0,
+            velocity: 90,
+        }
+    }
+
+    fn info(root: Note, mode: Mode) -> ScoreInfo<'static> {
+        ScoreInfo {
+            parts: &[Style::AcousticGrand],
+            root,
+            mode,
+            bpm: 100.0,
+            time_sig_numerator: 4,
+            time_sig_denominator: 4,
+        }
+    }
+
+    /// Notes of the only voice of a tune
+    fn abc_notes(abc: &str) -> &str {
+        abc.lines().last().unwrap()
+    }
+
+    #[test]
+    fn keys_are_named_from_the_circle_of_fifths() {
+        assert_eq!(abc_key(0, Mode::Major), "C");
+        assert_eq!(abc_key(-3, Mode::Major), "Eb");
+        assert_eq!(abc_key(1, Mode::Minor), "Em");
+        assert_eq!(abc_key(6, Mode::Major), "F#");
+    }
+
+    #[test]
+    fn triplets_are_written_as_tuplets() {
+        let third = 1.0 / 3.0;
+        let events = [
+            event(72, 0.0, third),
+            event(74, third, third),
+            event(76, 2.0 * third, third),
+            event(77, 1.0, 2.0 * third),
+            event(79, 5.0 * third, 2.0 * third),
+        ];
+        let abc = to_abc(&events, &info(Note::C, Mode::Major));
+        assert_eq!(abc_notes(&abc), "(3c2 d2 e2 (3:2:2f4 g2- (3:2:2g2 z4 z4 |]");
+    }
+
+    #[test]
+    fn accidentals_follow_the_key_and_the_measure() {
+        let events = [
+            event(66, 0.0, 1.0),
+            event(65, 1.0, 1.0),
+            event(65, 2.0, 1.0),
+            event(65, 4.0, 4.0),
+        ];
+        let abc = to_abc(&events, &info(Note::G, Mode::Major));
+        assert!(abc.contains("K:G"));
+        assert_eq!(abc_notes(&abc), "F4 =F4 F4 z4 | =F16 |]");
+    }
+
+    #[test]
+    fn note_list_names_the_values() {
+        let events = [event(60, 0.0, 1.0), event(62, 1.5, 1.0 / 3.0)];
+        let list = to_note_list(&events, &info(Note::C, Mode::Major));
+        assert_eq!(
+            list,
+            "C Major, 100 BPM, 4/4\n\n[Acoustic Grand]\nbar 1 beat 1: C4 q vel 90\nbar 1 beat 2.5: D4 e3 vel 90\n"
+        );
+    }
+}
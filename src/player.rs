@@ -0,0 +1,757 @@
+use crate::ensemble::{Routing, MAX_PARTS};
+use crate::host::Host;
+use crate::preview::MusicEvent;
+use crate::transform::Transform;
+use nih_plug::prelude::*;
+
+/// Maximum number of notes held at the same time by the player
+const MAX_ACTIVE_NOTES: usize = 128;
+/// Clips replaced between two calls to [`PreviewPlayer::take_released`]
+const MAX_RELEASED: usize = 8;
+/// Clips a change of the events can release: the played and the queued clips, then the queued
+/// clip started by the next call to [`PreviewPlayer::process`]
+const RELEASES_PER_CHANGE: usize = 3;
+
+/// When a new preview starts playing, relative to the bar grid of the host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LaunchQuantize {
+    Immediately,
+    NextBeat,
+    NextBar,
+    /// Waits for the next bar that is a multiple of the `Launch Bars` parameter
+    NextBars,
+}
+
+impl Enum for LaunchQuantize {
+    fn variants() -> &'static [&'static str] {
+        &["Immediately", "Next Beat", "Next Bar", "Next N Bars"]
+    }
+
+    fn to_index(self) -> usize {
+        match self {
+            LaunchQuantize::Immediately => 0,
+            LaunchQuantize::NextBeat => 1,
+            LaunchQuantize::NextBar => 2,
+            LaunchQuantize::NextBars => 3,
+        }
+    }
+
+    fn from_index(index: usize) -> Self {
+        match index {
+            1 => LaunchQuantize::NextBeat,
+            2 => LaunchQuantize::NextBar,
+            3 => LaunchQuantize::NextBars,
+            _ => LaunchQuantize::Immediately,
+        }
+    }
+
+    fn ids() -> Option<&'static [&'static str]> {
+        None
+    }
+}
+
+impl LaunchQuantize {
+    pub const ALL: [LaunchQuantize; 4] = [
+        LaunchQuantize::Immediately,
+        LaunchQuantize::NextBeat,
+        LaunchQuantize::NextBar,
+        LaunchQuantize::NextBars,
+    ];
+}
+
+impl std::fmt::Display for LaunchQuantize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(Self::variants()[self.to_index()])
+    }
+}
+
+/// Position of the host transport at the start of the block
+#[derive(Debug, Clone, Copy)]
+pub struct TransportInfo {
+    pub playing: bool,
+    /// Position in quarter notes
+    pub pos_beats: Option<f64>,
+    pub bar_start_pos_beats: Option<f64>,
+    pub bar_number: Option<i32>,
+    /// Length of a bar in quarter notes
+    pub bar_length: f64,
+}
+
+/// Length of a bar in quarter notes
+pub fn bar_length(time_sig_numerator: i32, time_sig_denominator: i32) -> f64 {
+    let denominator = if time_sig_denominator > 0 {
+        time_sig_denominator
+    } else {
+        4
+    };
+    time_sig_numerator.max(1) as f64 * 4.0 / denominator as f64
+}
+
+/// Bar the host is currently in, counted from 0
+pub fn bar_index(transport: &TransportInfo) -> Option<i32> {
+    transport.bar_number.or_else(|| {
+        transport
+            .pos_beats
+            .map(|pos| (pos / transport.bar_length).floor() as i32)
+    })
+}
+
+/// Host position, in quarter notes, at which a preview scheduled now should start
+pub fn launch_position(launch: LaunchQuantize, bars: i32, transport: &TransportInfo) -> f64 {
+    const EPSILON: f64 = 1e-6;
+
+    let pos = match transport.pos_beats {
+        Some(pos) => pos,
+        None => return 0.0,
+    };
+    let bar_length = transport.bar_length;
+    let bar_start = transport
+        .bar_start_pos_beats
+        .unwrap_or((pos / bar_length).floor() * bar_length);
+    let bar_number = transport
+        .bar_number
+        .unwrap_or((bar_start / bar_length).round() as i32);
+
+    let bars = match launch {
+        LaunchQuantize::Immediately => return pos,
+        LaunchQuantize::NextBeat => return (pos - EPSILON).ceil().max(pos),
+        LaunchQuantize::NextBar => 1,
+        LaunchQuantize::NextBars => bars.max(1),
+    };
+
+    let offset = bar_number.rem_euclid(bars);
+    if offset == 0 && pos - bar_start < EPSILON {
+        bar_start
+    } else {
+        bar_start + (bars - offset) as f64 * bar_length
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ActiveNote {
+    channel: u8,
+    note: u8,
+    end: f64,
+}
+
+/// Plays the events of a generation as MIDI notes, following the tempo of the host
+pub struct PreviewPlayer {
+    events: Vec<MusicEvent>,
+    next_event: usize,
+    /// Position in the clip, in beats
+    position: f64,
+    playing: bool,
+    /// Host position at which the playback has to start, if it is waiting for the bar grid
+    pending_launch: Option<f64>,
+    /// Clip replacing the current one at the pending launch, the current one playing until then
+    queued: Option<Vec<MusicEvent>>,
+    /// End of the last note of the clip, in beats
+    clip_end: f64,
+    /// Length of the loop in beats, `None` to play the clip once
+    loop_length: Option<f64>,
+    /// Tracks heard in the preview, from the mute and solo of the parts
+    audible: [bool; MAX_PARTS],
+    routing: Routing,
+    /// Set when the clip starts, so the programs of the parts are sent before the first notes
+    programs_pending: bool,
+    active: Vec<ActiveNote>,
+    /// Clips replaced by new ones, kept so they are not freed on the audio thread
+    released: Vec<Vec<MusicEvent>>,
+    /// Notes not played because too many notes were held, since the last `take_dropped`
+    dropped: u32,
+}
+
+impl PreviewPlayer {
+    pub fn new() -> Self {
+        PreviewPlayer {
+            events: Vec::new(),
+            next_event: 0,
+            position: 0.0,
+            playing: false,
+            pending_launch: None,
+            queued: None,
+            clip_end: 0.0,
+            loop_length: None,
+            audible: [true; MAX_PARTS],
+            routing: Routing::default(),
+            programs_pending: false,
+            active: Vec::with_capacity(MAX_ACTIVE_NOTES),
+            released: Vec::with_capacity(MAX_RELEASED),
+            dropped: 0,
+        }
+    }
+
+    /// Replaces the played events, they need to be sorted by time
+    pub fn load(&mut self, events: Vec<MusicEvent>) {
+        self.clip_end = clip_end(&events);
+        self.set_events(events);
+        self.playing = false;
+        self.pending_launch = None;
+        if let Some(queued) = self.queued.take() {
+            self.release(queued);
+        }
+    }
+
+    /// Replaces the played events without interrupting the playback. If a clip is queued, it is
+    /// the one replaced.
+    pub fn replace(&mut self, events: Vec<MusicEvent>) {
+        if let Some(queued) = self.queued.as_mut() {
+            let previous = std::mem::replace(queued, events);
+            self.release(previous);
+            return;
+        }
+
+        self.next_event = events.partition_point(|event| event.time < self.position);
+        self.clip_end = clip_end(&events);
+        self.set_events(events);
+    }
+
+    /// Starts playing from the beginning of the clip right away
+    pub fn start(&mut self) {
+        self.position = 0.0;
+        self.next_event = 0;
+        self.pending_launch = None;
+        self.playing = !self.events.is_empty();
+        self.programs_pending = self.playing;
+    }
+
+    /// Starts playing once the host reaches `host_position`, in quarter notes
+    pub fn schedule(&mut self, host_position: f64) {
+        self.playing = false;
+        self.pending_launch = Some(host_position);
+    }
+
+    /// Keeps playing the current clip until the host reaches `host_position`, then starts
+    /// playing `events` from their beginning
+    pub fn queue(&mut self, events: Vec<MusicEvent>, host_position: f64) {
+        if let Some(previous) = self.queued.replace(events) {
+            self.release(previous);
+        }
+        self.pending_launch = Some(host_position);
+    }
+
+    /// Stops the playback, the held notes are released on the next call to [`Self::process`]
+    pub fn stop(&mut self) {
+        self.playing = false;
+        self.pending_launch = None;
+        // A queued clip is the latest one, so it is the one played on the next start
+        if let Some(events) = self.queued.take() {
+            self.clip_end = clip_end(&events);
+            self.set_events(events);
+        }
+    }
+
+    /// Takes a clip replaced since the last call, to be freed out of the audio thread
+    pub fn take_released(&mut self) -> Option<Vec<MusicEvent>> {
+        self.released.pop()
+    }
+
+    /// Whether the clips released by one more change of the events are kept until they are
+    /// taken, rather than freed here
+    pub fn can_release(&self) -> bool {
+        self.released.len() + RELEASES_PER_CHANGE <= MAX_RELEASED
+    }
+
+    /// Number of notes dropped since the previous call
+    pub fn take_dropped(&mut self) -> u32 {
+        std::mem::take(&mut self.dropped)
+    }
+
+    fn set_events(&mut self, events: Vec<MusicEvent>) {
+        let previous = std::mem::replace(&mut self.events, events);
+        self.release(previous);
+    }
+
+    /// Keeps a replaced clip until it is taken. The clip is only freed here when the previous
+    /// ones have not been taken.
+    fn release(&mut self, events: Vec<MusicEvent>) {
+        if events.capacity() > 0 && self.released.len() < MAX_RELEASED {
+            self.released.push(events);
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending_launch.is_some()
+    }
+
+    /// Loops the clip over its length rounded up to whole bars, instead of playing it once
+    pub fn set_looping(&mut self, looping: bool, bar_length: f64) {
+        self.loop_length = if looping && bar_length > 0.0 {
+            Some((self.clip_end / bar_length).ceil().max(1.0) * bar_length)
+        } else {
+            None
+        };
+    }
+
+    /// Mutes the tracks set to `false`, the notes already playing are not cut
+    pub fn set_audible_tracks(&mut self, audible: [bool; MAX_PARTS]) {
+        self.audible = audible;
+    }
+
+    /// Output channels and programs of the parts, the notes already playing keep their channel
+    pub fn set_routing(&mut self, routing: Routing) {
+        self.routing = routing;
+    }
+
+    pub fn process(
+        &mut self,
+        context: &mut impl Host,
+        block_len: usize,
+        beats_per_sample: f64,
+        transport: &TransportInfo,
+        transform: &Transform,
+    ) {
+        let mut start_sample = 0;
+        if let Some(launch) = self.pending_launch {
+            let launch_offset = match transport.pos_beats {
+                Some(pos) if transport.playing => {
+                    Some(((launch - pos) / beats_per_sample).max(0.0))
+                }
+                // Without a position the preview cannot be synced, so it starts right away
+                None => Some(0.0),
+                _ => None,
+            };
+
+            match launch_offset {
+                Some(offset) if offset < block_len as f64 => {
+                    let launch_sample = (offset.round() as usize).min(block_len - 1);
+                    // The current clip keeps playing until the launch
+                    if self.playing {
+                        self.play_until(context, 0, launch_sample, beats_per_sample, transform);
+                    }
+                    self.release_all(context, launch_sample as u32);
+                    if let Some(events) = self.queued.take() {
+                        self.clip_end = clip_end(&events);
+                        self.set_events(events);
+                    }
+                    self.start();
+                    start_sample = launch_sample;
+                }
+                _ => {
+                    if self.playing {
+                        self.play_until(context, 0, block_len, beats_per_sample, transform);
+                    } else {
+                        // The notes of the previous preview are released while waiting
+                        self.release_all(context, 0);
+                    }
+                    return;
+                }
+            }
+        }
+
+        if !self.playing {
+            self.release_all(context, 0);
+            return;
+        }
+
+        if self.programs_pending {
+            self.programs_pending = false;
+            for (channel, program) in self.routing.program_changes() {
+                context.send_event(NoteEvent::MidiProgramChange {
+                    timing: start_sample as u32,
+                    channel,
+                    program,
+                });
+            }
+        }
+
+        self.play_until(
+            context,
+            start_sample,
+            block_len,
+            beats_per_sample,
+            transform,
+        );
+    }
+
+    /// Plays the clip between two samples of the block, looping it if needed
+    fn play_until(
+        &mut self,
+        context: &mut impl Host,
+        start_sample: usize,
+        end_sample: usize,
+        beats_per_sample: f64,
+        transform: &Transform,
+    ) {
+        let mut sample = start_sample;
+        while sample < end_sample {
+            let remaining = end_sample - sample;
+            let segment_end = self.position + remaining as f64 * beats_per_sample;
+            let end = match self.loop_length {
+                Some(loop_length) => segment_end.min(loop_length),
+                None => segment_end,
+            };
+
+            self.play_segment(
+                context,
+                sample,
+                end,
+                end_sample,
+                beats_per_sample,
+                transform,
+            );
+            sample += ((end - self.position) / beats_per_sample).round() as usize;
+            self.position = end;
+
+            match self.loop_length {
+                Some(loop_length) if self.position >= loop_length => {
+                    // The held notes keep their length across the loop point
+                    self.position -= loop_length;
+                    self.next_event = 0;
+                    for active in self.active.iter_mut() {
+                        active.end -= loop_length;
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        if self.loop_length.is_none()
+            && self.next_event >= self.events.len()
+            && self.active.is_empty()
+        {
+            self.playing = false;
+        }
+    }
+
+    /// Sends the events between the current position and `end`, the current position matching
+    /// the `start_sample` of the block. No event is sent at or after `end_sample`.
+    fn play_segment(
+        &mut self,
+        context: &mut impl Host,
+        start_sample: usize,
+        end: f64,
+        end_sample: usize,
+        beats_per_sample: f64,
+        transform: &Transform,
+    ) {
+        let position = self.position;
+        let timing = |beats: f64| {
+            (start_sample as u32 + ((beats - position) / beats_per_sample).max(0.0).round() as u32)
+                .min(end_sample.saturating_sub(1) as u32)
+        };
+
+        loop {
+            let next_off = self
+                .active
+                .iter()
+                .enumerate()
+                .filter(|(_, active)| active.end < end)
+                .min_by(|(_, a), (_, b)| a.end.total_cmp(&b.end))
+                .map(|(index, active)| (index, *active));
+            let next_on = self
+                .events
+                .get(self.next_event)
+                .filter(|event| event.time < end)
+                .copied();
+
+            match (next_off, next_on) {
+                // Notes are released before the ones starting at the same time
+                (Some((index, active)), next_on)
+                    if next_on.is_none_or(|event| active.end <= event.time) =>
+                {
+                    self.active.swap_remove(index);
+                    context.send_event(NoteEvent::NoteOff {
+                        timing: timing(active.end),
+                        voice_id: None,
+                        channel: active.channel,
+                        note: active.note,
+                        velocity: 0.0,
+                    });
+                }
+                (_, Some(event)) => {
+                    self.next_event += 1;
+                    let muted = self
+                        .audible
+                        .get(event.track as usize)
+                        .is_some_and(|audible| !audible);
+                    if muted {
+                        continue;
+                    }
+                    if self.active.len() >= MAX_ACTIVE_NOTES {
+                        self.dropped += 1;
+                        continue;
+                    }
+
+                    let note = transform.apply_event(&event);
+                    let channel = self.routing.channel(&event);
+                    context.send_event(NoteEvent::NoteOn {
+                        timing: timing(event.time),
+                        voice_id: None,
+                        channel,
+                        note,
+                        velocity: event.velocity as f32 / 127.0,
+                    });
+                    self.active.push(ActiveNote {
+                        channel,
+                        note,
+                        end: event.time + event.duration,
+                    });
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn release_all(&mut self, context: &mut impl Host, timing: u32) {
+        for active in self.active.drain(..) {
+            context.send_event(NoteEvent::NoteOff {
+                timing,
+                voice_id: None,
+                channel: active.channel,
+                note: active.note,
+                velocity: 0.0,
+            });
+        }
+    }
+}
+
+fn clip_end(events: &[MusicEvent]) -> f64 {
+    events
+        .iter()
+        .map(|event| event.time + event.duration)
+        .fold(0.0, f64::max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::{Mode, Note};
+    use crate::host::HostTransport;
+    use crate::transform::TransposeMode;
+
+    /// 100 samples per beat
+    const BEATS_PER_SAMPLE: f64 = 0.01;
+    const BLOCK_LEN: usize = 64;
+
+    /// Records the notes sent as `(sample, on, note)`, counted from the first block
+    #[derive(Default)]
+    struct Recorder {
+        block_start: usize,
+        notes: Vec<(usize, bool, u8)>,
+    }
+
+    impl Host for Recorder {
+        fn transport(&self) -> HostTransport {
+            HostTransport::default()
+        }
+
+        fn next_event(&mut self) -> Option<NoteEvent<()>> {
+            None
+        }
+
+        fn send_event(&mut self, event: NoteEvent<()>) {
+            match event {
+                NoteEvent::NoteOn { timing, note, .. } => {
+                    self.notes
+                        .push((self.block_start + timing as usize, true, note))
+                }
+                NoteEvent::NoteOff { timing, note, .. } => {
+                    self.notes
+                        .push((self.block_start + timing as usize, false, note))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn transport(pos: f64) -> TransportInfo {
+        TransportInfo {
+            playing: true,
+            pos_beats: Some(pos),
+            bar_start_pos_beats: Some((pos / 4.0).floor() * 4.0),
+            bar_number: Some((pos / 4.0).floor() as i32),
+            bar_length: 4.0,
+        }
+    }
+
+    fn note(note: u8, time: f64, duration: f64) -> MusicEvent {
+        MusicEvent {
+            channel: 0,
+            duration,
+            note,
+            time,
+            track: 0,
+            velocity: 100,
+        }
+    }
+
+    /// Processes `blocks` blocks with the host playing from `start`, calling `between` before
+    /// each block with its index
+    fn play(
+        player: &mut PreviewPlayer,
+        start: f64,
+        blocks: usize,
+        mut between: impl FnMut(&mut PreviewPlayer, usize),
+    ) -> Vec<(usize, bool, u8)> {
+        let transform = Transform {
+            mode: TransposeMode::Off,
+            from_root: Note::C,
+            from_mode: Mode::Major,
+            to_root: Note::C,
+            to_mode: Mode::Major,
+            octaves: 0,
+            ranges: [(0, 127); MAX_PARTS],
+        };
+        let mut recorder = Recorder::default();
+        for block in 0..blocks {
+            between(player, block);
+            recorder.block_start = block * BLOCK_LEN;
+            let pos = start + (block * BLOCK_LEN) as f64 * BEATS_PER_SAMPLE;
+            player.process(
+                &mut recorder,
+                BLOCK_LEN,
+                BEATS_PER_SAMPLE,
+                &transport(pos),
+                &transform,
+            );
+        }
+        recorder.notes
+    }
+
+    #[test]
+    fn bars_follow_the_time_signature() {
+        assert_eq!(bar_length(4, 4), 4.0);
+        assert_eq!(bar_length(6, 8), 3.0);
+        assert_eq!(bar_length(7, 0), 7.0);
+        assert_eq!(bar_length(0, 4), 1.0);
+    }
+
+    #[test]
+    fn bars_are_counted_without_the_host() {
+        let transport = TransportInfo {
+            bar_number: None,
+            bar_length: 3.0,
+            ..transport(10.0)
+        };
+        assert_eq!(bar_index(&transport), Some(3));
+        assert_eq!(
+            bar_index(&TransportInfo {
+                bar_number: Some(7),
+                ..transport
+            }),
+            Some(7)
+        );
+        assert_eq!(
+            bar_index(&TransportInfo {
+                pos_beats: None,
+                ..transport
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn launches_follow_the_bar_grid() {
+        let launch = |launch, bars, pos| launch_position(launch, bars, &transport(pos));
+        assert_eq!(launch(LaunchQuantize::Immediately, 1, 5.5), 5.5);
+        assert_eq!(launch(LaunchQuantize::NextBeat, 1, 5.5), 6.0);
+        assert_eq!(launch(LaunchQuantize::NextBeat, 1, 6.0), 6.0);
+        assert_eq!(launch(LaunchQuantize::NextBar, 1, 5.5), 8.0);
+        // Bar 1 is playing, so the next multiple of 4 bars is bar 4
+        assert_eq!(launch(LaunchQuantize::NextBars, 4, 5.5), 16.0);
+        assert_eq!(launch(LaunchQuantize::NextBars, 2, 8.0), 8.0);
+        assert_eq!(launch(LaunchQuantize::NextBars, 0, 8.5), 12.0);
+
+        // Without the bar from the host, the bars are counted from the start in 3/4
+        let waltz = TransportInfo {
+            bar_start_pos_beats: None,
+            bar_number: None,
+            bar_length: 3.0,
+            ..transport(10.0)
+        };
+        assert_eq!(launch_position(LaunchQuantize::NextBar, 1, &waltz), 12.0);
+        assert_eq!(launch_position(LaunchQuantize::NextBars, 2, &waltz), 12.0);
+        assert_eq!(launch_position(LaunchQuantize::NextBars, 3, &waltz), 18.0);
+        assert_eq!(
+            launch_position(
+                LaunchQuantize::NextBar,
+                1,
+                &TransportInfo {
+                    pos_beats: None,
+                    ..waltz
+                }
+            ),
+            0.0
+        );
+    }
+
+    #[test]
+    fn scheduled_previews_start_on_the_launch() {
+        let mut player = PreviewPlayer::new();
+        player.load(vec![note(60, 0.0, 1.0), note(64, 0.5, 0.25)]);
+        player.schedule(4.0);
+        assert!(player.is_pending());
+
+        // The host starts 2.5 beats before the launch
+        let notes = play(&mut player, 1.5, 8, |_, _| {});
+        assert_eq!(
+            notes,
+            [
+                (250, true, 60),
+                (300, true, 64),
+                (325, false, 64),
+                (350, false, 60)
+            ]
+        );
+        assert!(!player.is_playing());
+        assert!(!player.is_pending());
+    }
+
+    #[test]
+    fn queued_clips_start_when_the_current_one_is_done() {
+        let mut player = PreviewPlayer::new();
+        player.load(vec![note(60, 0.0, 4.0)]);
+        player.set_looping(true, 4.0);
+        player.start();
+
+        let notes = play(&mut player, 0.0, 12, |player, block| {
+            if block == 2 {
+                player.queue(vec![note(70, 0.0, 1.0)], 4.0);
+            }
+        });
+        assert_eq!(
+            notes,
+            [
+                (0, true, 60),
+                (400, false, 60),
+                (400, true, 70),
+                (500, false, 70)
+            ]
+        );
+        // The first clip is handed back to be freed out of the audio thread
+        assert_eq!(
+            player.take_released().map(|events| events[0].note),
+            Some(60)
+        );
+        assert!(player.take_released().is_none());
+    }
+
+    #[test]
+    fn replaced_clips_keep_playing_from_the_same_position() {
+        let mut player = PreviewPlayer::new();
+        player.load(vec![note(60, 0.0, 0.5), note(62, 1.0, 0.5)]);
+        player.start();
+
+        let notes = play(&mut player, 0.0, 4, |player, block| {
+            if block == 1 {
+                // At 0.64 beat, so only the notes after it are played
+                player.replace(vec![note(65, 0.5, 0.5), note(67, 1.5, 0.5)]);
+            }
+        });
+        assert_eq!(
+            notes,
+            [
+                (0, true, 60),
+                (50, false, 60),
+                (150, true, 67),
+                (200, false, 67)
+            ]
+        );
+        assert!(!player.is_playing());
+    }
+}
@@ -0,0 +1,94 @@
+use crate::editor::{Mode, Note, Style};
+use crate::groove::GrooveSettings;
+use crate::quantize::{self, QuantizeSettings};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EventGroup {
+    pub events: Vec<MusicEvent>,
+    pub time: f64,
+}
+
+/// A single note of the preview. `time` and `duration` are expressed in beats from the start of
+/// the generated clip.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct MusicEvent {
+    pub channel: u8,
+    pub duration: f64,
+    pub note: u8,
+    pub time: f64,
+    pub track: u8,
+    pub velocity: u8,
+}
+
+/// A generation returned by the API, along with the settings it has been requested with
+#[derive(Clone, Debug)]
+pub struct Generation {
+    pub download_link: String,
+    /// Style of every part, the events of a part having its index as their `track`
+    pub parts: Vec<Style>,
+    pub root: Note,
+    pub mode: Mode,
+    pub bpm: f64,
+    pub time_sig_numerator: i32,
+    pub time_sig_denominator: i32,
+    /// Seed sent with the request, the server giving the same generation for the same seed and
+    /// settings
+    pub seed: u64,
+    /// Seed of the local humanization, drawn for each generation
+    pub humanize_seed: u64,
+    /// All the events of the preview, sorted by time
+    pub events: Vec<MusicEvent>,
+}
+
+impl Generation {
+    /// Settings the generation has been requested with, written in the exported files
+    pub fn describe(&self) -> String {
+        let styles: Vec<String> = self.parts.iter().map(|style| style.to_string()).collect();
+        format!(
+            "Harmonia generation - style: {}; key: {} {}; bpm: {}; time signature: {}/{}; seed: {:016x}; humanize seed: {:016x}",
+            styles.join(", "),
+            self.root,
+            self.mode,
+            self.bpm,
+            self.time_sig_numerator,
+            self.time_sig_denominator,
+            self.seed,
+            self.humanize_seed
+        )
+    }
+}
+
+/// Flattens the event groups of the preview into a single list sorted by time
+pub fn flatten_groups(groups: Vec<EventGroup>) -> Vec<MusicEvent> {
+    let mut events: Vec<MusicEvent> = groups.into_iter().flat_map(|group| group.events).collect();
+    events.sort_by(|a, b| a.time.total_cmp(&b.time));
+    events
+}
+
+/// Local processing applied to the preview before it is played or saved. The generation itself
+/// is never modified, so the original preview can always be recovered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Processing {
+    /// Plays and saves the preview as it has been generated
+    pub bypass: bool,
+    pub quantize: QuantizeSettings,
+    pub groove: GrooveSettings,
+}
+
+impl Processing {
+    pub fn apply(&self, events: &[MusicEvent]) -> Vec<MusicEvent> {
+        let mut processed = events.to_vec();
+        if self.bypass {
+            return processed;
+        }
+
+        self.quantize.apply(&mut processed);
+        self.groove.apply(&mut processed);
+        processed.sort_by(|a, b| a.time.total_cmp(&b.time));
+        if self.quantize.remove_overlaps {
+            quantize::remove_overlaps(&mut processed);
+        }
+        processed
+    }
+}
@@ -0,0 +1,348 @@
+use crate::preview::MusicEvent;
+use nih_plug::prelude::Enum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridDivision {
+    Quarter,
+    Eighth,
+    Sixteenth,
+    ThirtySecond,
+}
+
+impl Enum for GridDivision {
+    fn variants() -> &'static [&'static str] {
+        &["1/4", "1/8", "1/16", "1/32"]
+    }
+
+    fn to_index(self) -> usize {
+        match self {
+            GridDivision::Quarter => 0,
+            GridDivision::Eighth => 1,
+            GridDivision::Sixteenth => 2,
+            GridDivision::ThirtySecond => 3,
+        }
+    }
+
+    fn from_index(index: usize) -> Self {
+        match index {
+            0 => GridDivision::Quarter,
+            1 => GridDivision::Eighth,
+            3 => GridDivision::ThirtySecond,
+            _ => GridDivision::Sixteenth,
+        }
+    }
+
+    fn ids() -> Option<&'static [&'static str]> {
+        None
+    }
+}
+
+impl GridDivision {
+    pub const ALL: [GridDivision; 4] = [
+        GridDivision::Quarter,
+        GridDivision::Eighth,
+        GridDivision::Sixteenth,
+        GridDivision::ThirtySecond,
+    ];
+}
+
+impl std::fmt::Display for GridDivision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(Self::variants()[self.to_index()])
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GridFeel {
+    Straight,
+    Triplet,
+    Dotted,
+}
+
+impl Enum for GridFeel {
+    fn variants() -> &'static [&'static str] {
+        &["Straight", "Triplet", "Dotted"]
+    }
+
+    fn to_index(self) -> usize {
+        match self {
+            GridFeel::Straight => 0,
+            GridFeel::Triplet => 1,
+            GridFeel::Dotted => 2,
+        }
+    }
+
+    fn from_index(index: usize) -> Self {
+        match index {
+            1 => GridFeel::Triplet,
+            2 => GridFeel::Dotted,
+            _ => GridFeel::Straight,
+        }
+    }
+
+    fn ids() -> Option<&'static [&'static str]> {
+        None
+    }
+}
+
+impl GridFeel {
+    pub const ALL: [GridFeel; 3] = [GridFeel::Straight, GridFeel::Triplet, GridFeel::Dotted];
+}
+
+impl std::fmt::Display for GridFeel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(Self::variants()[self.to_index()])
+    }
+}
+
+/// Length of a grid step, in beats
+pub fn grid_beats(division: GridDivision, feel: GridFeel) -> f64 {
+    let straight = match division {
+        GridDivision::Quarter => 1.0,
+        GridDivision::Eighth => 0.5,
+        GridDivision::Sixteenth => 0.25,
+        GridDivision::ThirtySecond => 0.125,
+    };
+
+    match feel {
+        GridFeel::Straight => straight,
+        GridFeel::Triplet => straight * 2.0 / 3.0,
+        GridFeel::Dotted => straight * 1.5,
+    }
+}
+
+/// Everything needed to quantize a generation, built from the plugin parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantizeSettings {
+    /// Length of a grid step, in beats
+    pub grid: f64,
+    /// Between 0 (nothing moves) and 1 (the notes land on the grid)
+    pub strength: f32,
+    /// Only the notes closer to the grid than this fraction of half a step are moved
+    pub range: f32,
+    pub quantize_lengths: bool,
+    /// Extends every note until the next one on the same track and channel
+    pub legato: bool,
+    /// Multiplies the length of the notes, below 1 for staccato
+    pub length_scale: f32,
+    pub remove_overlaps: bool,
+}
+
+impl QuantizeSettings {
+    /// Moves the notes to the grid and processes their lengths. The events need to be sorted by
+    /// time.
+    pub fn apply(&self, events: &mut [MusicEvent]) {
+        let strength = self.strength as f64;
+        for event in events.iter_mut() {
+            let target = (event.time / self.grid).round() * self.grid;
+            if (target - event.time).abs() <= self.range as f64 * self.grid / 2.0 {
+                event.time += (target - event.time) * strength;
+            }
+
+            if self.quantize_lengths {
+                let target = (event.duration / self.grid).round().max(1.0) * self.grid;
+                event.duration += (target - event.duration) * strength;
+            }
+        }
+
+        if self.legato {
+            for i in 0..events.len() {
+                let next_start = events[i + 1..]
+                    .iter()
+                    .find(|next| {
+                        next.track == events[i].track
+                            && next.channel == events[i].channel
+                            && next.time > events[i].time
+                    })
+                    .map(|next| next.time);
+                if let Some(next_start) = next_start {
+                    events[i].duration = next_start - events[i].time;
+                }
+            }
+        }
+
+        for event in events.iter_mut() {
+            event.duration *= self.length_scale as f64;
+        }
+    }
+}
+
+/// Shortens the notes overlapping the next occurrence of the same note, and removes the notes
+/// starting at the same time as another one, keeping the loudest. The events need to be sorted
+/// by time.
+pub fn remove_overlaps(events: &mut Vec<MusicEvent>) {
+    let same_note = |a: &MusicEvent, b: &MusicEvent| {
+        (a.track, a.channel, a.note) == (b.track, b.channel, b.note)
+    };
+
+    let mut kept: Vec<MusicEvent> = Vec::with_capacity(events.len());
+    for event in events.drain(..) {
+        if let Some(duplicate) = kept
+            .iter_mut()
+            .rev()
+            .take_while(|other| other.time >= event.time)
+            .find(|other| same_note(other, &event))
+        {
+            duplicate.velocity = duplicate.velocity.max(event.velocity);
+            duplicate.duration = duplicate.duration.max(event.duration);
+            continue;
+        }
+
+        if let Some(previous) = kept.iter_mut().rev().find(|other| same_note(other, &event)) {
+            if previous.time + previous.duration > event.time {
+                previous.duration = event.time - previous.time;
+            }
+        }
+        kept.push(event);
+    }
+
+    *events = kept;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(note: u8, time: f64, duration: f64) -> MusicEvent {
+        MusicEvent {
+            channel: 0,
+            duration,
+            note,
+            time,
+            track: 0,
+            velocity: 100,
+        }
+    }
+
+    fn settings() -> QuantizeSettings {
+        QuantizeSettings {
+            grid: 0.25,
+            strength: 1.0,
+            range: 1.0,
+            quantize_lengths: false,
+            legato: false,
+            length_scale: 1.0,
+            remove_overlaps: false,
+        }
+    }
+
+    fn timing(events: &[MusicEvent]) -> Vec<(f64, f64)> {
+        events
+            .iter()
+            .map(|event| (event.time, event.duration))
+            .collect()
+    }
+
+    #[test]
+    fn grid_steps_follow_the_feel() {
+        assert_eq!(grid_beats(GridDivision::Quarter, GridFeel::Straight), 1.0);
+        assert_eq!(
+            grid_beats(GridDivision::Eighth, GridFeel::Triplet),
+            1.0 / 3.0
+        );
+        assert_eq!(grid_beats(GridDivision::Sixteenth, GridFeel::Dotted), 0.375);
+        assert_eq!(
+            grid_beats(GridDivision::ThirtySecond, GridFeel::Straight),
+            0.125
+        );
+    }
+
+    #[test]
+    fn notes_are_moved_by_the_strength_within_the_range() {
+        let notes = [
+            event(60, 0.0625, 0.5),
+            event(62, 0.375, 0.5),
+            event(64, 0.625, 0.5),
+        ];
+
+        let mut events = notes;
+        settings().apply(&mut events);
+        assert_eq!(timing(&events), [(0.0, 0.5), (0.5, 0.5), (0.75, 0.5)]);
+
+        let mut events = notes;
+        QuantizeSettings {
+            strength: 0.5,
+            ..settings()
+        }
+        .apply(&mut events);
+        assert_eq!(
+            timing(&events),
+            [(0.03125, 0.5), (0.4375, 0.5), (0.6875, 0.5)]
+        );
+
+        // Only the first note is within a quarter of a step from the grid
+        let mut events = notes;
+        QuantizeSettings {
+            range: 0.5,
+            ..settings()
+        }
+        .apply(&mut events);
+        assert_eq!(timing(&events), [(0.0, 0.5), (0.375, 0.5), (0.625, 0.5)]);
+    }
+
+    #[test]
+    fn lengths_are_quantized_to_at_least_a_step() {
+        let mut events = [event(60, 0.0, 0.0625), event(62, 1.0, 0.625)];
+        QuantizeSettings {
+            quantize_lengths: true,
+            length_scale: 0.5,
+            ..settings()
+        }
+        .apply(&mut events);
+        assert_eq!(timing(&events), [(0.0, 0.125), (1.0, 0.375)]);
+    }
+
+    #[test]
+    fn legato_extends_the_notes_to_the_next_one() {
+        let mut events = [
+            event(60, 0.0, 0.25),
+            event(64, 0.0, 0.25),
+            event(62, 1.0, 0.25),
+            MusicEvent {
+                track: 1,
+                ..event(36, 1.5, 0.25)
+            },
+            event(65, 2.0, 0.25),
+        ];
+        QuantizeSettings {
+            legato: true,
+            ..settings()
+        }
+        .apply(&mut events);
+        assert_eq!(
+            timing(&events),
+            [(0.0, 1.0), (0.0, 1.0), (1.0, 1.0), (1.5, 0.25), (2.0, 0.25)]
+        );
+    }
+
+    #[test]
+    fn overlapping_notes_are_removed() {
+        let mut events = vec![
+            event(60, 0.0, 1.0),
+            MusicEvent {
+                velocity: 120,
+                ..event(60, 0.0, 0.5)
+            },
+            event(64, 0.0, 2.0),
+            event(60, 0.5, 1.0),
+            MusicEvent {
+                channel: 1,
+                ..event(64, 1.0, 1.0)
+            },
+        ];
+        remove_overlaps(&mut events);
+        let notes: Vec<_> = events
+            .iter()
+            .map(|event| (event.note, event.time, event.duration, event.velocity))
+            .collect();
+        assert_eq!(
+            notes,
+            [
+                (60, 0.0, 0.5, 120),
+                (64, 0.0, 2.0, 100),
+                (60, 0.5, 1.0, 100),
+                (64, 1.0, 1.0, 100),
+            ]
+        );
+    }
+}
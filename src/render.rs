@@ -0,0 +1,500 @@
+use crate::bus::Event;
+use crate::drums::DRUM_CHANNEL;
+use crate::logging::{log_error, log_info};
+use crate::preview::MusicEvent;
+use nih_plug::prelude::Enum;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+/// Value of the render progress when no render is running
+pub const RENDER_IDLE: u8 = u8::MAX;
+
+/// Peak level of the normalized renders, -1 dBFS
+const NORMALIZED_PEAK: f32 = 0.891;
+/// Gain of a single voice, leaving some headroom when the parts play together
+const VOICE_GAIN: f32 = 0.2;
+/// Samples per FLAC frame
+const FLAC_BLOCK_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+}
+
+impl Enum for AudioFormat {
+    fn variants() -> &'static [&'static str] {
+        &["WAV", "FLAC"]
+    }
+
+    fn to_index(self) -> usize {
+        match self {
+            AudioFormat::Wav => 0,
+            AudioFormat::Flac => 1,
+        }
+    }
+
+    fn from_index(index: usize) -> Self {
+        match index {
+            1 => AudioFormat::Flac,
+            _ => AudioFormat::Wav,
+        }
+    }
+
+    fn ids() -> Option<&'static [&'static str]> {
+        None
+    }
+}
+
+impl AudioFormat {
+    pub const ALL: [AudioFormat; 2] = [AudioFormat::Wav, AudioFormat::Flac];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "wav",
+            AudioFormat::Flac => "flac",
+        }
+    }
+}
+
+impl std::fmt::Display for AudioFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(Self::variants()[self.to_index()])
+    }
+}
+
+/// Everything needed to render a generation, built from the plugin parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenderSettings {
+    pub sample_rate: f32,
+    pub bpm: f64,
+    /// Time left after the last note so the releases can ring, in seconds
+    pub tail_seconds: f32,
+    /// Brings the peak of the render to -1 dBFS
+    pub normalize: bool,
+    pub format: AudioFormat,
+}
+
+/// Renders the events with the internal synth, as mono samples. `progress` is called with the
+/// fraction of the events rendered so far.
+pub fn render(
+    events: &[MusicEvent],
+    settings: &RenderSettings,
+    mut progress: impl FnMut(f32),
+) -> Vec<f32> {
+    let seconds_per_beat = 60.0 / settings.bpm.max(1.0);
+    let sample_rate = settings.sample_rate as f64;
+    let clip_end = events
+        .iter()
+        .map(|event| event.time + event.duration)
+        .fold(0.0, f64::max);
+    let length = ((clip_end * seconds_per_beat + settings.tail_seconds.max(0.0) as f64)
+        * sample_rate)
+        .ceil() as usize;
+
+    let mut samples = vec![0.0f32; length];
+    for (index, event) in events.iter().enumerate() {
+        let start = (event.time * seconds_per_beat * sample_rate).round() as usize;
+        let duration = (event.duration * seconds_per_beat * sample_rate).round() as usize;
+        let velocity = event.velocity as f32 / 127.0;
+        if start < length {
+            let output = &mut samples[start..];
+            if event.channel == DRUM_CHANNEL {
+                drum_voice(
+                    output,
+                    event.note,
+                    velocity,
+                    settings.sample_rate,
+                    index as u32,
+                );
+            } else {
+                melodic_voice(output, event.note, velocity, duration, settings.sample_rate);
+            }
+        }
+        progress((index + 1) as f32 / events.len() as f32);
+    }
+
+    let peak = samples
+        .iter()
+        .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+    if settings.normalize && peak > 0.0 {
+        let gain = NORMALIZED_PEAK / peak;
+        samples.iter_mut().for_each(|sample| *sample *= gain);
+    }
+
+    samples
+}
+
+/// A note of the internal synth, a few harmonics with an ADSR envelope
+fn melodic_voice(output: &mut [f32], note: u8, velocity: f32, duration: usize, sample_rate: f32) {
+    const ATTACK: f32 = 0.005;
+    const DECAY: f32 = 0.2;
+    const SUSTAIN: f32 = 0.6;
+    const RELEASE: f32 = 0.15;
+
+    let frequency = 440.0 * 2f32.powf((note as f32 - 69.0) / 12.0);
+    let attack = (ATTACK * sample_rate).max(1.0);
+    let decay = (DECAY * sample_rate).max(1.0);
+    let release = (RELEASE * sample_rate).max(1.0);
+    let phase_increment = frequency / sample_rate;
+
+    let mut phase = 0.0f32;
+    let mut level = 0.0f32;
+    let voice_len = (duration + release as usize).min(output.len());
+    for (i, sample) in output[..voice_len].iter_mut().enumerate() {
+        let t = i as f32;
+        if i < duration {
+            level = if t < attack {
+                t / attack
+            } else {
+                SUSTAIN + (1.0 - SUSTAIN) * (-(t - attack) / decay).exp()
+            };
+        }
+        let envelope = if i < duration {
+            level
+        } else {
+            level * (1.0 - (i - duration) as f32 / release)
+        };
+
+        let tau = std::f32::consts::TAU;
+        let tone = (tau * phase).sin()
+            + 0.3 * (2.0 * tau * phase).sin()
+            + 0.15 * (3.0 * tau * phase).sin();
+        *sample += tone * envelope * velocity * VOICE_GAIN;
+
+        phase = (phase + phase_increment).fract();
+    }
+}
+
+/// A hit of the internal drum kit, built from pitch sweeps and noise depending on the percussion
+fn drum_voice(output: &mut [f32], note: u8, velocity: f32, sample_rate: f32, seed: u32) {
+    // (start frequency, end frequency, tone level, noise level, decay in seconds)
+    let (start_frequency, end_frequency, tone, noise, decay) = match note {
+        35 | 36 => (150.0, 45.0, 1.0, 0.05, 0.3),
+        37 | 39 => (400.0, 400.0, 0.1, 0.8, 0.08),
+        38 | 40 => (220.0, 180.0, 0.5, 0.7, 0.15),
+        41 | 43 | 45 | 47 | 48 | 50 => {
+            let frequency = 80.0 + (note - 41) as f32 * 15.0;
+            (frequency * 1.5, frequency, 0.9, 0.1, 0.25)
+        }
+        42 | 44 => (8000.0, 8000.0, 0.0, 0.6, 0.05),
+        46 => (8000.0, 8000.0, 0.0, 0.6, 0.3),
+        49 | 52 | 55 | 57 => (6000.0, 6000.0, 0.0, 0.5, 0.9),
+        51 | 53 | 59 => (5000.0, 5000.0, 0.1, 0.35, 0.6),
+        _ => (600.0, 500.0, 0.5, 0.4, 0.12),
+    };
+
+    let voice_len = ((decay * 5.0 * sample_rate) as usize).min(output.len());
+    let mut phase = 0.0f32;
+    let mut random = seed.wrapping_mul(747796405).wrapping_add(2891336453) | 1;
+    for (i, sample) in output[..voice_len].iter_mut().enumerate() {
+        let t = i as f32 / sample_rate;
+        let envelope = (-t / decay).exp();
+        let frequency = end_frequency + (start_frequency - end_frequency) * (-t / 0.03).exp();
+
+        // Xorshift noise, so the renders are reproducible
+        random ^= random << 13;
+        random ^= random >> 17;
+        random ^= random << 5;
+        let white = random as f32 / u32::MAX as f32 * 2.0 - 1.0;
+
+        let value = (std::f32::consts::TAU * phase).sin() * tone + white * noise;
+        *sample += value * envelope * velocity * VOICE_GAIN;
+        phase = (phase + frequency / sample_rate).fract();
+    }
+}
+
+fn to_24_bit(sample: f32) -> i32 {
+    (sample.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32
+}
+
+/// Encodes mono samples as a 24-bit PCM WAV file
+pub fn encode_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    let data_len = samples.len() as u32 * 3;
+    let mut bytes = Vec::with_capacity(44 + data_len as usize);
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(36 + data_len).to_le_bytes());
+    bytes.extend_from_slice(b"WAVE");
+
+    bytes.extend_from_slice(b"fmt ");
+    bytes.extend_from_slice(&16u32.to_le_bytes());
+    bytes.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    bytes.extend_from_slice(&1u16.to_le_bytes()); // Mono
+    bytes.extend_from_slice(&sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sample_rate * 3).to_le_bytes());
+    bytes.extend_from_slice(&3u16.to_le_bytes());
+    bytes.extend_from_slice(&24u16.to_le_bytes());
+
+    bytes.extend_from_slice(b"data");
+    bytes.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        bytes.extend_from_slice(&to_24_bit(*sample).to_le_bytes()[..3]);
+    }
+    bytes
+}
+
+/// Encodes mono samples as a 24-bit FLAC file. The frames are stored verbatim, the file is
+/// lossless and readable everywhere but not compressed.
+pub fn encode_flac(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    let mut bytes = b"fLaC".to_vec();
+
+    // STREAMINFO, the only and so the last metadata block
+    bytes.extend_from_slice(&[0x80, 0x00, 0x00, 34]);
+    bytes.extend_from_slice(&(FLAC_BLOCK_SIZE as u16).to_be_bytes());
+    bytes.extend_from_slice(&(FLAC_BLOCK_SIZE as u16).to_be_bytes());
+    bytes.extend_from_slice(&[0; 6]); // Unknown frame sizes
+    let total_samples = samples.len() as u64;
+    // Sample rate, channels - 1 (0 for mono), bits per sample - 1 and total samples
+    let packed =
+        ((sample_rate as u64 & 0xF_FFFF) << 44) | (23u64 << 36) | (total_samples & 0xF_FFFF_FFFF);
+    bytes.extend_from_slice(&packed.to_be_bytes());
+    bytes.extend_from_slice(&[0; 16]); // MD5 not computed
+
+    for (frame_number, block) in samples.chunks(FLAC_BLOCK_SIZE).enumerate() {
+        let frame_start = bytes.len();
+        // Sync code, fixed block size, block size stored at the end of the header, sample rate
+        // from STREAMINFO, mono, 24 bits per sample
+        bytes.extend_from_slice(&[0xFF, 0xF8, 0x70, 0x0C]);
+        write_utf8_number(&mut bytes, frame_number as u64);
+        bytes.extend_from_slice(&(block.len() as u16 - 1).to_be_bytes());
+        let header_crc = crc8(&bytes[frame_start..]);
+        bytes.push(header_crc);
+
+        // Verbatim subframe
+        bytes.push(0x02);
+        for sample in block {
+            bytes.extend_from_slice(&to_24_bit(*sample).to_be_bytes()[1..]);
+        }
+
+        let frame_crc = crc16(&bytes[frame_start..]);
+        bytes.extend_from_slice(&frame_crc.to_be_bytes());
+    }
+    bytes
+}
+
+/// Frame numbers are coded like UTF-8 characters
+fn write_utf8_number(bytes: &mut Vec<u8>, value: u64) {
+    if value < 0x80 {
+        bytes.push(value as u8);
+        return;
+    }
+
+    let mut continuation = Vec::new();
+    let mut value = value;
+    let mut first_bits = 6;
+    while value >= 1 << first_bits {
+        continuation.push(0x80 | (value & 0x3F) as u8);
+        value >>= 6;
+        first_bits -= 1;
+    }
+    let prefix = !(0xFFu8 >> (continuation.len() + 1));
+    bytes.push(prefix | value as u8);
+    bytes.extend(continuation.iter().rev());
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Renders the events on a separate thread and writes the file. The progress is shared with
+/// the editor, from 0 to 100 and [`RENDER_IDLE`] once done.
+pub fn render_to_file(
+    events: Vec<MusicEvent>,
+    settings: RenderSettings,
+    path: PathBuf,
+    progress: Arc<AtomicU8>,
+    sender: mpsc::Sender<Event>,
+) {
+    progress.store(0, Ordering::Relaxed);
+    thread::spawn(move || {
+        let samples = render(&events, &settings, |fraction| {
+            // The encoding takes the last percent
+            progress.store((fraction * 99.0) as u8, Ordering::Relaxed);
+        });
+
+        let sample_rate = settings.sample_rate.round() as u32;
+        let bytes = match settings.format {
+            AudioFormat::Wav => encode_wav(&samples, sample_rate),
+            AudioFormat::Flac => encode_flac(&samples, sample_rate),
+        };
+        let result = std::fs::write(&path, bytes);
+        progress.store(RENDER_IDLE, Ordering::Relaxed);
+
+        match result {
+            Ok(()) => {
+                log_info!("Audio render saved: {}", path.display());
+                let _ = sender.send(Event::DownloadComplete(path));
+            }
+            Err(err) => {
+                log_error!("Failed to write {}: {}", path.display(), err);
+                sender
+                    .send(Event::DownloadError(err.to_string()))
+                    .unwrap_or_else(|e| log_error!("Failed to send error message: {}", e));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_le(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn u32_le(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn wav_header_describes_the_samples() {
+        let bytes = encode_wav(&[0.0, 1.0, -1.0, 2.0, 0.5], 48_000);
+        assert_eq!(bytes.len(), 44 + 5 * 3);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_le(&bytes, 4), bytes.len() as u32 - 8);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_le(&bytes, 16), 16);
+        assert_eq!(u16_le(&bytes, 20), 1);
+        assert_eq!(u16_le(&bytes, 22), 1);
+        assert_eq!(u32_le(&bytes, 24), 48_000);
+        assert_eq!(u32_le(&bytes, 28), 144_000);
+        assert_eq!(u16_le(&bytes, 32), 3);
+        assert_eq!(u16_le(&bytes, 34), 24);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_le(&bytes, 40), 15);
+        assert_eq!(
+            &bytes[44..],
+            [0, 0, 0, 0xFF, 0xFF, 0x7F, 0x01, 0x00, 0x80, 0xFF, 0xFF, 0x7F, 0x00, 0x00, 0x40]
+        );
+    }
+
+    #[test]
+    fn checksums_match_the_flac_format() {
+        let check = b"123456789";
+        assert_eq!(crc8(check), 0xF4);
+        assert_eq!(crc16(check), 0xFEE8);
+    }
+
+    #[test]
+    fn frame_numbers_are_coded_like_utf8() {
+        for (value, expected) in [
+            (0x00, &[0x00][..]),
+            (0x7F, &[0x7F]),
+            (0x80, &[0xC2, 0x80]),
+            (0x7FF, &[0xDF, 0xBF]),
+            (0x800, &[0xE0, 0xA0, 0x80]),
+            (0x10000, &[0xF0, 0x90, 0x80, 0x80]),
+        ] {
+            let mut bytes = Vec::new();
+            write_utf8_number(&mut bytes, value);
+            assert_eq!(bytes, expected, "{value:#x}");
+        }
+    }
+
+    #[test]
+    fn flac_frames_hold_the_samples() {
+        let samples: Vec<f32> = (0..FLAC_BLOCK_SIZE * 2 + 10)
+            .map(|index| (index as f32 * 0.01).sin())
+            .collect();
+        let bytes = encode_flac(&samples, 44_100);
+        assert_eq!(&bytes[0..4], b"fLaC");
+        // Last metadata block, STREAMINFO of 34 bytes
+        assert_eq!(&bytes[4..8], [0x80, 0, 0, 34]);
+        let info = &bytes[8..42];
+        assert_eq!(&info[0..4], [0x10, 0x00, 0x10, 0x00]);
+        let packed = u64::from_be_bytes(info[10..18].try_into().unwrap());
+        assert_eq!(packed >> 44, 44_100);
+        assert_eq!((packed >> 41) & 0x7, 0);
+        assert_eq!((packed >> 36) & 0x1F, 23);
+        assert_eq!(packed & 0xF_FFFF_FFFF, samples.len() as u64);
+
+        let mut position = 42;
+        for (frame_number, block) in samples.chunks(FLAC_BLOCK_SIZE).enumerate() {
+            let frame = &bytes[position..];
+            assert_eq!(&frame[0..4], [0xFF, 0xF8, 0x70, 0x0C]);
+            assert_eq!(frame[4], frame_number as u8);
+            assert_eq!(
+                u16::from_be_bytes([frame[5], frame[6]]),
+                block.len() as u16 - 1
+            );
+            assert_eq!(crc8(&frame[..7]), frame[7]);
+            assert_eq!(frame[8], 0x02);
+            assert_eq!(&frame[9..12], &to_24_bit(block[0]).to_be_bytes()[1..]);
+
+            // The CRC of a frame followed by its CRC is 0
+            let length = 9 + block.len() * 3 + 2;
+            assert_eq!(crc16(&frame[..length]), 0);
+            position += length;
+        }
+        assert_eq!(position, bytes.len());
+    }
+
+    #[test]
+    fn renders_ring_after_the_last_note_and_are_normalized() {
+        let events = [MusicEvent {
+            channel: 0,
+            duration: 1.0,
+            note: 69,
+            time: 0.5,
+            track: 0,
+            velocity: 40,
+        }];
+        let settings = RenderSettings {
+            sample_rate: 1000.0,
+            bpm: 120.0,
+            tail_seconds: 0.5,
+            normalize: true,
+            format: AudioFormat::Wav,
+        };
+
+        let mut fractions = Vec::new();
+        let samples = render(&events, &settings, |fraction| fractions.push(fraction));
+        assert_eq!(fractions, [1.0]);
+        // 1.5 beat at 120 bpm and the tail
+        assert_eq!(samples.len(), 1250);
+        assert!(samples[..250].iter().all(|sample| *sample == 0.0));
+        let peak = samples
+            .iter()
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!((peak - NORMALIZED_PEAK).abs() < 1e-6);
+
+        let quiet = render(
+            &events,
+            &RenderSettings {
+                normalize: false,
+                ..settings
+            },
+            |_| {},
+        );
+        assert!(quiet.iter().all(|sample| sample.abs() < NORMALIZED_PEAK));
+    }
+}
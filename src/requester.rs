@@ -1,19 +1,18 @@
-use crate::Message as MainMessage;
-use crate::Style;
+use crate::bus::{Event, GenerationRequest};
+use crate::logging::{log_debug, log_error, log_info};
+use crate::preview::EventGroup;
 use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::error::Error;
+use std::io::{Read, Write};
 use std::{fs, path::PathBuf, sync::mpsc, thread};
 
-use std::time::{SystemTime, UNIX_EPOCH};
-use rand::{thread_rng, Rng};
-use rand::distributions::Alphanumeric;
-
 #[derive(Clone)]
 pub struct Requester {
     api_url: String,
-    main_thread_sender: mpsc::Sender<MainMessage>,
+    /// Progress, completion and errors of the downloads, forwarded to the editor by the worker
+    events: mpsc::Sender<Event>,
     client: Client,
 }
 
@@ -22,7 +21,16 @@ struct GenerateRequest {
     bpm: f64,
     duration: u32, // not currently used
     scale: String,
+    /// `major` or `minor`
+    mode: String,
+    /// The same seed gives the same generation, the server picks one when there is none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
     style: String,
+    /// Styles of all the parts of an ensemble, the first one being `style`. The events of each
+    /// part come back with the index of the part as their `track`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    ensemble: Vec<String>,
     #[serde(rename = "timeSignatureNum")]
     time_signature_num: i32,
     #[serde(rename = "timeSignatureDen")]
@@ -36,49 +44,38 @@ pub struct GenerationResponse {
     pub preview: Vec<EventGroup>,
 }
 
-// TODO: maybe move this elsewhere when the events will be handled
-#[derive(Serialize, Deserialize)]
-pub struct EventGroup {
-    pub events: Vec<MusicEvent>,
-    pub time: f64,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct MusicEvent {
-    pub channel: u8,
-    pub duration: f64,
-    pub note: u8,
-    pub time: f64,
-    pub track: u8,
-    pub velocity: u8,
-}
-
 impl Requester {
-    pub fn new(api_url: String, main_thread_sender: mpsc::Sender<MainMessage>) -> Self {
+    pub fn new(api_url: String, events: mpsc::Sender<Event>) -> Self {
         Requester {
             api_url,
-            main_thread_sender,
+            events,
             client: Client::new(),
         }
     }
 
     pub fn generate(
         &self,
-        bpm: f64,
-        style: Option<Style>,
-        scale: String,
-        time_signature_num: i32,
-        time_signature_den: i32,
-    ) -> Result<String, String> {
-        let style = style.ok_or("No style selected")?;
+        request: &GenerationRequest,
+        seed: Option<u64>,
+    ) -> Result<GenerationResponse, String> {
+        let parts = request.parts();
+        let style = parts.first().ok_or("No style selected")?;
+        let ensemble = if parts.len() > 1 {
+            parts.iter().map(|part| part.to_string()).collect()
+        } else {
+            Vec::new()
+        };
 
         let request = GenerateRequest {
-            bpm,
+            bpm: request.bpm,
             duration: 128,
-            scale: scale,
+            scale: request.root.to_string(),
+            mode: request.mode.to_string().to_lowercase(),
+            seed,
             style: style.to_string(),
-            time_signature_num,
-            time_signature_den,
+            ensemble,
+            time_signature_num: request.time_sig_numerator,
+            time_signature_den: request.time_sig_denominator,
         };
 
         let body = serde_json::to_string(&request).map_err(|e| e.to_string())?;
@@ -90,29 +87,51 @@ impl Requester {
             .send()
             .map_err(|e| e.to_string())?;
 
-        println!("generation: {:?}", response);
+        log_debug!("Generation response: {}", response.status());
 
         let parsed: GenerationResponse =
             serde_json::from_reader(response).map_err(|e| e.to_string())?;
 
-        Ok(parsed.download_link)
+        Ok(parsed)
     }
 
-    pub fn download_midi(&self, link: String, download_folder: &PathBuf) {
-        println!(
-            "Downloading {} to {}",
-            link,
-            download_folder.to_str().unwrap()
-        );
-        let file_path = download_folder.join(generate_unique_filename());
-        let sender = self.main_thread_sender.clone();
+    pub fn download_midi(&self, link: String, file_path: PathBuf) {
+        let sender = self.events.clone();
 
         let requester_clone = self.clone();
         thread::spawn(move || {
             if let Err(err) = Self::download_file(requester_clone, &link, &file_path, &sender) {
+                log_error!("Download of {} failed: {}", link, err);
                 sender
-                    .send(MainMessage::DownloadError(err.to_string()))
-                    .unwrap_or_else(|e| eprintln!("Failed to send error message: {}", e));
+                    .send(Event::DownloadError(err.to_string()))
+                    .unwrap_or_else(|e| log_error!("Failed to send error message: {}", e));
+            }
+        });
+    }
+
+    /// Writes the files exported from the preview, the MIDI file and the files written
+    /// alongside it
+    pub fn save_files(&self, files: Vec<(PathBuf, Vec<u8>)>) {
+        let sender = self.events.clone();
+
+        thread::spawn(move || {
+            let main_file = files.first().map(|(file_path, _)| file_path.clone());
+            let count = files.len();
+            for (index, (file_path, bytes)) in files.into_iter().enumerate() {
+                if let Err(err) = fs::write(&file_path, bytes) {
+                    log_error!("Failed to write {}: {}", file_path.display(), err);
+                    sender
+                        .send(Event::DownloadError(err.to_string()))
+                        .unwrap_or_else(|e| log_error!("Failed to send error message: {}", e));
+                    return;
+                }
+                log_info!("File saved: {}", file_path.display());
+                let _ = sender.send(Event::DownloadProgress(
+                    ((index + 1) * 100 / count) as u8,
+                ));
+            }
+            if let Some(main_file) = main_file {
+                let _ = sender.send(Event::DownloadComplete(main_file));
             }
         });
     }
@@ -121,10 +140,9 @@ impl Requester {
         self,
         link: &str,
         file_path: &PathBuf,
-        sender: &mpsc::Sender<MainMessage>,
+        sender: &mpsc::Sender<Event>,
     ) -> Result<(), Box<dyn Error>> {
-        println!("lien telechager -> {}", link);
-        println!("Téléchargement commencé vers: {}", file_path.display());
+        log_info!("Downloading {} to {}", link, file_path.display());
 
         // Obtenir la réponse du serveur
         let mut response = self.client.get(link).send()?;
@@ -132,34 +150,33 @@ impl Requester {
         // Créer le fichier
         let mut file = std::fs::File::create(file_path)?;
 
-        // Copier les données directement de la réponse vers le fichier
-        std::io::copy(&mut response, &mut file)?;
+        // Copier les données de la réponse vers le fichier, en donnant l'avancement quand le
+        // serveur donne la taille du fichier
+        let total = response.content_length().filter(|total| *total > 0);
+        let mut buffer = [0u8; 16 * 1024];
+        let mut written = 0u64;
+        let mut last_progress = 0;
+        loop {
+            let len = response.read(&mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            file.write_all(&buffer[..len])?;
+            written += len as u64;
+
+            if let Some(total) = total {
+                let progress = (written * 100 / total).min(100) as u8;
+                if progress != last_progress {
+                    last_progress = progress;
+                    let _ = sender.send(Event::DownloadProgress(progress));
+                }
+            }
+        }
 
-        println!("Téléchargement terminé: {} octets", file.metadata()?.len());
+        log_info!("Download complete: {} bytes", written);
 
-        sender.send(MainMessage::DownloadProgress(255))?;
+        sender.send(Event::DownloadComplete(file_path.clone()))?;
 
         Ok(())
     }
 }
-
-fn generate_unique_filename() -> PathBuf {
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
-
-    let random_string: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(8)
-        .map(char::from)
-        .collect();
-
-    let filename = format!(
-        "{}_{}.mid",
-        timestamp,
-        random_string
-    );
-
-    filename.into()
-}
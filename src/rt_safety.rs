@@ -0,0 +1,407 @@
+//! Real-time safety of the audio thread. The blocks are processed under the allocator nih-plug
+//! installs with `assert_process_allocs`, which counts the allocations and frees made inside
+//! `assert_no_alloc` instead of aborting in the tests. That allocator is only installed with
+//! `debug_assertions`, so every count first checks that allocations are seen at all, and the
+//! tests fail under `cargo test --release` instead of passing with nothing counted.
+//!
+//! nih-plug's `Transport` cannot be built outside of it, so the blocks are driven through
+//! `process_block` with a fake host rather than through `Plugin::process`.
+
+use crate::bus::{self, Command, Job};
+use crate::editor::{Mode, Note, Style};
+use crate::ensemble::MAX_PARTS;
+use crate::groove::GrooveTemplate;
+use crate::host::{Host, HostTransport};
+use crate::notation::NotationFormat;
+use crate::player::{self, TransportInfo};
+use crate::preview::{Generation, MusicEvent};
+use crate::spsc;
+use crate::telemetry::Queue;
+use crate::Harmonia;
+use nih_plug::prelude::*;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Nothing listens on the discard port, so the generations fail right away
+const UNREACHABLE_API: &str = "http://127.0.0.1:9";
+
+const BLOCK_LEN: usize = 512;
+
+struct FakeHost {
+    transport: HostTransport,
+    /// Events of the next block, in reverse order
+    input: Vec<NoteEvent<()>>,
+    /// Allocated once, the events sent past its capacity are dropped
+    output: Vec<NoteEvent<()>>,
+}
+
+impl FakeHost {
+    fn new(playing: bool) -> Self {
+        FakeHost {
+            transport: HostTransport {
+                playing,
+                tempo: Some(120.0),
+                time_sig_numerator: Some(4),
+                time_sig_denominator: Some(4),
+                pos_beats: Some(0.0),
+                bar_start_pos_beats: Some(0.0),
+                bar_number: Some(0),
+            },
+            input: Vec::with_capacity(64),
+            output: Vec::with_capacity(1024),
+        }
+    }
+
+    /// Moves the transport to the next block, at 120 BPM and 44.1 kHz
+    fn advance(&mut self) {
+        if !self.transport.playing {
+            return;
+        }
+        let pos = self.transport.pos_beats.unwrap_or_default() + BLOCK_LEN as f64 / 22050.0;
+        self.transport.pos_beats = Some(pos);
+        self.transport.bar_start_pos_beats = Some((pos / 4.0).floor() * 4.0);
+        self.transport.bar_number = Some((pos / 4.0).floor() as i32);
+    }
+}
+
+impl Host for FakeHost {
+    fn transport(&self) -> HostTransport {
+        self.transport
+    }
+
+    fn next_event(&mut self) -> Option<NoteEvent<()>> {
+        self.input.pop()
+    }
+
+    fn send_event(&mut self, event: NoteEvent<()>) {
+        if self.output.len() < self.output.capacity() {
+            self.output.push(event);
+        }
+    }
+}
+
+fn plugin() -> Harmonia {
+    Harmonia::new(String::from(UNREACHABLE_API))
+}
+
+fn generation(first_note: u8) -> Generation {
+    Generation {
+        download_link: String::new(),
+        parts: vec![Style::AcousticGrand],
+        root: Note::C,
+        mode: Mode::Major,
+        bpm: 120.0,
+        time_sig_numerator: 4,
+        time_sig_denominator: 4,
+        seed: 1,
+        humanize_seed: 1,
+        events: (0..32)
+            .map(|i| MusicEvent {
+                channel: 0,
+                duration: 0.5,
+                note: first_note + (i % 12) as u8,
+                time: i as f64 * 0.25,
+                track: 0,
+                velocity: 100,
+            })
+            .collect(),
+    }
+}
+
+fn groove() -> Option<GrooveTemplate> {
+    GrooveTemplate::from_events(&generation(60).events, 4, 4)
+}
+
+fn transport_info(host: &FakeHost) -> TransportInfo {
+    TransportInfo {
+        playing: host.transport.playing,
+        pos_beats: host.transport.pos_beats,
+        bar_start_pos_beats: host.transport.bar_start_pos_beats,
+        bar_number: host.transport.bar_number,
+        bar_length: player::bar_length(4, 4),
+    }
+}
+
+/// Panics when the allocations made inside `assert_no_alloc` are not counted
+fn check_counting_allocator() {
+    assert_no_alloc::reset_violation_count();
+    assert_no_alloc::assert_no_alloc(|| drop(std::hint::black_box(Box::new(0u64))));
+    assert!(
+        assert_no_alloc::violation_count() > 0,
+        "nih-plug's counting allocator is not installed, run the tests without --release"
+    );
+}
+
+/// Number of allocations and frees made by `f` on this thread
+fn allocations(f: impl FnOnce()) -> u32 {
+    check_counting_allocator();
+    assert_no_alloc::reset_violation_count();
+    assert_no_alloc::assert_no_alloc(f);
+    assert_no_alloc::violation_count()
+}
+
+fn send(plugin: &Harmonia, command: Command) {
+    let pushed = plugin.command_sender.lock().unwrap().push(command).is_ok();
+    assert!(pushed, "the command queue is full");
+}
+
+/// Every command the editor can send
+fn all_commands() -> Vec<Command> {
+    let mut ensemble = [None; MAX_PARTS];
+    ensemble[0] = Some(Style::AcousticGrand);
+    ensemble[1] = Some(Style::AcousticBass);
+
+    vec![
+        Command::SelectStyle(Style::AcousticGrand),
+        Command::SetEnsemble(ensemble),
+        Command::SelectNote(Note::D),
+        Command::SelectMode(Mode::Minor),
+        Command::SetGrooveTemplate(groove()),
+        Command::PlayPreview,
+        Command::Generate,
+        Command::Download,
+        Command::RenderAudio,
+        Command::CopyNotation(NotationFormat::Abc),
+        Command::CopyNotation(NotationFormat::NoteList),
+        Command::SetGrooveTemplate(None),
+        Command::StopPreview,
+    ]
+}
+
+#[test]
+fn allocations_are_counted() {
+    let count = allocations(|| drop(std::hint::black_box(vec![0u8; 64])));
+    assert!(count > 0);
+}
+
+#[test]
+fn commands_do_not_allocate() {
+    let mut plugin = plugin();
+    let mut host = FakeHost::new(true);
+    plugin.process_block(&mut host, BLOCK_LEN);
+
+    for command in all_commands() {
+        send(&plugin, command);
+        host.advance();
+        let count = allocations(|| plugin.process_block(&mut host, BLOCK_LEN));
+        assert_eq!(count, 0, "{:?} allocated on the audio thread", command);
+    }
+}
+
+#[test]
+fn commands_are_drained_in_one_block() {
+    let mut plugin = plugin();
+    let mut host = FakeHost::new(false);
+    for command in all_commands() {
+        send(&plugin, command);
+    }
+
+    let count = allocations(|| plugin.process_block(&mut host, BLOCK_LEN));
+    assert_eq!(count, 0);
+    assert!(plugin.commands.pop().is_none());
+}
+
+#[test]
+fn generations_are_freed_by_the_worker() {
+    let mut plugin = plugin();
+    let mut host = FakeHost::new(true);
+    plugin.process_block(&mut host, BLOCK_LEN);
+    let processing = plugin.processing();
+
+    // The second generation is queued behind the first one, then replaces it
+    for first_note in [48, 60, 72] {
+        let generation = generation(first_note);
+        let events = generation.events.clone();
+        let transport = transport_info(&host);
+        let count = allocations(|| {
+            plugin.receive_generation(generation, events, processing, &transport, true)
+        });
+        assert_eq!(count, 0, "receiving a generation allocated");
+
+        for _ in 0..8 {
+            host.advance();
+            host.output.clear();
+            let count = allocations(|| plugin.process_block(&mut host, BLOCK_LEN));
+            assert_eq!(count, 0, "playing a generation allocated");
+        }
+    }
+
+    // The parameters changed by the editor are processed again by the worker
+    send(&plugin, Command::SetGrooveTemplate(groove()));
+    let count = allocations(|| plugin.process_block(&mut host, BLOCK_LEN));
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn releases_wait_for_room_in_the_job_queue() {
+    let mut plugin = plugin();
+    let mut host = FakeHost::new(false);
+    plugin.process_block(&mut host, BLOCK_LEN);
+    let processing = plugin.processing();
+
+    // The jobs are read by the test instead of the worker, and the queue is left full
+    let (jobs, mut worker_jobs) = spsc::channel(bus::JOB_CAPACITY);
+    plugin.jobs = jobs;
+    while plugin.jobs.push(Job::Process(processing)).is_ok() {}
+
+    for first_note in [48, 60, 72] {
+        let generation = generation(first_note);
+        let events = generation.events.clone();
+        let transport = transport_info(&host);
+        let count = allocations(|| {
+            plugin.receive_generation(generation, events, processing, &transport, false);
+            plugin.process_block(&mut host, BLOCK_LEN);
+        });
+        assert_eq!(count, 0, "a generation was freed on the audio thread");
+    }
+
+    // Once the worker catches up, the generations and the clips replaced are sent to it
+    assert_eq!(worker_jobs.drain().count(), bus::JOB_CAPACITY);
+    plugin.process_block(&mut host, BLOCK_LEN);
+    let jobs: Vec<_> = worker_jobs.drain().collect();
+    let generations = jobs
+        .iter()
+        .filter(|job| matches!(job, Job::Release(_)))
+        .count();
+    let clips = jobs
+        .iter()
+        .filter(|job| matches!(job, Job::ReleaseEvents(_)))
+        .count();
+    assert_eq!((generations, clips), (2, 2));
+}
+
+#[test]
+fn incoming_notes_do_not_allocate() {
+    let mut plugin = plugin();
+    let mut host = FakeHost::new(true);
+    plugin.process_block(&mut host, BLOCK_LEN);
+
+    for note in 36..96 {
+        host.input.push(NoteEvent::NoteOff {
+            timing: 10,
+            voice_id: None,
+            channel: 0,
+            note,
+            velocity: 0.0,
+        });
+        host.input.push(NoteEvent::NoteOn {
+            timing: 0,
+            voice_id: None,
+            channel: 0,
+            note,
+            velocity: 0.8,
+        });
+        host.advance();
+        host.output.clear();
+        let count = allocations(|| plugin.process_block(&mut host, BLOCK_LEN));
+        assert_eq!(count, 0, "note {} allocated on the audio thread", note);
+    }
+}
+
+#[test]
+fn process_does_not_wait_for_the_editor() {
+    let mut plugin = plugin();
+    for command in all_commands() {
+        send(&plugin, command);
+    }
+
+    // Every lock the editor and the worker share with the plugin is held during the blocks
+    let command_sender = plugin.command_sender.clone();
+    let editor_events = plugin.editor_events.clone();
+    let drum_grid = plugin.drum_grid.clone();
+    let params = plugin.params.clone();
+    let _command_sender = command_sender.lock().unwrap();
+    let _editor_events = editor_events.lock().unwrap();
+    let _drum_grid = drum_grid.write().unwrap();
+    let _downloads_folder = params.downloads_folder.write().unwrap();
+    let _file_name_template = params.file_name_template.write().unwrap();
+    let _project_folder = params.project_folder.write().unwrap();
+
+    let (done, finished) = mpsc::channel();
+    thread::spawn(move || {
+        let mut host = FakeHost::new(true);
+        for _ in 0..16 {
+            plugin.process_block(&mut host, BLOCK_LEN);
+            host.advance();
+        }
+        let _ = done.send(());
+    });
+
+    finished
+        .recv_timeout(Duration::from_secs(5))
+        .expect("the audio thread waited for a lock held by another thread");
+}
+
+#[test]
+fn telemetry_follows_the_blocks() {
+    let mut plugin = plugin();
+    let mut host = FakeHost::new(true);
+    assert!(plugin.telemetry.snapshot().is_none());
+
+    send(&plugin, Command::SelectNote(Note::D));
+    send(&plugin, Command::SelectMode(Mode::Minor));
+    assert_eq!(plugin.commands.queued(), 2);
+    for _ in 0..4 {
+        host.advance();
+        let count = allocations(|| plugin.process_block(&mut host, BLOCK_LEN));
+        assert_eq!(count, 0);
+    }
+
+    let block = plugin.telemetry.snapshot().expect("no block published");
+    assert!(block.playing);
+    assert_eq!(block.tempo, 120.0);
+    assert_eq!(block.samples, BLOCK_LEN);
+    assert_eq!(block.pos_beats, host.transport.pos_beats);
+    assert_eq!(plugin.telemetry.queued(Queue::Commands), 0);
+    assert!(block.peak_load >= block.load());
+    assert!(block.average_load >= 0.0);
+}
+
+#[test]
+fn sent_midi_events_are_counted() {
+    let mut plugin = plugin();
+    let mut host = FakeHost::new(true);
+    host.input.push(NoteEvent::MidiCC {
+        timing: 0,
+        channel: 0,
+        cc: 1,
+        value: 0.5,
+    });
+    plugin.process_block(&mut host, BLOCK_LEN);
+
+    let block = plugin.telemetry.snapshot().expect("no block published");
+    assert_eq!(block.midi_sent, host.output.len() as u64);
+    assert_eq!(block.midi_sent, 1);
+    assert_eq!(block.midi_dropped, 0);
+}
+
+#[test]
+fn selected_key_is_used_by_the_transform_and_the_generations() {
+    let mut plugin = plugin();
+    let mut host = FakeHost::new(true);
+    plugin.process_block(&mut host, BLOCK_LEN);
+    let generation = generation(60);
+    let events = generation.events.clone();
+    let processing = plugin.processing();
+    let transport = transport_info(&host);
+    plugin.receive_generation(generation, events, processing, &transport, false);
+
+    send(&plugin, Command::SelectNote(Note::E));
+    send(&plugin, Command::SelectMode(Mode::Minor));
+    host.advance();
+    let count = allocations(|| plugin.process_block(&mut host, BLOCK_LEN));
+    assert_eq!(count, 0);
+
+    let transform = plugin.transform();
+    assert_eq!(
+        (transform.from_root, transform.from_mode),
+        (Note::C, Mode::Major)
+    );
+    assert_eq!(
+        (transform.to_root, transform.to_mode),
+        (Note::E, Mode::Minor)
+    );
+    let request = plugin.generation_request();
+    assert_eq!((request.root, request.mode), (Note::E, Mode::Minor));
+}
@@ -0,0 +1,329 @@
+use crate::editor::{Mode, Note};
+use nih_plug::prelude::*;
+
+/// How the incoming notes are snapped to the selected scale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantizeMode {
+    Off,
+    Nearest,
+    Up,
+    Down,
+    Drop,
+}
+
+impl Enum for QuantizeMode {
+    fn variants() -> &'static [&'static str] {
+        &["Off", "Nearest", "Up", "Down", "Drop"]
+    }
+
+    fn to_index(self) -> usize {
+        match self {
+            QuantizeMode::Off => 0,
+            QuantizeMode::Nearest => 1,
+            QuantizeMode::Up => 2,
+            QuantizeMode::Down => 3,
+            QuantizeMode::Drop => 4,
+        }
+    }
+
+    fn from_index(index: usize) -> Self {
+        match index {
+            1 => QuantizeMode::Nearest,
+            2 => QuantizeMode::Up,
+            3 => QuantizeMode::Down,
+            4 => QuantizeMode::Drop,
+            _ => QuantizeMode::Off,
+        }
+    }
+
+    fn ids() -> Option<&'static [&'static str]> {
+        None
+    }
+}
+
+impl QuantizeMode {
+    pub const ALL: [QuantizeMode; 5] = [
+        QuantizeMode::Off,
+        QuantizeMode::Nearest,
+        QuantizeMode::Up,
+        QuantizeMode::Down,
+        QuantizeMode::Drop,
+    ];
+}
+
+impl std::fmt::Display for QuantizeMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(Self::variants()[self.to_index()])
+    }
+}
+
+/// Pitch classes allowed by the scale, only keeping the enabled degrees
+pub fn scale_pitch_classes(root: Note, mode: Mode, enabled_degrees: [bool; 7]) -> [bool; 12] {
+    let mut allowed = [false; 12];
+    for (interval, enabled) in mode.intervals().iter().zip(enabled_degrees) {
+        if enabled {
+            allowed[((root.pitch_class() + interval) % 12) as usize] = true;
+        }
+    }
+    allowed
+}
+
+/// Snaps a single note to the allowed pitch classes. Returns `None` when the note has to be
+/// dropped. If no degree is enabled the note is left untouched.
+pub fn quantize_note(note: u8, allowed: &[bool; 12], mode: QuantizeMode) -> Option<u8> {
+    let is_allowed = |n: i16| (0..=127).contains(&n) && allowed[(n % 12) as usize];
+
+    if mode == QuantizeMode::Off || !allowed.contains(&true) || is_allowed(note as i16) {
+        return Some(note);
+    }
+
+    if mode == QuantizeMode::Drop {
+        return None;
+    }
+
+    let note = note as i16;
+    for distance in 1..12 {
+        let up = note + distance;
+        let down = note - distance;
+        let candidate = match mode {
+            // On a tie we prefer going down, which keeps the bass lines from drifting up
+            QuantizeMode::Nearest if is_allowed(down) => Some(down),
+            QuantizeMode::Nearest if is_allowed(up) => Some(up),
+            QuantizeMode::Up if is_allowed(up) => Some(up),
+            QuantizeMode::Down if is_allowed(down) => Some(down),
+            _ => None,
+        };
+        if let Some(candidate) = candidate {
+            return Some(candidate as u8);
+        }
+    }
+
+    // Nothing was found in the requested direction without leaving the MIDI range
+    None
+}
+
+/// Remembers which note each incoming note has been mapped to, so the note off (and the
+/// polyphonic expressions) go to the same note even if the scale changed in the meantime.
+/// Several held notes can be snapped to the same note, which is only played once and released
+/// with the last of them.
+pub struct ScaleQuantizer {
+    mapped: [[Option<u8>; 128]; 16],
+    /// Number of held incoming notes mapped to each outgoing note
+    held: [[u8; 128]; 16],
+}
+
+impl ScaleQuantizer {
+    pub fn new() -> Self {
+        ScaleQuantizer {
+            mapped: [[None; 128]; 16],
+            held: [[0; 128]; 16],
+        }
+    }
+
+    /// Maps an incoming note, returns the note to play or `None` when there is nothing to send
+    /// because the note is dropped or already played for another held note
+    pub fn note_on(
+        &mut self,
+        channel: u8,
+        note: u8,
+        allowed: &[bool; 12],
+        mode: QuantizeMode,
+    ) -> Option<u8> {
+        let channel = (channel & 15) as usize;
+        let mapped = &mut self.mapped[channel][(note & 127) as usize];
+        // A second note on without a note off keeps playing the first one
+        if mapped.is_some() {
+            return None;
+        }
+
+        let output = quantize_note(note, allowed, mode)?;
+        *mapped = Some(output);
+        let held = &mut self.held[channel][output as usize];
+        *held += 1;
+        (*held == 1).then_some(output)
+    }
+
+    /// Note currently played for a held incoming note
+    pub fn mapped_note(&self, channel: u8, note: u8) -> Option<u8> {
+        self.mapped[(channel & 15) as usize][(note & 127) as usize]
+    }
+
+    /// Releases an incoming note, returns the note to stop once no other held note is mapped to
+    /// it
+    pub fn note_off(&mut self, channel: u8, note: u8) -> Option<u8> {
+        let channel = (channel & 15) as usize;
+        let output = self.mapped[channel][(note & 127) as usize].take()?;
+        let held = &mut self.held[channel][output as usize];
+        *held = held.saturating_sub(1);
+        (*held == 0).then_some(output)
+    }
+
+    pub fn reset(&mut self) {
+        self.mapped = [[None; 128]; 16];
+        self.held = [[0; 128]; 16];
+    }
+}
+
+/// Channel and note of a polyphonic expression, which has to follow the note it was sent for
+pub fn expression_note<S>(event: &mut NoteEvent<S>) -> Option<(u8, &mut u8)> {
+    match event {
+        NoteEvent::PolyPressure { channel, note, .. }
+        | NoteEvent::PolyVolume { channel, note, .. }
+        | NoteEvent::PolyPan { channel, note, .. }
+        | NoteEvent::PolyTuning { channel, note, .. }
+        | NoteEvent::PolyVibrato { channel, note, .. }
+        | NoteEvent::PolyExpression { channel, note, .. }
+        | NoteEvent::PolyBrightness { channel, note, .. } => Some((*channel, note)),
+        _ => None,
+    }
+}
+
+#[derive(Params)]
+pub struct ScaleDegreeParams {
+    #[id = "degree"]
+    pub enabled: BoolParam,
+}
+
+impl ScaleDegreeParams {
+    pub fn new(degree: usize) -> Self {
+        Self {
+            enabled: BoolParam::new(format!("Scale Degree {}", degree), true),
+        }
+    }
+}
+
+pub fn enabled_degrees(degrees: &[ScaleDegreeParams; 7]) -> [bool; 7] {
+    std::array::from_fn(|i| degrees[i].enabled.value())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn c_major() -> [bool; 12] {
+        scale_pitch_classes(Note::C, Mode::Major, [true; 7])
+    }
+
+    /// Allows a single pitch class
+    fn only(pitch_class: usize) -> [bool; 12] {
+        let mut allowed = [false; 12];
+        allowed[pitch_class] = true;
+        allowed
+    }
+
+    #[test]
+    fn scales_only_keep_the_enabled_degrees() {
+        let mut degrees = [true; 7];
+        degrees[1] = false;
+        degrees[6] = false;
+        let allowed = scale_pitch_classes(Note::D, Mode::Minor, degrees);
+        // D minor without E and C
+        let expected = [2, 5, 7, 9, 10];
+        for (pitch_class, allowed) in allowed.iter().enumerate() {
+            assert_eq!(*allowed, expected.contains(&pitch_class));
+        }
+    }
+
+    #[test]
+    fn notes_are_snapped_in_the_requested_direction() {
+        let allowed = c_major();
+        assert_eq!(quantize_note(61, &allowed, QuantizeMode::Nearest), Some(60));
+        assert_eq!(quantize_note(61, &allowed, QuantizeMode::Up), Some(62));
+        assert_eq!(quantize_note(66, &allowed, QuantizeMode::Down), Some(65));
+        assert_eq!(quantize_note(66, &allowed, QuantizeMode::Drop), None);
+        assert_eq!(quantize_note(64, &allowed, QuantizeMode::Drop), Some(64));
+        assert_eq!(quantize_note(61, &allowed, QuantizeMode::Off), Some(61));
+        assert_eq!(quantize_note(61, &[false; 12], QuantizeMode::Up), Some(61));
+    }
+
+    #[test]
+    fn notes_are_not_snapped_out_of_the_midi_range() {
+        let c = only(0);
+        assert_eq!(quantize_note(127, &c, QuantizeMode::Up), None);
+        assert_eq!(quantize_note(127, &c, QuantizeMode::Down), Some(120));
+        assert_eq!(quantize_note(127, &c, QuantizeMode::Nearest), Some(120));
+
+        let b = only(11);
+        assert_eq!(quantize_note(0, &b, QuantizeMode::Down), None);
+        assert_eq!(quantize_note(0, &b, QuantizeMode::Up), Some(11));
+        assert_eq!(quantize_note(0, &b, QuantizeMode::Nearest), Some(11));
+    }
+
+    #[test]
+    fn notes_mapped_to_the_same_note_are_released_with_the_last_one() {
+        let allowed = c_major();
+        let mut quantizer = ScaleQuantizer::new();
+
+        assert_eq!(
+            quantizer.note_on(0, 61, &allowed, QuantizeMode::Up),
+            Some(62)
+        );
+        // D is already played for C#
+        assert_eq!(quantizer.note_on(0, 62, &allowed, QuantizeMode::Up), None);
+        assert_eq!(quantizer.mapped_note(0, 62), Some(62));
+
+        assert_eq!(quantizer.note_off(0, 61), None);
+        assert_eq!(quantizer.note_off(0, 62), Some(62));
+        assert_eq!(quantizer.note_off(0, 62), None);
+
+        // The channels are counted apart
+        assert_eq!(
+            quantizer.note_on(0, 61, &allowed, QuantizeMode::Up),
+            Some(62)
+        );
+        assert_eq!(
+            quantizer.note_on(1, 62, &allowed, QuantizeMode::Up),
+            Some(62)
+        );
+        assert_eq!(quantizer.note_off(1, 62), Some(62));
+        assert_eq!(quantizer.note_off(0, 61), Some(62));
+    }
+
+    #[test]
+    fn a_repeated_note_on_keeps_the_first_note() {
+        let mut quantizer = ScaleQuantizer::new();
+        assert_eq!(
+            quantizer.note_on(0, 61, &c_major(), QuantizeMode::Up),
+            Some(62)
+        );
+        assert_eq!(
+            quantizer.note_on(0, 61, &c_major(), QuantizeMode::Down),
+            None
+        );
+        assert_eq!(quantizer.note_off(0, 61), Some(62));
+    }
+
+    #[test]
+    fn dropped_notes_are_not_released() {
+        let mut quantizer = ScaleQuantizer::new();
+        assert_eq!(
+            quantizer.note_on(0, 61, &c_major(), QuantizeMode::Drop),
+            None
+        );
+        assert_eq!(quantizer.mapped_note(0, 61), None);
+        assert_eq!(quantizer.note_off(0, 61), None);
+    }
+
+    #[test]
+    fn expressions_follow_their_note() {
+        let mut event: NoteEvent<()> = NoteEvent::PolyTuning {
+            timing: 0,
+            voice_id: None,
+            channel: 2,
+            note: 61,
+            tuning: 0.5,
+        };
+        let (channel, note) = expression_note(&mut event).unwrap();
+        assert_eq!((channel, *note), (2, 61));
+        *note = 62;
+        assert!(matches!(event, NoteEvent::PolyTuning { note: 62, .. }));
+
+        let mut event: NoteEvent<()> = NoteEvent::MidiCC {
+            timing: 0,
+            channel: 0,
+            cc: 1,
+            value: 0.5,
+        };
+        assert!(expression_note(&mut event).is_none());
+    }
+}
@@ -0,0 +1,215 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Bounded queue between exactly one producer and one consumer. Pushing and popping never lock
+/// nor allocate, so either side can be the audio thread. The slots are allocated once by
+/// `channel`.
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Number of values popped since the creation of the queue
+    head: AtomicUsize,
+    /// Number of values pushed since the creation of the queue
+    tail: AtomicUsize,
+}
+
+// The slots between `head` and `tail` are only read by the consumer, the others only written by
+// the producer
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn queued(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let head = *self.head.get_mut();
+        let tail = *self.tail.get_mut();
+        for index in head..tail {
+            let slot = &mut self.slots[index % self.slots.len()];
+            // SAFETY: the slots between `head` and `tail` have been written and not read
+            unsafe { slot.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// Writing end of a queue created by `channel`
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Reading end of a queue created by `channel`
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Creates a queue holding at most `capacity` values
+pub fn channel<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    assert!(capacity > 0, "the capacity of a queue cannot be 0");
+
+    let shared = Arc::new(Shared {
+        slots: (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+impl<T> Producer<T> {
+    /// Adds a value at the end of the queue, or gives it back when the queue is full
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let shared = &*self.shared;
+        let tail = shared.tail.load(Ordering::Relaxed);
+        let head = shared.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == shared.slots.len() {
+            return Err(value);
+        }
+
+        let slot = &shared.slots[tail % shared.slots.len()];
+        // SAFETY: the slot is free since the queue is not full, and the consumer does not read
+        // it before `tail` is incremented
+        unsafe { (*slot.get()).write(value) };
+        shared.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Number of values waiting in the queue
+    pub fn queued(&self) -> usize {
+        self.shared.queued()
+    }
+
+    /// Whether the next push would give the value back. Only the consumer frees slots, so a
+    /// queue that is not full has room for at least one more value.
+    pub fn is_full(&self) -> bool {
+        self.shared.queued() == self.shared.slots.len()
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Takes the oldest value of the queue
+    pub fn pop(&mut self) -> Option<T> {
+        let shared = &*self.shared;
+        let head = shared.head.load(Ordering::Relaxed);
+        let tail = shared.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let slot = &shared.slots[head % shared.slots.len()];
+        // SAFETY: the slot has been written by the producer, which does not write it again
+        // before `head` is incremented
+        let value = unsafe { (*slot.get()).assume_init_read() };
+        shared.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Number of values waiting in the queue
+    pub fn queued(&self) -> usize {
+        self.shared.queued()
+    }
+
+    /// Iterator taking the values of the queue until it is empty
+    pub fn drain(&mut self) -> Drain<'_, T> {
+        Drain { consumer: self }
+    }
+}
+
+pub struct Drain<'a, T> {
+    consumer: &'a mut Consumer<T>,
+}
+
+impl<T> Iterator for Drain<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.consumer.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn values_come_out_in_order() {
+        let (mut producer, mut consumer) = channel(3);
+        assert_eq!(consumer.pop(), None);
+        for round in 0..5 {
+            assert_eq!(producer.push(round * 10), Ok(()));
+            assert_eq!(producer.push(round * 10 + 1), Ok(()));
+            assert_eq!(consumer.queued(), 2);
+            assert_eq!(consumer.pop(), Some(round * 10));
+            assert_eq!(consumer.pop(), Some(round * 10 + 1));
+        }
+        assert_eq!(producer.queued(), 0);
+    }
+
+    #[test]
+    fn full_queues_give_the_value_back() {
+        let (mut producer, mut consumer) = channel(2);
+        assert_eq!(producer.push('a'), Ok(()));
+        assert!(!producer.is_full());
+        assert_eq!(producer.push('b'), Ok(()));
+        assert!(producer.is_full());
+        assert_eq!(producer.push('c'), Err('c'));
+        assert_eq!(consumer.drain().collect::<String>(), "ab");
+        assert!(!producer.is_full());
+        assert_eq!(producer.push('d'), Ok(()));
+    }
+
+    #[test]
+    fn queued_values_are_dropped_with_the_queue() {
+        let value = Arc::new(());
+        let (mut producer, mut consumer) = channel(4);
+        for _ in 0..3 {
+            producer.push(value.clone()).unwrap();
+        }
+        drop(consumer.pop());
+        assert_eq!(Arc::strong_count(&value), 3);
+
+        drop(producer);
+        assert_eq!(Arc::strong_count(&value), 3);
+        drop(consumer);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[test]
+    fn values_cross_threads() {
+        const COUNT: usize = 100_000;
+        let (mut producer, mut consumer) = channel(16);
+        let sender = thread::spawn(move || {
+            for mut value in 0..COUNT {
+                while let Err(back) = producer.push(value) {
+                    value = back;
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0;
+        while expected < COUNT {
+            match consumer.pop() {
+                Some(value) => {
+                    assert_eq!(value, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        sender.join().unwrap();
+        assert_eq!(consumer.pop(), None);
+    }
+}
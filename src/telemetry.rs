@@ -0,0 +1,399 @@
+use crate::bus;
+use crate::logging;
+use std::fmt;
+use std::sync::atomic::{
+    fence, AtomicBool, AtomicI32, AtomicU32, AtomicU64, AtomicUsize, Ordering,
+};
+use std::time::Duration;
+
+// State of the audio thread shown in the debug panel. Every value has its own atomic slot, so
+// the audio thread never waits for the editor nor allocates to publish them. The values of a
+// block are published together behind a sequence lock: the editor reads them again when the
+// audio thread wrote them in the meantime, and always sees the values of a single block.
+
+/// Time constant of the average load, in seconds
+const AVERAGE_TIME: f64 = 1.0;
+/// Time the peak load is held before it follows the load of the blocks again, in seconds
+const PEAK_HOLD: f64 = 2.0;
+
+/// Queues between the threads of the plugin, see `bus`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Queue {
+    Commands,
+    Jobs,
+    Replies,
+    Events,
+    Log,
+}
+
+impl Queue {
+    pub const ALL: [Queue; 5] = [
+        Queue::Commands,
+        Queue::Jobs,
+        Queue::Replies,
+        Queue::Events,
+        Queue::Log,
+    ];
+
+    pub fn capacity(self) -> usize {
+        match self {
+            Queue::Commands => bus::COMMAND_CAPACITY,
+            Queue::Jobs => bus::JOB_CAPACITY,
+            Queue::Replies => bus::REPLY_CAPACITY,
+            Queue::Events => bus::EVENT_CAPACITY,
+            Queue::Log => logging::RT_CAPACITY,
+        }
+    }
+}
+
+impl fmt::Display for Queue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Queue::Commands => "commands",
+            Queue::Jobs => "jobs",
+            Queue::Replies => "replies",
+            Queue::Events => "events",
+            Queue::Log => "log",
+        })
+    }
+}
+
+/// Values published by the audio thread at the end of a block
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BlockSnapshot {
+    pub playing: bool,
+    pub tempo: f64,
+    pub samples_per_beat: usize,
+    pub time_sig_numerator: i32,
+    pub time_sig_denominator: i32,
+    /// Position of the host in quarter notes
+    pub pos_beats: Option<f64>,
+    pub bar_number: Option<i32>,
+    pub sample_rate: f32,
+    /// Length of the block in samples
+    pub samples: usize,
+    /// Time spent processing the block
+    pub process_time: Duration,
+    /// Load of the last second, see `load`
+    pub average_load: f32,
+    /// Highest load of the last blocks, held for a couple of seconds
+    pub peak_load: f32,
+    /// MIDI events sent to the host since the plugin was created
+    pub midi_sent: u64,
+    /// Notes of the preview not played since the plugin was created, because too many notes
+    /// were held
+    pub midi_dropped: u64,
+}
+
+impl BlockSnapshot {
+    /// Time the host gives to process the block
+    pub fn budget(&self) -> Duration {
+        if self.sample_rate > 0.0 {
+            Duration::from_secs_f64(self.samples as f64 / self.sample_rate as f64)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// Part of the budget of the block spent processing it, 1 when it took all of it
+    pub fn load(&self) -> f32 {
+        let budget = self.budget();
+        if budget.is_zero() {
+            return 0.0;
+        }
+        (self.process_time.as_secs_f64() / budget.as_secs_f64()) as f32
+    }
+}
+
+/// Rolling average and peak of the load of the blocks, on the audio thread
+pub struct LoadMeter {
+    average: f32,
+    peak: f32,
+    /// Time since the peak was reached, in seconds
+    peak_age: f64,
+}
+
+impl LoadMeter {
+    pub fn new() -> Self {
+        LoadMeter {
+            average: 0.0,
+            peak: 0.0,
+            peak_age: 0.0,
+        }
+    }
+
+    /// Adds the load of a block lasting `duration`
+    pub fn record(&mut self, load: f32, duration: Duration) {
+        let duration = duration.as_secs_f64();
+        let smoothing = 1.0 - (-duration / AVERAGE_TIME).exp();
+        self.average += (load - self.average) * smoothing as f32;
+
+        self.peak_age += duration;
+        if load >= self.peak || self.peak_age >= PEAK_HOLD {
+            self.peak = load;
+            self.peak_age = 0.0;
+        }
+    }
+
+    pub fn average(&self) -> f32 {
+        self.average
+    }
+
+    pub fn peak(&self) -> f32 {
+        self.peak
+    }
+}
+
+/// Shared by the audio thread, the worker and the editor. Only the audio thread publishes the
+/// blocks.
+pub struct Telemetry {
+    /// Incremented before and after the values of a block are written, odd while they are
+    sequence: AtomicU32,
+    playing: AtomicBool,
+    tempo: AtomicU64,
+    samples_per_beat: AtomicUsize,
+    time_sig_numerator: AtomicI32,
+    time_sig_denominator: AtomicI32,
+    /// NaN when the host does not give it
+    pos_beats: AtomicU64,
+    /// `i32::MIN` when the host does not give it
+    bar_number: AtomicI32,
+    sample_rate: AtomicU32,
+    samples: AtomicUsize,
+    process_nanos: AtomicU64,
+    average_load: AtomicU32,
+    peak_load: AtomicU32,
+    midi_sent: AtomicU64,
+    midi_dropped: AtomicU64,
+
+    /// Number of values waiting in each queue, in the order of `Queue::ALL`
+    queues: [AtomicUsize; Queue::ALL.len()],
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Telemetry {
+            sequence: AtomicU32::new(0),
+            playing: AtomicBool::new(false),
+            tempo: AtomicU64::new(0),
+            samples_per_beat: AtomicUsize::new(0),
+            time_sig_numerator: AtomicI32::new(0),
+            time_sig_denominator: AtomicI32::new(0),
+            pos_beats: AtomicU64::new(f64::NAN.to_bits()),
+            bar_number: AtomicI32::new(i32::MIN),
+            sample_rate: AtomicU32::new(0),
+            samples: AtomicUsize::new(0),
+            process_nanos: AtomicU64::new(0),
+            average_load: AtomicU32::new(0),
+            peak_load: AtomicU32::new(0),
+            midi_sent: AtomicU64::new(0),
+            midi_dropped: AtomicU64::new(0),
+            queues: Default::default(),
+        }
+    }
+
+    /// Publishes the values of a block, from the audio thread only
+    pub fn publish(&self, block: &BlockSnapshot) {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        self.playing.store(block.playing, Ordering::Relaxed);
+        self.tempo.store(block.tempo.to_bits(), Ordering::Relaxed);
+        self.samples_per_beat
+            .store(block.samples_per_beat, Ordering::Relaxed);
+        self.time_sig_numerator
+            .store(block.time_sig_numerator, Ordering::Relaxed);
+        self.time_sig_denominator
+            .store(block.time_sig_denominator, Ordering::Relaxed);
+        self.pos_beats.store(
+            block.pos_beats.unwrap_or(f64::NAN).to_bits(),
+            Ordering::Relaxed,
+        );
+        self.bar_number
+            .store(block.bar_number.unwrap_or(i32::MIN), Ordering::Relaxed);
+        self.sample_rate
+            .store(block.sample_rate.to_bits(), Ordering::Relaxed);
+        self.samples.store(block.samples, Ordering::Relaxed);
+        self.process_nanos.store(
+            block.process_time.as_nanos().min(u64::MAX as u128) as u64,
+            Ordering::Relaxed,
+        );
+        self.average_load
+            .store(block.average_load.to_bits(), Ordering::Relaxed);
+        self.peak_load
+            .store(block.peak_load.to_bits(), Ordering::Relaxed);
+        self.midi_sent.store(block.midi_sent, Ordering::Relaxed);
+        self.midi_dropped
+            .store(block.midi_dropped, Ordering::Relaxed);
+
+        self.sequence
+            .store(sequence.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Values of the last block published, `None` before the first one
+    pub fn snapshot(&self) -> Option<BlockSnapshot> {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before == 0 {
+                return None;
+            }
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+
+            let pos_beats = f64::from_bits(self.pos_beats.load(Ordering::Relaxed));
+            let bar_number = self.bar_number.load(Ordering::Relaxed);
+            let block = BlockSnapshot {
+                playing: self.playing.load(Ordering::Relaxed),
+                tempo: f64::from_bits(self.tempo.load(Ordering::Relaxed)),
+                samples_per_beat: self.samples_per_beat.load(Ordering::Relaxed),
+                time_sig_numerator: self.time_sig_numerator.load(Ordering::Relaxed),
+                time_sig_denominator: self.time_sig_denominator.load(Ordering::Relaxed),
+                pos_beats: (!pos_beats.is_nan()).then_some(pos_beats),
+                bar_number: (bar_number != i32::MIN).then_some(bar_number),
+                sample_rate: f32::from_bits(self.sample_rate.load(Ordering::Relaxed)),
+                samples: self.samples.load(Ordering::Relaxed),
+                process_time: Duration::from_nanos(self.process_nanos.load(Ordering::Relaxed)),
+                average_load: f32::from_bits(self.average_load.load(Ordering::Relaxed)),
+                peak_load: f32::from_bits(self.peak_load.load(Ordering::Relaxed)),
+                midi_sent: self.midi_sent.load(Ordering::Relaxed),
+                midi_dropped: self.midi_dropped.load(Ordering::Relaxed),
+            };
+
+            fence(Ordering::Acquire);
+            if self.sequence.load(Ordering::Relaxed) == before {
+                return Some(block);
+            }
+        }
+    }
+
+    /// Records the number of values waiting in a queue, from the thread owning one of its ends
+    pub fn set_queued(&self, queue: Queue, queued: usize) {
+        self.queues[queue as usize].store(queued, Ordering::Relaxed);
+    }
+
+    pub fn queued(&self, queue: Queue) -> usize {
+        self.queues[queue as usize].load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// A block whose values can all be checked against its number
+    fn block(number: u32) -> BlockSnapshot {
+        BlockSnapshot {
+            playing: number % 2 == 1,
+            tempo: number as f64,
+            samples_per_beat: number as usize,
+            time_sig_numerator: number as i32,
+            time_sig_denominator: number as i32,
+            pos_beats: Some(number as f64),
+            bar_number: Some(number as i32),
+            sample_rate: number as f32,
+            samples: number as usize,
+            process_time: Duration::from_nanos(number as u64),
+            average_load: number as f32,
+            peak_load: number as f32,
+            midi_sent: number as u64,
+            midi_dropped: number as u64,
+        }
+    }
+
+    #[test]
+    fn blocks_are_published_whole() {
+        let telemetry = Telemetry::new();
+        assert_eq!(telemetry.snapshot(), None);
+
+        telemetry.publish(&block(7));
+        assert_eq!(telemetry.snapshot(), Some(block(7)));
+
+        let stopped = BlockSnapshot {
+            pos_beats: None,
+            bar_number: None,
+            ..block(8)
+        };
+        telemetry.publish(&stopped);
+        assert_eq!(telemetry.snapshot(), Some(stopped));
+
+        telemetry.set_queued(Queue::Events, 3);
+        assert_eq!(telemetry.queued(Queue::Events), 3);
+        assert_eq!(telemetry.queued(Queue::Jobs), 0);
+    }
+
+    #[test]
+    fn snapshots_never_mix_two_blocks() {
+        let telemetry = Arc::new(Telemetry::new());
+        let done = Arc::new(AtomicBool::new(false));
+        let publisher = {
+            let telemetry = telemetry.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut number = 1;
+                while !done.load(Ordering::Relaxed) {
+                    telemetry.publish(&block(number));
+                    number += 1;
+                }
+            })
+        };
+
+        let mut last = 0;
+        for _ in 0..10_000 {
+            if let Some(snapshot) = telemetry.snapshot() {
+                let number = snapshot.samples as u32;
+                assert_eq!(snapshot, block(number));
+                assert!(number >= last);
+                last = number;
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+        publisher.join().unwrap();
+    }
+
+    #[test]
+    fn load_is_the_part_of_the_budget_used() {
+        let block = BlockSnapshot {
+            sample_rate: 48_000.0,
+            samples: 480,
+            process_time: Duration::from_micros(2500),
+            ..Default::default()
+        };
+        assert_eq!(block.budget(), Duration::from_millis(10));
+        assert!((block.load() - 0.25).abs() < 1e-6);
+
+        let unknown = BlockSnapshot {
+            sample_rate: 0.0,
+            ..block
+        };
+        assert_eq!(unknown.budget(), Duration::ZERO);
+        assert_eq!(unknown.load(), 0.0);
+    }
+
+    #[test]
+    fn the_meter_averages_and_holds_the_peak() {
+        let block = Duration::from_millis(10);
+        let mut meter = LoadMeter::new();
+        meter.record(1.0, Duration::from_secs_f64(AVERAGE_TIME));
+        assert!((meter.average() - (1.0 - (-1.0f32).exp())).abs() < 1e-6);
+
+        let mut meter = LoadMeter::new();
+        meter.record(0.9, block);
+        // Just under the hold time
+        for _ in 0..199 {
+            meter.record(0.2, block);
+        }
+        assert_eq!(meter.peak(), 0.9);
+        assert!((meter.average() - 0.2).abs() < 0.05);
+
+        meter.record(0.2, block);
+        assert_eq!(meter.peak(), 0.2);
+        meter.record(0.3, block);
+        assert_eq!(meter.peak(), 0.3);
+    }
+}
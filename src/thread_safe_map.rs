@@ -1,58 +1,205 @@
 use std::collections::HashMap;
+use std::error::Error;
 use std::fmt;
 use std::hash::Hash;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, RwLock};
+use std::time::Duration;
 
+/// Map shared between threads. Must not be used on the audio thread, see `telemetry` for the
+/// state it shares.
 pub struct ThreadSafeMap<K, V> {
     data: Arc<RwLock<HashMap<K, V>>>,
+    changes: Arc<Changes>,
 }
 
+/// Changes of the entries of a map, counted for the watchers that poll and notified to the ones
+/// that wait
+struct Changes {
+    /// Incremented by every change of the entries, read without taking any lock
+    version: AtomicU64,
+    /// Held while the version is incremented, so a waiting watcher cannot miss a change
+    lock: Mutex<()>,
+    changed: Condvar,
+}
+
+/// A thread panicked while it was changing the map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Poisoned;
+
+impl fmt::Display for Poisoned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Lock poisoned")
+    }
+}
+
+impl Error for Poisoned {}
+
 impl<K, V> ThreadSafeMap<K, V> {
     pub fn new() -> Self {
         ThreadSafeMap {
             data: Arc::new(RwLock::new(HashMap::new())),
+            changes: Arc::new(Changes {
+                version: AtomicU64::new(0),
+                lock: Mutex::new(()),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Number of changes made to the map since its creation. Writing a value equal to the
+    /// current one, or removing a missing key, is not a change.
+    pub fn version(&self) -> u64 {
+        self.changes.version.load(Ordering::Acquire)
+    }
+
+    /// Watches the changes of the map, see `Watcher`
+    pub fn watch(&self) -> Watcher<K, V> {
+        Watcher {
+            map: self.clone(),
+            seen: None,
+        }
+    }
+
+    /// Applies `change` under the write lock. It returns whether the entries changed, the
+    /// version is only incremented and the waiting watchers woken then.
+    fn change<R>(
+        &self,
+        change: impl FnOnce(&mut HashMap<K, V>) -> (R, bool),
+    ) -> Result<R, Poisoned> {
+        let mut guard = self.data.write().map_err(|_| Poisoned)?;
+        let (result, changed) = change(&mut guard);
+        if changed {
+            // Nothing can be left inconsistent behind this lock, so a poisoned one is still used
+            let _lock = self
+                .changes
+                .lock
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            self.changes.version.fetch_add(1, Ordering::Release);
+            self.changes.changed.notify_all();
         }
+        Ok(result)
     }
 
-    pub fn insert(&self, key: K, value: V) -> Result<Option<V>, String>
+    pub fn insert(&self, key: K, value: V) -> Result<Option<V>, Poisoned>
     where
         K: Eq + Hash,
+        V: PartialEq,
     {
-        match self.data.write() {
-            Ok(mut guard) => Ok(guard.insert(key, value)),
-            Err(_) => Err("Lock poisoned".to_string()),
-        }
+        self.change(|data| {
+            let changed = data.get(&key) != Some(&value);
+            (data.insert(key, value), changed)
+        })
     }
 
-    pub fn get(&self, key: &K) -> Result<Option<V>, String>
+    pub fn get(&self, key: &K) -> Result<Option<V>, Poisoned>
     where
         K: Eq + Hash,
         V: Clone,
     {
-        match self.data.read() {
-            Ok(guard) => Ok(guard.get(key).cloned()),
-            Err(_) => Err("Lock poisoned".to_string()),
+        let guard = self.data.read().map_err(|_| Poisoned)?;
+        Ok(guard.get(key).cloned())
+    }
+
+    pub fn remove(&self, key: &K) -> Result<Option<V>, Poisoned>
+    where
+        K: Eq + Hash,
+    {
+        self.change(|data| {
+            let removed = data.remove(key);
+            let changed = removed.is_some();
+            (removed, changed)
+        })
+    }
+
+    /// Changes the value of `key` in place, `None` when there is none. The value is inserted
+    /// when `f` sets it, and removed when `f` takes it.
+    pub fn update<R>(&self, key: K, f: impl FnOnce(&mut Option<V>) -> R) -> Result<R, Poisoned>
+    where
+        K: Eq + Hash,
+        V: Clone + PartialEq,
+    {
+        self.change(|data| {
+            let mut value = data.remove(&key);
+            let before = value.clone();
+            let result = f(&mut value);
+            let changed = value != before;
+            if let Some(value) = value {
+                data.insert(key, value);
+            }
+            (result, changed)
+        })
+    }
+
+    /// Calls `f` on every entry under the read lock, in no particular order
+    pub fn for_each(&self, mut f: impl FnMut(&K, &V)) -> Result<(), Poisoned> {
+        let guard = self.data.read().map_err(|_| Poisoned)?;
+        for (key, value) in guard.iter() {
+            f(key, value);
         }
+        Ok(())
+    }
+
+    /// Copy of the entries, so they can be read without holding the lock
+    pub fn snapshot(&self) -> Result<HashMap<K, V>, Poisoned>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let guard = self.data.read().map_err(|_| Poisoned)?;
+        Ok(guard.clone())
+    }
+}
+
+/// Tells whether a map changed since it was last looked at, see `ThreadSafeMap::watch`. A reader
+/// that can use the change at any time asks with `changed`, for the price of an atomic load, as
+/// the editor does on every frame. A reader with nothing else to do blocks in `wait` until a
+/// change wakes it up.
+pub struct Watcher<K, V> {
+    map: ThreadSafeMap<K, V>,
+    /// Version of the map when it was last looked at
+    seen: Option<u64>,
+}
+
+impl<K, V> Watcher<K, V> {
+    /// Whether the map changed since the previous call, always true on the first one
+    pub fn changed(&mut self) -> bool {
+        let version = self.map.version();
+        let changed = self.seen != Some(version);
+        self.seen = Some(version);
+        changed
+    }
+
+    /// Blocks until the map changes or `timeout` elapses, and returns whether it changed. Like
+    /// `changed`, it returns right away for a change made since the previous call.
+    pub fn wait(&mut self, timeout: Duration) -> bool {
+        let changes = &self.map.changes;
+        let seen = self.seen;
+        let lock = changes.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let waited = changes.changed.wait_timeout_while(lock, timeout, |_| {
+            Some(changes.version.load(Ordering::Acquire)) == seen
+        });
+        drop(waited);
+        self.changed()
     }
 }
 
 impl<K: fmt::Debug, V: fmt::Debug> fmt::Display for ThreadSafeMap<K, V> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self.data.read() {
-            Ok(guard) => {
-                let max_len = guard
-                    .keys()
-                    .map(|k| format!("{:?}", k).len())
-                    .max()
-                    .unwrap_or(0);
-
-                for (key, value) in guard.iter() {
-                    writeln!(f, "{:max_len$}: {:?}", format!("{:?}", key), value)?;
-                }
-                Ok(())
-            }
-            Err(_) => write!(f, "ThreadSafeMap {{ <poisoned> }}"),
+        let mut max_len = 0;
+        let read = self.for_each(|key, _| max_len = max_len.max(format!("{:?}", key).len()));
+        if read.is_err() {
+            return write!(f, "ThreadSafeMap {{ <poisoned> }}");
         }
+
+        let mut result = Ok(());
+        let _ = self.for_each(|key, value| {
+            if result.is_ok() {
+                result = writeln!(f, "{:max_len$}: {:?}", format!("{:?}", key), value);
+            }
+        });
+        result
     }
 }
 
@@ -60,6 +207,75 @@ impl<K, V> Clone for ThreadSafeMap<K, V> {
     fn clone(&self) -> Self {
         ThreadSafeMap {
             data: Arc::clone(&self.data),
+            changes: Arc::clone(&self.changes),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    #[test]
+    fn only_the_changes_are_counted() {
+        let map = ThreadSafeMap::new();
+        let mut watcher = map.watch();
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        assert_eq!(map.insert("tempo", 120), Ok(None));
+        assert_eq!(map.insert("tempo", 120), Ok(Some(120)));
+        assert_eq!(map.version(), 1);
+        assert!(watcher.changed());
+
+        assert_eq!(map.remove(&"key"), Ok(None));
+        assert_eq!(map.update("tempo", |tempo| tempo.is_some()), Ok(true));
+        assert_eq!(map.update("key", |_| ()), Ok(()));
+        assert_eq!(map.version(), 1);
+        assert!(!watcher.changed());
+
+        map.update("tempo", |tempo| *tempo = Some(90)).unwrap();
+        assert_eq!(map.remove(&"tempo"), Ok(Some(90)));
+        assert_eq!(map.version(), 3);
+        assert!(watcher.changed());
+        assert_eq!(map.snapshot(), Ok(HashMap::new()));
+    }
+
+    #[test]
+    fn waiting_watchers_are_woken_by_a_change() {
+        let map = ThreadSafeMap::new();
+        let mut watcher = map.watch();
+        assert!(watcher.wait(Duration::ZERO));
+        assert!(!watcher.wait(Duration::from_millis(10)));
+
+        let subscriber = thread::spawn(move || {
+            let start = Instant::now();
+            (watcher.wait(Duration::from_secs(10)), start.elapsed())
+        });
+        thread::sleep(Duration::from_millis(20));
+        map.insert("tempo", 120).unwrap();
+        let (changed, waited) = subscriber.join().unwrap();
+        assert!(changed);
+        assert!(waited < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn a_panic_while_writing_poisons_the_map() {
+        let map = ThreadSafeMap::new();
+        map.insert(1, String::from("one")).unwrap();
+
+        let writer = map.clone();
+        let _ = std::thread::spawn(move || {
+            writer
+                .update(1, |_| panic!("panicked while writing"))
+                .unwrap();
+        })
+        .join();
+
+        assert_eq!(map.get(&1), Err(Poisoned));
+        assert_eq!(map.insert(2, String::from("two")), Err(Poisoned));
+        assert_eq!(map.to_string(), "ThreadSafeMap { <poisoned> }");
+    }
+}
@@ -0,0 +1,242 @@
+use crate::drums::DRUM_CHANNEL;
+use crate::editor::{Mode, Note};
+use crate::ensemble::MAX_PARTS;
+use crate::preview::MusicEvent;
+use nih_plug::prelude::Enum;
+
+/// How a generated part follows the key selected in the editor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransposeMode {
+    /// The part is played as generated
+    Off,
+    /// Every note is shifted by the interval between the two roots
+    Chromatic,
+    /// Every note keeps its scale degree, so the part also follows a change of mode
+    Diatonic,
+}
+
+impl Enum for TransposeMode {
+    fn variants() -> &'static [&'static str] {
+        &["Off", "Chromatic", "Diatonic"]
+    }
+
+    fn to_index(self) -> usize {
+        match self {
+            TransposeMode::Off => 0,
+            TransposeMode::Chromatic => 1,
+            TransposeMode::Diatonic => 2,
+        }
+    }
+
+    fn from_index(index: usize) -> Self {
+        match index {
+            1 => TransposeMode::Chromatic,
+            2 => TransposeMode::Diatonic,
+            _ => TransposeMode::Off,
+        }
+    }
+
+    fn ids() -> Option<&'static [&'static str]> {
+        None
+    }
+}
+
+impl TransposeMode {
+    pub const ALL: [TransposeMode; 3] = [
+        TransposeMode::Off,
+        TransposeMode::Chromatic,
+        TransposeMode::Diatonic,
+    ];
+}
+
+impl std::fmt::Display for TransposeMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(Self::variants()[self.to_index()])
+    }
+}
+
+/// Moves the notes of a generated part from the key it has been generated in to another key
+#[derive(Debug, Clone, Copy)]
+pub struct Transform {
+    pub mode: TransposeMode,
+    pub from_root: Note,
+    pub from_mode: Mode,
+    pub to_root: Note,
+    pub to_mode: Mode,
+    pub octaves: i32,
+    /// Lowest and highest notes the instrument of every part can play, indexed by the track of
+    /// the events
+    pub ranges: [(u8, u8); MAX_PARTS],
+}
+
+impl Transform {
+    /// Key of the transformed notes
+    pub fn output_key(&self) -> (Note, Mode) {
+        match self.mode {
+            TransposeMode::Off => (self.from_root, self.from_mode),
+            _ => (self.to_root, self.to_mode),
+        }
+    }
+
+    /// Transforms a note of the part played on `track`
+    pub fn apply(&self, note: u8, track: u8) -> u8 {
+        let note = note as i32;
+        let transposed = match self.mode {
+            TransposeMode::Off => return note as u8,
+            TransposeMode::Chromatic => note + self.root_offset(),
+            TransposeMode::Diatonic => self.diatonic(note),
+        } + 12 * self.octaves;
+
+        self.clamp_to_range(transposed, track)
+    }
+
+    /// Transforms the note of an event, the drums are left untouched since their notes select
+    /// the percussion
+    pub fn apply_event(&self, event: &MusicEvent) -> u8 {
+        if event.channel == DRUM_CHANNEL {
+            event.note
+        } else {
+            self.apply(event.note, event.track)
+        }
+    }
+
+    pub fn apply_all(&self, events: &[MusicEvent]) -> Vec<MusicEvent> {
+        events
+            .iter()
+            .map(|event| MusicEvent {
+                note: self.apply_event(event),
+                ..*event
+            })
+            .collect()
+    }
+
+    /// Smallest interval between the two roots, in `-5..=6` semitones
+    fn root_offset(&self) -> i32 {
+        let offset = (self.to_root.pitch_class() as i32 - self.from_root.pitch_class() as i32)
+            .rem_euclid(12);
+        if offset > 6 {
+            offset - 12
+        } else {
+            offset
+        }
+    }
+
+    fn diatonic(&self, note: i32) -> i32 {
+        let from_intervals = self.from_mode.intervals();
+        let to_intervals = self.to_mode.intervals();
+
+        let relative = note - self.from_root.pitch_class() as i32;
+        let octave_start = note - relative.rem_euclid(12);
+        let relative = relative.rem_euclid(12);
+
+        // Notes outside of the scale keep their chromatic offset from the degree below them
+        let degree = from_intervals
+            .iter()
+            .rposition(|interval| *interval as i32 <= relative)
+            .unwrap_or(0);
+        let chromatic = relative - from_intervals[degree] as i32;
+
+        octave_start + self.root_offset() + to_intervals[degree] as i32 + chromatic
+    }
+
+    /// Moves the note by octaves until it fits in the range of the instrument of the part, the
+    /// tracks past the parts keep the whole MIDI range
+    fn clamp_to_range(&self, mut note: i32, track: u8) -> u8 {
+        let (low, high) = self.ranges.get(track as usize).copied().unwrap_or((0, 127));
+        let (low, high) = (low as i32, high as i32);
+        while note < low && note + 12 <= high {
+            note += 12;
+        }
+        while note > high && note - 12 >= low {
+            note -= 12;
+        }
+        note.clamp(0, 127) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(mode: TransposeMode, to_root: Note, to_mode: Mode) -> Transform {
+        Transform {
+            mode,
+            from_root: Note::C,
+            from_mode: Mode::Major,
+            to_root,
+            to_mode,
+            octaves: 0,
+            ranges: [(0, 127); MAX_PARTS],
+        }
+    }
+
+    #[test]
+    fn chromatic_takes_the_shortest_way_to_the_root() {
+        let up = transform(TransposeMode::Chromatic, Note::D, Mode::Major);
+        assert_eq!(up.apply(60, 0), 62);
+        let down = transform(TransposeMode::Chromatic, Note::A, Mode::Minor);
+        assert_eq!(down.apply(60, 0), 57);
+        assert_eq!(down.output_key(), (Note::A, Mode::Minor));
+
+        let off = transform(TransposeMode::Off, Note::A, Mode::Minor);
+        assert_eq!(off.apply(61, 0), 61);
+        assert_eq!(off.output_key(), (Note::C, Mode::Major));
+    }
+
+    #[test]
+    fn diatonic_keeps_the_scale_degrees() {
+        let minor = transform(TransposeMode::Diatonic, Note::C, Mode::Minor);
+        assert_eq!(minor.apply(64, 0), 63);
+        assert_eq!(minor.apply(71, 0), 70);
+        // Out of the scale, C# stays a semitone above the tonic
+        assert_eq!(minor.apply(61, 0), 61);
+
+        let relative = transform(TransposeMode::Diatonic, Note::A, Mode::Minor);
+        // The third of C major becomes the third of A minor
+        assert_eq!(relative.apply(64, 0), 60);
+        assert_eq!(relative.apply(59, 0), 55);
+    }
+
+    #[test]
+    fn notes_stay_in_the_midi_range() {
+        let relative = transform(TransposeMode::Diatonic, Note::A, Mode::Minor);
+        assert_eq!(relative.apply(0, 0), 9);
+
+        let mut up = transform(TransposeMode::Chromatic, Note::FSharp, Mode::Major);
+        up.octaves = 2;
+        assert_eq!(up.apply(127, 0), 121);
+        assert_eq!(up.apply(120, 0), 126);
+
+        let mut down = transform(TransposeMode::Chromatic, Note::C, Mode::Major);
+        down.octaves = -1;
+        assert_eq!(down.apply(5, 0), 5);
+    }
+
+    #[test]
+    fn notes_are_moved_by_octaves_into_the_range_of_their_instrument() {
+        let mut ensemble = transform(TransposeMode::Chromatic, Note::C, Mode::Major);
+        ensemble.ranges[1] = (28, 55);
+        assert_eq!(ensemble.apply(72, 1), 48);
+        assert_eq!(ensemble.apply(12, 1), 36);
+        assert_eq!(ensemble.apply(40, 1), 40);
+        // The other parts are not moved into the range of the bass
+        assert_eq!(ensemble.apply(72, 0), 72);
+        assert_eq!(ensemble.apply(12, 2), 12);
+        assert_eq!(ensemble.apply(72, 9), 72);
+    }
+
+    #[test]
+    fn drums_are_not_transposed() {
+        let up = transform(TransposeMode::Chromatic, Note::D, Mode::Major);
+        let event = |channel| MusicEvent {
+            channel,
+            duration: 1.0,
+            note: 36,
+            time: 0.0,
+            track: 0,
+            velocity: 100,
+        };
+        assert_eq!(up.apply_event(&event(DRUM_CHANNEL)), 36);
+        assert_eq!(up.apply_event(&event(0)), 38);
+    }
+}
@@ -113,7 +113,6 @@ impl<'a> Widget<ParamMessage, Renderer> for ParamKnob<'a> {
         match event {
             Event::Mouse(mouse::Event::ButtonPressed(mouse::Button::Left)) => {
                 if knob_bounds.contains(cursor_position) {
-                    println!("Knob clicked at y={}", cursor_position.y);
                     *self.is_dragging = true;
                     *self.last_y = cursor_position.y;
 
@@ -124,7 +123,6 @@ impl<'a> Widget<ParamMessage, Renderer> for ParamKnob<'a> {
 
             Event::Mouse(mouse::Event::ButtonReleased(mouse::Button::Left)) => {
                 if *self.is_dragging {
-                    println!("Drag released");
                     *self.is_dragging = false;
                     shell.publish(ParamMessage::EndSetParameter(self.param_ptr));
                     return event::Status::Captured;
@@ -144,11 +142,6 @@ impl<'a> Widget<ParamMessage, Renderer> for ParamKnob<'a> {
                     let delta_normalized = delta_y / 100.0;
                     let new_value = (current_value + delta_normalized).clamp(0.0, 1.0);
 
-                    println!(
-                        "Dragging: delta_y={}, current={}, new={}",
-                        delta_y, current_value, new_value
-                    );
-
                     // Publier la nouvelle valeur
                     shell.publish(ParamMessage::SetParameterNormalized(
                         self.param_ptr,
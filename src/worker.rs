@@ -0,0 +1,472 @@
+use crate::bus::{Event, GenerationRequest, Job, Reply};
+use crate::drums::{self, DrumRow};
+use crate::ensemble::{self, Routing};
+use crate::file_naming::{self, NameFields};
+use crate::logging::{self, log_debug, log_error, log_info, log_warn, RtRecord};
+use crate::midi_file::MidiFile;
+use crate::musicxml::{self, ScoreInfo};
+use crate::notation::NotationFormat;
+use crate::preview::{self, Generation, Processing};
+use crate::render::{self, RenderSettings};
+use crate::requester::Requester;
+use crate::spsc;
+use crate::telemetry::{Queue, Telemetry};
+use crate::thread_safe_map::ThreadSafeMap;
+use crate::transform::Transform;
+use crate::HarmoniaParams;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// Longest wait of the worker between two looks at its queues, when nobody wakes it up
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Longest wait for a change of the debug info, so its logger stops soon after the plugin
+const DEBUG_INFO_WAIT: Duration = Duration::from_millis(500);
+
+/// Thread running the work the audio thread cannot do: the requests to the server, the exports
+/// and the notations. It owns the `Requester`, and is the only producer of the events of the
+/// editor.
+pub struct Worker {
+    thread: thread::Thread,
+    running: Arc<AtomicBool>,
+}
+
+/// State shared by the plugin and the editor the worker writes to
+pub struct SharedState {
+    pub drum_grid: Arc<RwLock<Vec<DrumRow>>>,
+    pub download_available: Arc<AtomicBool>,
+    pub render_progress: Arc<AtomicU8>,
+    pub telemetry: Arc<Telemetry>,
+    /// Results of the generations, shown in the debug panel
+    pub debug_info: ThreadSafeMap<String, String>,
+}
+
+impl Worker {
+    pub fn spawn(
+        api_url: String,
+        params: Arc<HarmoniaParams>,
+        shared: SharedState,
+        jobs: spsc::Consumer<Job>,
+        replies: spsc::Producer<Reply>,
+        events: spsc::Producer<Event>,
+        log: spsc::Consumer<RtRecord>,
+    ) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        log_debug_info(shared.debug_info.clone(), running.clone());
+
+        // The download and render threads report to the worker, which forwards to the editor
+        let (sender, receiver) = mpsc::channel();
+        let mut state = WorkerState {
+            requester: Requester::new(api_url, sender.clone()),
+            params,
+            shared,
+            jobs,
+            replies,
+            events,
+            log,
+            sender,
+            receiver,
+            generation: None,
+        };
+
+        let handle = {
+            let running = running.clone();
+            thread::Builder::new()
+                .name(String::from("harmonia-worker"))
+                .spawn(move || {
+                    while running.load(Ordering::Acquire) {
+                        state.run_pending();
+                        thread::park_timeout(POLL_INTERVAL);
+                    }
+                })
+                .expect("failed to spawn the worker thread")
+        };
+
+        Worker {
+            thread: handle.thread().clone(),
+            running,
+        }
+    }
+
+    /// Wakes the worker up after a job has been queued, without waiting for it
+    pub fn wake(&self) {
+        self.thread.unpark();
+    }
+}
+
+impl Drop for Worker {
+    // The thread is not joined, a request in progress would block the host until its timeout
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        self.thread.unpark();
+    }
+}
+
+/// Writes every change of the debug info in the log. The debug panel is only seen with the
+/// editor open, the log keeps the changes for the bug reports.
+fn log_debug_info(debug_info: ThreadSafeMap<String, String>, running: Arc<AtomicBool>) {
+    let mut watcher = debug_info.watch();
+    // Only the changes are logged
+    watcher.changed();
+    let spawned = thread::Builder::new()
+        .name(String::from("harmonia-debug-info"))
+        .spawn(move || {
+            while running.load(Ordering::Acquire) {
+                if watcher.wait(DEBUG_INFO_WAIT) {
+                    log_debug!("Debug info changed:\n{}", debug_info);
+                }
+            }
+        });
+    if let Err(err) = spawned {
+        log_warn!("Failed to spawn the debug info logger: {}", err);
+    }
+}
+
+struct WorkerState {
+    requester: Requester,
+    params: Arc<HarmoniaParams>,
+    shared: SharedState,
+    jobs: spsc::Consumer<Job>,
+    replies: spsc::Producer<Reply>,
+    events: spsc::Producer<Event>,
+    /// Records of the audio thread, written in the log from here
+    log: spsc::Consumer<RtRecord>,
+    /// Given to the download and render threads
+    sender: mpsc::Sender<Event>,
+    receiver: mpsc::Receiver<Event>,
+    /// Copy of the last generation sent to the audio thread, exported from here
+    generation: Option<Generation>,
+}
+
+impl WorkerState {
+    fn run_pending(&mut self) {
+        while let Some(job) = self.jobs.pop() {
+            self.run(job);
+        }
+        while let Ok(event) = self.receiver.try_recv() {
+            self.emit(event);
+        }
+        while let Some(record) = self.log.pop() {
+            logging::log_rt(record);
+        }
+        self.shared
+            .telemetry
+            .set_queued(Queue::Events, self.events.queued());
+    }
+
+    /// Sends an event to the editor, dropped when the editor has been closed for too long
+    fn emit(&mut self, event: Event) {
+        if self.events.push(event).is_err() {
+            log_warn!("The editor event queue is full, an event has been dropped");
+        }
+    }
+
+    fn run(&mut self, job: Job) {
+        match job {
+            Job::Generate {
+                request,
+                processing,
+                at_bar,
+            } => self.generate(request, processing, at_bar),
+            Job::Process(processing) => {
+                if let Some(generation) = &self.generation {
+                    let events = processing.apply(&generation.events);
+                    if self
+                        .replies
+                        .push(Reply::Processed { events, processing })
+                        .is_err()
+                    {
+                        log_warn!("The generation queue is full, the processed events are dropped");
+                    }
+                }
+            }
+            Job::Download {
+                processing,
+                transform,
+                routing,
+            } => {
+                if let Err(err) = self.download(processing, transform, routing) {
+                    log_error!("Download failed: {}", err);
+                    self.emit(Event::DownloadError(err));
+                }
+            }
+            Job::RenderAudio {
+                processing,
+                transform,
+                sample_rate,
+            } => {
+                if let Err(err) = self.render_audio(processing, transform, sample_rate) {
+                    log_error!("Audio render failed: {}", err);
+                    self.emit(Event::DownloadError(err));
+                }
+            }
+            Job::CopyNotation {
+                format,
+                processing,
+                transform,
+            } => {
+                let text = self.notation(format, processing, transform);
+                self.emit(Event::NotationCopied(text));
+            }
+            Job::Release(generation) => drop(generation),
+            Job::ReleaseEvents(events) => drop(events),
+        }
+    }
+
+    /// Requests a new generation from the server and sends it to the audio thread, processed
+    /// with the groove of the new generation
+    fn generate(&mut self, request: GenerationRequest, mut processing: Processing, at_bar: bool) {
+        let parts = request.parts();
+        log_info!(
+            "Generating {:?} in {} {} at {} BPM in {}/{}",
+            parts,
+            request.root,
+            request.mode,
+            request.bpm,
+            request.time_sig_numerator,
+            request.time_sig_denominator
+        );
+
+        // Drawn here rather than by the server, so the seed of the generation can be recorded
+        let seed = rand::random();
+        match self.requester.generate(&request, Some(seed)) {
+            Ok(response) => {
+                let mut events = preview::flatten_groups(response.preview);
+                ensemble::assign_channels(&mut events, &parts);
+                if let Ok(mut drum_grid) = self.shared.drum_grid.write() {
+                    *drum_grid = drums::drum_grid(&events);
+                }
+                let generation = Generation {
+                    download_link: response.download_link,
+                    parts,
+                    root: request.root,
+                    mode: request.mode,
+                    bpm: request.bpm,
+                    time_sig_numerator: request.time_sig_numerator,
+                    time_sig_denominator: request.time_sig_denominator,
+                    seed,
+                    humanize_seed: rand::random(),
+                    events,
+                };
+                log_debug!("Download link: {}", generation.download_link);
+
+                processing.groove.bpm = generation.bpm;
+                processing.groove.seed = generation.humanize_seed;
+                let reply = Reply::Generated {
+                    generation: generation.clone(),
+                    events: processing.apply(&generation.events),
+                    processing,
+                    at_bar,
+                };
+                if self.replies.push(reply).is_err() {
+                    log_warn!("The generation queue is full, the generation is dropped");
+                    return;
+                }
+                self.record_generation(&generation);
+                self.generation = Some(generation);
+                self.shared.download_available.store(true, Ordering::SeqCst);
+                self.emit(Event::DownloadAvailable);
+            }
+            // The previous generation keeps playing, so a failed request does not stop the performance
+            Err(message) => {
+                log_error!("Generation failed: {}", message);
+                let _ = self
+                    .shared
+                    .debug_info
+                    .insert(String::from("Generation error"), message);
+            }
+        }
+    }
+
+    /// Writes the last generation and the number of generations in the debug panel
+    fn record_generation(&self, generation: &Generation) {
+        let debug_info = &self.shared.debug_info;
+        let _ = debug_info.remove(&String::from("Generation error"));
+        let _ = debug_info.insert(
+            String::from("Generation"),
+            format!(
+                "{} in {} {}, {} BPM, {}/{}, {} notes",
+                generation
+                    .parts
+                    .iter()
+                    .map(|style| style.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" + "),
+                generation.root,
+                generation.mode,
+                generation.bpm,
+                generation.time_sig_numerator,
+                generation.time_sig_denominator,
+                generation.events.len()
+            ),
+        );
+        let _ = debug_info.update(String::from("Generations"), |count| {
+            let generations = count
+                .as_deref()
+                .and_then(|count| count.parse::<u32>().ok())
+                .unwrap_or(0);
+            *count = Some((generations + 1).to_string());
+        });
+    }
+
+    fn download(
+        &mut self,
+        processing: Processing,
+        transform: Transform,
+        routing: Routing,
+    ) -> Result<(), String> {
+        let generation = self
+            .generation
+            .as_ref()
+            .ok_or("No download link available")?;
+
+        // The server file is only used when there is no preview to save
+        if generation.events.is_empty() {
+            let path = self.export_path(generation, &transform, "mid", &[])?;
+            self.requester
+                .download_midi(generation.download_link.clone(), path);
+            return Ok(());
+        }
+
+        // The preview is saved locally so the transformations are kept
+        let events = processing.apply(&generation.events);
+        let events = transform.apply_all(&events);
+        let events = routing.apply_all(&events);
+        let info = score_info(generation, &transform);
+        let midi = MidiFile::from_events(&events, &routing.programs, &info, &generation.describe());
+        let notations: Vec<NotationFormat> = [
+            (self.params.export_abc.value(), NotationFormat::Abc),
+            (
+                self.params.export_note_list.value(),
+                NotationFormat::NoteList,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, format)| enabled.then_some(format))
+        .collect();
+        let mut extensions: Vec<&str> = notations.iter().map(|format| format.extension()).collect();
+        if self.params.export_musicxml.value() {
+            extensions.push("musicxml");
+        }
+
+        let path = self.export_path(generation, &transform, "mid", &extensions)?;
+        let mut files = vec![(path.clone(), midi.encode())];
+        if self.params.export_musicxml.value() {
+            let score = musicxml::to_musicxml(&events, &info);
+            files.push((path.with_extension("musicxml"), score.into_bytes()));
+        }
+        for format in notations {
+            let text = format.write(&events, &info);
+            files.push((path.with_extension(format.extension()), text.into_bytes()));
+        }
+        self.requester.save_files(files);
+        Ok(())
+    }
+
+    fn render_audio(
+        &mut self,
+        processing: Processing,
+        transform: Transform,
+        sample_rate: f32,
+    ) -> Result<(), String> {
+        let generation = match &self.generation {
+            Some(generation) if !generation.events.is_empty() => generation,
+            _ => return Err(String::from("No generation to render")),
+        };
+
+        let events = processing.apply(&generation.events);
+        let events = transform.apply_all(&events);
+        let settings = RenderSettings {
+            sample_rate,
+            bpm: generation.bpm,
+            tail_seconds: self.params.render_tail.value(),
+            normalize: self.params.render_normalize.value(),
+            format: self.params.render_format.value(),
+        };
+        let path = self.export_path(generation, &transform, settings.format.extension(), &[])?;
+        render::render_to_file(
+            events,
+            settings,
+            path,
+            self.shared.render_progress.clone(),
+            self.sender.clone(),
+        );
+        Ok(())
+    }
+
+    fn notation(
+        &self,
+        format: NotationFormat,
+        processing: Processing,
+        transform: Transform,
+    ) -> Option<String> {
+        let generation = self
+            .generation
+            .as_ref()
+            .filter(|generation| !generation.events.is_empty())?;
+        let events = processing.apply(&generation.events);
+        let events = transform.apply_all(&events);
+        Some(format.write(&events, &score_info(generation, &transform)))
+    }
+
+    /// Path of the next file exported from a generation, named from the template of the user.
+    /// The files written next to it with the `extensions` are given the same name, and they are
+    /// all created empty to keep the name. Fails when the folder or the files cannot be created.
+    fn export_path(
+        &self,
+        generation: &Generation,
+        transform: &Transform,
+        extension: &str,
+        extensions: &[&str],
+    ) -> Result<PathBuf, String> {
+        let project = self
+            .params
+            .project_folder
+            .read()
+            .map(|project| project.clone())
+            .unwrap_or_default();
+        let folder = file_naming::export_folder(
+            &self.params.downloads_folder(),
+            self.params.sub_folder.value(),
+            &project,
+        )?;
+
+        let (root, mode) = transform.output_key();
+        let fields = NameFields {
+            style: generation
+                .parts
+                .iter()
+                .map(|style| style.to_string())
+                .collect::<Vec<_>>()
+                .join("+"),
+            key: root.to_string(),
+            mode: mode.to_string(),
+            bpm: generation.bpm,
+            date: file_naming::today(),
+            seed: generation.seed,
+        };
+        let template = self
+            .params
+            .file_name_template
+            .read()
+            .map(|template| template.clone())
+            .unwrap_or_default();
+
+        file_naming::unique_path(&folder, &template, &fields, extension, extensions)
+    }
+}
+
+/// Key, tempo and time signature written in the scores of a generation
+fn score_info<'a>(generation: &'a Generation, transform: &Transform) -> ScoreInfo<'a> {
+    let (root, mode) = transform.output_key();
+    ScoreInfo {
+        parts: &generation.parts,
+        root,
+        mode,
+        bpm: generation.bpm,
+        time_sig_numerator: generation.time_sig_numerator,
+        time_sig_denominator: generation.time_sig_denominator,
+    }
+}